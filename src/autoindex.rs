@@ -0,0 +1,243 @@
+//! Recursive downloads of the HTML directory listings nginx and Apache
+//! (autoindex) generate: every link on an index page that points below it
+//! is a file, or a subdirectory when it ends with `/`.
+//!
+//! Files are saved under a directory named like the listed one, keeping the
+//! remote tree; their relative path is what include/exclude globs match.
+
+use std::path::PathBuf;
+
+use percent_encoding::percent_decode_str;
+use reqwest::{header::HeaderMap, Client, Method, Url};
+use tracing::{debug, warn};
+
+use crate::{
+    auth::{redacted, Auth},
+    limits::glob_match,
+    source::send_authorized,
+    DResult, Errors,
+};
+
+/// Subdirectory levels followed when no depth is given, like wget's `-l`
+pub const DEFAULT_DEPTH: usize = 5;
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// subdirectory levels below the listed directory to follow
+    pub depth: usize,
+    /// when not empty, only files matching one of these are downloaded
+    pub include: Vec<String>,
+    /// files and directories (with a trailing `/`) matching one of these
+    /// are left out
+    pub exclude: Vec<String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            depth: DEFAULT_DEPTH,
+            include: vec![],
+            exclude: vec![],
+        }
+    }
+}
+
+impl Options {
+    /// Whether a directory at `path` (ending in `/`) is followed
+    pub fn wants_dir(&self, path: &str) -> bool {
+        !self.exclude.iter().any(|glob| glob_match(glob, path))
+    }
+
+    /// Whether the file at `path` is downloaded
+    pub fn wants_file(&self, path: &str) -> bool {
+        self.wants_dir(path)
+            && (self.include.is_empty() || self.include.iter().any(|glob| glob_match(glob, path)))
+    }
+}
+
+/// A file found in a listing
+#[derive(Debug, Clone)]
+pub struct RemoteFile {
+    pub url: String,
+    /// where it goes locally, relative to the save path, starting with the
+    /// listed directory's name
+    pub path: PathBuf,
+}
+
+/// Walks the listing at `root` and its subdirectories, returning the
+/// files to download
+pub async fn crawl(
+    client: &Client,
+    auth: Option<&Auth>,
+    root: &str,
+    headers: &HeaderMap,
+    options: &Options,
+    retries: usize,
+) -> DResult<Vec<RemoteFile>> {
+    let mut root = Url::parse(root)
+        .map_err(|e| Errors::Custom(format!("Failed parsing url {}: {}", root, e)))?;
+    if !root.path().ends_with('/') {
+        root.set_path(&format!("{}/", root.path()));
+    }
+    let top = root
+        .path_segments()
+        .and_then(|mut segments| segments.rfind(|s| !s.is_empty()))
+        .map(decode_segment)
+        .transpose()?
+        .or_else(|| root.host_str().map(|host| host.to_owned()))
+        .unwrap_or_else(|| "index".to_owned());
+
+    let mut files = vec![];
+    // (directory url, path relative to root, depth)
+    let mut dirs = vec![(root.clone(), String::new(), 0)];
+    while let Some((dir, relative, depth)) = dirs.pop() {
+        debug!("listing {}", redacted(&dir));
+        let page = send_authorized(
+            auth,
+            &Method::GET,
+            dir.as_str(),
+            || client.get(dir.as_str()).headers(headers.clone()),
+            retries,
+        )
+        .await?
+        .text()
+        .await?;
+        for link in links(&page, &dir) {
+            let Some(name) = link.path().strip_prefix(dir.path()) else {
+                continue;
+            };
+            let is_dir = name.ends_with('/');
+            let name = match decode_segment(name.trim_end_matches('/')) {
+                Ok(name) => name,
+                Err(e) => {
+                    warn!("Skipping {}: {}", redacted(&link), e);
+                    continue;
+                }
+            };
+            let path = format!("{}{}", relative, name);
+            if is_dir {
+                if depth < options.depth && options.wants_dir(&format!("{}/", path)) {
+                    dirs.push((link, format!("{}/", path), depth + 1));
+                }
+            } else if options.wants_file(&path) {
+                files.push(RemoteFile {
+                    url: link.to_string(),
+                    path: PathBuf::from(&top).join(&path),
+                });
+            }
+        }
+    }
+    debug!("found {} files under {}", files.len(), redacted(&root));
+    Ok(files)
+}
+
+/// Links of an index page that point straight into `dir`, one level down,
+/// leaving out parent directories and sort links like `?C=N;O=D`
+fn links(page: &str, dir: &Url) -> Vec<Url> {
+    let mut links: Vec<Url> = vec![];
+    let mut rest = page;
+    while let Some(start) = rest.find("href=") {
+        rest = &rest[start + 5..];
+        let quote = match rest.chars().next() {
+            Some(q @ ('"' | '\'')) => q,
+            _ => continue,
+        };
+        let Some(end) = rest[1..].find(quote) else {
+            break;
+        };
+        let href = rest[1..end + 1].replace("&amp;", "&");
+        rest = &rest[end + 1..];
+
+        let Ok(mut link) = dir.join(&href) else {
+            continue;
+        };
+        if link.query().is_some() {
+            continue;
+        }
+        link.set_fragment(None);
+        let below = link.origin() == dir.origin()
+            && link.path().strip_prefix(dir.path()).is_some_and(|name| {
+                let name = name.trim_end_matches('/');
+                !name.is_empty() && !name.contains('/')
+            });
+        if below && !links.contains(&link) {
+            links.push(link);
+        }
+    }
+    links
+}
+
+/// A percent-encoded path segment as a file name, refusing ones that
+/// would leave the directory they're saved in
+fn decode_segment(segment: &str) -> DResult<String> {
+    let name = percent_decode_str(segment)
+        .decode_utf8()
+        .map_err(|e| Errors::Custom(format!("Invalid file name {}: {}", segment, e)))?;
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+        return Err(Errors::Custom(format!("Unsafe file name {:?}", name)));
+    }
+    Ok(name.into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: &str = r#"<html><body><h1>Index of /pub/isos</h1>
+<a href="?C=N;O=D">Name</a> <a href="?C=M;O=A">Last modified</a>
+<a href="/pub/">Parent Directory</a>
+<a href="../">..</a>
+<a href="a.iso">a.iso</a>
+<a href='b%20c.iso'>b c.iso</a>
+<a href="old/">old/</a>
+<a href="a.iso#top">a.iso again</a>
+<a href="/pub/isos/d.iso">d.iso</a>
+<a href="sub/deeper/e.iso">e.iso</a>
+<a href="https://elsewhere.example/pub/isos/f.iso">f.iso</a>
+<a href="http://example.org/pub/isos/g.iso">g.iso</a>
+<a href="x.iso?download=1&amp;v=2">x.iso</a>
+<a href=unquoted.iso>unquoted</a>
+</body></html>"#;
+
+    #[test]
+    fn index_links() {
+        let dir = Url::parse("https://example.org/pub/isos/").unwrap();
+        let links: Vec<String> = links(PAGE, &dir).iter().map(Url::to_string).collect();
+        assert_eq!(
+            links,
+            [
+                "https://example.org/pub/isos/a.iso",
+                "https://example.org/pub/isos/b%20c.iso",
+                "https://example.org/pub/isos/old/",
+                "https://example.org/pub/isos/d.iso",
+            ]
+        );
+    }
+
+    #[test]
+    fn unterminated_href() {
+        let dir = Url::parse("https://example.org/").unwrap();
+        assert_eq!(
+            links(r#"<a href="a.iso">a</a><a href="b.iso"#, &dir).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn segments() {
+        assert_eq!(decode_segment("b%20c.iso").unwrap(), "b c.iso");
+        assert_eq!(decode_segment("gr%C3%BC%C3%9Fe").unwrap(), "grüße");
+        for unsafe_name in [
+            "",
+            ".",
+            "..",
+            "%2E%2E",
+            "a%2Fb",
+            "..%2F..%2Fetc",
+            "a%5Cb",
+            "%FF",
+        ] {
+            assert!(decode_segment(unsafe_name).is_err(), "{}", unsafe_name);
+        }
+    }
+}
@@ -0,0 +1,231 @@
+//! Input files listing many downloads, in the spirit of wget/aria2's `-i`:
+//!
+//! ```text
+//! # comments and blank lines are ignored
+//! https://example.com/a.iso
+//!   out=renamed.iso
+//!   dir=isos
+//!   checksum=sha-256=9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08
+//!   header=Authorization: Bearer abc
+//!   fallback=https://backup.example.com/a.iso
+//! https://example.com/b.iso
+//! ```
+//!
+//! Indented `key=value` lines belong to the url above them.
+
+use std::path::PathBuf;
+
+use reqwest::header::{HeaderName, HeaderValue};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tracing::debug;
+
+use crate::{
+    checksum::Checksum,
+    download::{parse_header, DownloadBuilder},
+    DResult, Errors,
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct Entry {
+    pub url: String,
+    /// file name to save as, instead of the url's
+    pub out: Option<String>,
+    /// directory to save into, instead of the default path
+    pub dir: Option<String>,
+    pub checksum: Option<Checksum>,
+    pub headers: Vec<(HeaderName, HeaderValue)>,
+    pub fallbacks: Vec<String>,
+}
+
+impl Entry {
+    /// Where the entry is saved, `base` when it sets neither `dir` nor
+    /// `out`
+    pub fn path(&self, base: &str) -> String {
+        let mut path = PathBuf::from(self.dir.as_deref().unwrap_or(base));
+        if let Some(out) = &self.out {
+            path.push(out);
+        }
+        path.to_string_lossy().into_owned()
+    }
+
+    /// Turns the entry into a builder, `base` is used as the save path
+    /// when the entry sets neither `dir` nor `out`
+    pub fn builder(&self, base: &str) -> DownloadBuilder {
+        self.options(DownloadBuilder::new(&self.url).path(self.path(base)))
+    }
+
+    /// The entry's headers, fallbacks and checksum on a builder made
+    /// elsewhere, e.g. for a `.torrent`
+    pub fn options(&self, mut builder: DownloadBuilder) -> DownloadBuilder {
+        for (name, value) in &self.headers {
+            builder = builder.header(name.clone(), value.clone());
+        }
+        for fallback in &self.fallbacks {
+            builder = builder.fallback(fallback);
+        }
+        if let Some(checksum) = &self.checksum {
+            builder = builder.checksum(checksum.clone());
+        }
+        builder
+    }
+}
+
+/// Parses the whole input file
+pub fn parse(input: &str) -> DResult<Vec<Entry>> {
+    let mut entries: Vec<Entry> = vec![];
+    for (no, line) in input.lines().enumerate() {
+        let no = no + 1;
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        if !line.starts_with(char::is_whitespace) {
+            entries.push(Entry {
+                url: trimmed.to_owned(),
+                ..Default::default()
+            });
+            continue;
+        }
+
+        let entry = entries
+            .last_mut()
+            .ok_or_else(|| Errors::Custom(format!("line {}: option before any url", no)))?;
+        let (key, value) = trimmed
+            .split_once('=')
+            .ok_or_else(|| Errors::Custom(format!("line {}: expected key=value", no)))?;
+        let in_line = |e: Errors| Errors::Custom(format!("line {}: {:?}", no, e));
+        match key.trim() {
+            "out" => entry.out = Some(value.trim().to_owned()),
+            "dir" => entry.dir = Some(value.trim().to_owned()),
+            "checksum" => entry.checksum = Some(value.parse().map_err(in_line)?),
+            "header" => entry.headers.push(parse_header(value).map_err(in_line)?),
+            "fallback" => entry.fallbacks.push(value.trim().to_owned()),
+            other => {
+                return Err(Errors::Custom(format!(
+                    "line {}: unknown option {}",
+                    no, other
+                )))
+            }
+        }
+    }
+    debug!("parsed {} entries from input file", entries.len());
+    Ok(entries)
+}
+
+/// Reads and parses an input file, `-` reads stdin
+pub async fn read(path: &str) -> DResult<Vec<Entry>> {
+    read_from(path, tokio::io::stdin()).await
+}
+
+/// `read` with `-` reading `stdin`
+async fn read_from<R: AsyncRead + Unpin>(path: &str, mut stdin: R) -> DResult<Vec<Entry>> {
+    let input = if path == "-" {
+        let mut input = String::new();
+        stdin.read_to_string(&mut input).await?;
+        input
+    } else {
+        tokio::fs::read_to_string(path).await?
+    };
+    parse(&input)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use reqwest::header::AUTHORIZATION;
+
+    use super::*;
+    use crate::checksum::Algorithm;
+
+    #[test]
+    fn comments_and_blank_lines() {
+        let entries = parse(
+            "# a list\n\nhttps://example.com/a.iso\n   \n  # dir=ignored\n\thttps://not.a.url\n",
+        );
+        // an indented line is an option, even one that looks like a url
+        assert!(entries.is_err());
+
+        let entries = parse(
+            "# a list\n\nhttps://example.com/a.iso\n   \n  # dir=ignored\nhttps://example.com/b.iso\n",
+        )
+        .unwrap();
+        let urls: Vec<&str> = entries.iter().map(|e| e.url.as_str()).collect();
+        assert_eq!(
+            urls,
+            ["https://example.com/a.iso", "https://example.com/b.iso"]
+        );
+        assert_eq!(entries[0].dir, None);
+    }
+
+    #[test]
+    fn options_before_the_first_url() {
+        let e = parse("# header\n  out=a.iso\nhttps://example.com/a.iso\n").unwrap_err();
+        assert!(matches!(e, Errors::Custom(e) if e == "line 2: option before any url"));
+        assert!(parse("https://example.com/a.iso\n  out\n").is_err());
+        assert!(parse("https://example.com/a.iso\n  color=red\n").is_err());
+    }
+
+    #[test]
+    fn values_keep_their_separators() {
+        let entries = parse(
+            "https://example.com/a.iso
+  checksum=sha-256=9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08
+  header=Authorization: Basic dXNlcjpwYXNz==
+  header=X-Time: 12:30
+  fallback=https://backup.example.com/a.iso?v=1
+",
+        )
+        .unwrap();
+        let entry = &entries[0];
+        let checksum = entry.checksum.as_ref().unwrap();
+        assert_eq!(checksum.algorithm, Algorithm::Sha256);
+        assert_eq!(
+            hex::encode(&checksum.digest),
+            "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+        );
+        assert_eq!(entry.headers[0].0, AUTHORIZATION);
+        assert_eq!(entry.headers[0].1, "Basic dXNlcjpwYXNz==");
+        assert_eq!(entry.headers[1].0, "x-time");
+        assert_eq!(entry.headers[1].1, "12:30");
+        assert_eq!(entry.fallbacks, ["https://backup.example.com/a.iso?v=1"]);
+
+        assert!(parse("https://example.com/a.iso\n  checksum=sha-256\n").is_err());
+        assert!(parse("https://example.com/a.iso\n  header=no colon\n").is_err());
+    }
+
+    #[test]
+    fn paths() {
+        let entries = parse(
+            "https://example.com/a.iso
+  out=renamed.iso
+  dir=isos
+https://example.com/b.iso
+  out=b.iso
+https://example.com/c.iso
+  dir=isos
+https://example.com/d.iso
+",
+        )
+        .unwrap();
+        let paths: Vec<String> = entries.iter().map(|e| e.path("downloads")).collect();
+        assert_eq!(
+            paths,
+            [
+                Path::new("isos").join("renamed.iso"),
+                Path::new("downloads").join("b.iso"),
+                PathBuf::from("isos"),
+                PathBuf::from("downloads"),
+            ]
+            .map(|p| p.to_string_lossy().into_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn dash_reads_stdin() {
+        let stdin: &[u8] = b"https://example.com/a.iso\n  out=a.iso\n";
+        let entries = read_from("-", stdin).await.unwrap();
+        assert_eq!(entries[0].url, "https://example.com/a.iso");
+        assert_eq!(entries[0].out.as_deref(), Some("a.iso"));
+    }
+}
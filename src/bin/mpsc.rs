@@ -1,13 +1,8 @@
-use std::{
-    borrow::Borrow,
-    collections::HashSet,
-    fmt::write,
-    path::{self, PathBuf},
-};
+use std::collections::HashSet;
 
-use clap::{builder::Str, Parser};
+use clap::Parser;
 use colored::Colorize;
-use reqwest::{Client, Response, Url};
+use reqwest::Client;
 use tokio::{
     fs::File,
     io::{AsyncSeekExt, AsyncWriteExt},
@@ -15,16 +10,12 @@ use tokio::{
     task::JoinHandle,
     time::Instant,
 };
-use tokio_util::bytes::{BufMut, Bytes};
-use tracing::{
-    debug, error, info,
-    subscriber::{self, SetGlobalDefaultError},
-    warn,
-};
+use tokio_util::bytes::Bytes;
+use tracing::{debug, error};
 
 use donldr::{
     download::{determine_file_path, Download},
-    DResult, Errors,
+    DResult,
 };
 
 #[derive(Parser, Debug)]
@@ -49,7 +40,7 @@ async fn main() -> DResult<()> {
     debug!("parsed cli:\n{:#?}", c);
     let download = Download::new(c.url, c.path, c.chunks).await?;
 
-    let (tx, mut rx) = tokio::sync::mpsc::channel((download.info.chunk_size * 4) as usize);
+    let (tx, rx) = tokio::sync::mpsc::channel((download.info.chunk_size * 4) as usize);
     let start_time = Instant::now();
     let mut downloaders: Vec<JoinHandle<()>> = vec![];
     //downloaders
@@ -58,14 +49,7 @@ async fn main() -> DResult<()> {
         let client = download.client.clone();
         let url = download.url.clone();
         let tx = tx.clone();
-        downloaders.push(tokio::spawn(get_chunk(
-            client,
-            tx,
-            from,
-            to,
-            url,
-            idx as usize,
-        )))
+        downloaders.push(tokio::spawn(get_chunk(client, tx, from, to, url, idx)))
     }
 
     let file_manager = tokio::spawn(file_manager(
@@ -100,6 +84,7 @@ async fn file_manager(
         .read(true)
         .write(true)
         .create(true)
+        .truncate(false)
         .open(&file_path)
         .await
         .expect("Failed opening file");
@@ -187,7 +172,7 @@ impl Status {
 impl std::fmt::Display for Status {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "[")?;
-        for c in (0..self.total_chunks - 1) {
+        for c in 0..self.total_chunks - 1 {
             if self.chunks.contains(&c) {
                 let s = format!("✓{:>3}", superscript(c)).green().on_bright_green();
                 write!(f, "{}", s)?;
@@ -209,16 +194,16 @@ impl std::fmt::Display for Status {
     }
 }
 
-const supe: [char; 10] = ['⁰', '¹', '²', '³', '⁴', '⁵', '⁶', '⁷', '⁸', '⁹'];
+const SUPE: [char; 10] = ['⁰', '¹', '²', '³', '⁴', '⁵', '⁶', '⁷', '⁸', '⁹'];
 fn superscript(mut n: usize) -> String {
     let mut s: String = String::new();
     if n == 0 {
-        s.push(supe[0]);
+        s.push(SUPE[0]);
         s
     } else {
         while n > 0 {
-            s.push(supe[n % 10]);
-            n = n / 10;
+            s.push(SUPE[n % 10]);
+            n /= 10;
         }
         s.chars().rev().collect()
     }
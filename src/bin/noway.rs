@@ -1,4 +1,4 @@
-use std::{borrow::Borrow, path::PathBuf};
+use std::path::PathBuf;
 
 use clap::Parser;
 use donldr::{
@@ -6,22 +6,11 @@ use donldr::{
     DResult, Errors,
 };
 use futures::StreamExt;
-use reqwest::Client;
 use tokio::{fs::File, io::AsyncWriteExt, time::Instant};
-use tokio_util::bytes::BufMut;
-use tracing::{
-    debug,
-    subscriber::{self, SetGlobalDefaultError},
-    warn,
-};
+use tracing::{debug, warn};
 
 // mod main_tokio;
 
-struct DownloadTask {
-    url: String,
-    path: String,
-}
-
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Cli {
@@ -0,0 +1,146 @@
+//! Whole-file digests in aria2's `checksum=<type>=<hex digest>` form,
+//! e.g. `sha-256=e3b0c442...`, checked before the `.part` file is moved
+//! into place, and the per-piece SHA-1s torrents carry, checked as each
+//! piece comes in.
+
+use std::{fmt, str::FromStr};
+
+use md5::Md5;
+use sha1::{Digest, Sha1};
+use sha2::{Sha224, Sha256, Sha384, Sha512};
+use tracing::info;
+
+use crate::{DResult, Errors};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Md5,
+    Sha1,
+    Sha224,
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl Algorithm {
+    pub fn digest(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Algorithm::Md5 => Md5::digest(data).to_vec(),
+            Algorithm::Sha1 => Sha1::digest(data).to_vec(),
+            Algorithm::Sha224 => Sha224::digest(data).to_vec(),
+            Algorithm::Sha256 => Sha256::digest(data).to_vec(),
+            Algorithm::Sha384 => Sha384::digest(data).to_vec(),
+            Algorithm::Sha512 => Sha512::digest(data).to_vec(),
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = Errors;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().replace('-', "").as_str() {
+            "md5" => Ok(Algorithm::Md5),
+            "sha1" => Ok(Algorithm::Sha1),
+            "sha224" => Ok(Algorithm::Sha224),
+            "sha256" => Ok(Algorithm::Sha256),
+            "sha384" => Ok(Algorithm::Sha384),
+            "sha512" => Ok(Algorithm::Sha512),
+            _ => Err(Errors::Custom(format!("Unsupported checksum type: {}", s))),
+        }
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Algorithm::Md5 => "md5",
+            Algorithm::Sha1 => "sha-1",
+            Algorithm::Sha224 => "sha-224",
+            Algorithm::Sha256 => "sha-256",
+            Algorithm::Sha384 => "sha-384",
+            Algorithm::Sha512 => "sha-512",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checksum {
+    pub algorithm: Algorithm,
+    pub digest: Vec<u8>,
+}
+
+impl Checksum {
+    pub fn new(algorithm: Algorithm, digest: Vec<u8>) -> Self {
+        Checksum { algorithm, digest }
+    }
+
+    pub fn verify(&self, data: &[u8]) -> DResult<()> {
+        let actual = self.algorithm.digest(data);
+        if actual == self.digest {
+            info!("checksum verified: {}", self);
+            Ok(())
+        } else {
+            Err(Errors::Custom(format!(
+                "Checksum mismatch, expected {} got {}={}",
+                self,
+                self.algorithm,
+                hex::encode(actual)
+            )))
+        }
+    }
+}
+
+impl FromStr for Checksum {
+    type Err = Errors;
+
+    /// `<type>=<hex digest>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (algorithm, digest) = s
+            .split_once('=')
+            .ok_or_else(|| Errors::Custom(format!("Checksum isn't `type=digest`: {}", s)))?;
+        let algorithm = algorithm.trim().parse::<Algorithm>()?;
+        let digest = hex::decode(digest.trim())
+            .map_err(|e| Errors::Custom(format!("Invalid checksum digest {}: {}", digest, e)))?;
+        Ok(Checksum { algorithm, digest })
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.algorithm, hex::encode(&self.digest))
+    }
+}
+
+/// SHA-1 of every `length` bytes of a file, the last piece shorter
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pieces {
+    pub length: u64,
+    pub sha1: Vec<[u8; 20]>,
+}
+
+impl Pieces {
+    /// Whether the `len` byte file these are the pieces of can be
+    pub fn fits(&self, len: u64) -> bool {
+        self.length > 0 && len.div_ceil(self.length) == self.sha1.len() as u64
+    }
+
+    pub fn verify(&self, idx: usize, data: &[u8]) -> DResult<()> {
+        let expected = self
+            .sha1
+            .get(idx)
+            .ok_or_else(|| Errors::Custom(format!("No piece {}", idx)))?;
+        let actual = Sha1::digest(data);
+        if actual.as_slice() == expected {
+            Ok(())
+        } else {
+            Err(Errors::Custom(format!(
+                "Piece {} hashes to {}, expected {}",
+                idx,
+                hex::encode(actual),
+                hex::encode(expected)
+            )))
+        }
+    }
+}
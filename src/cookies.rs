@@ -0,0 +1,428 @@
+//! A cookie jar read from and written to the Netscape `cookies.txt` format
+//! that curl, wget and the browser export extensions use, so downloads
+//! behind a login can reuse the browser's session.
+//!
+//! One line per cookie, tab separated: domain, whether subdomains get it
+//! too, path, secure only, expiry in unix seconds (0 for a session
+//! cookie), name and value. `#HttpOnly_` in front of the domain marks an
+//! HttpOnly cookie, other `#` lines are comments.
+//!
+//! The jar plugs into the HTTP clients as their cookie store, cookies the
+//! server sets on any response (redirects included) are sent with every
+//! later request of every connection sharing the jar.
+
+use std::{
+    path::Path,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use reqwest::{cookie::CookieStore, header::HeaderValue, Url};
+use tracing::{debug, warn};
+
+use crate::{download::parse_http_date, DResult, Errors};
+
+const HTTP_ONLY: &str = "#HttpOnly_";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cookie {
+    /// lowercase, without a leading dot
+    pub domain: String,
+    /// sent to subdomains of `domain` too, false for host-only cookies
+    pub subdomains: bool,
+    pub path: String,
+    /// only sent over https
+    pub secure: bool,
+    pub http_only: bool,
+    /// `None` for a session cookie
+    pub expires: Option<SystemTime>,
+    pub name: String,
+    pub value: String,
+}
+
+impl Cookie {
+    /// A line of a cookies.txt file, `None` for comments and blank lines
+    pub fn parse_line(line: &str) -> Option<DResult<Cookie>> {
+        let (line, http_only) = match line.strip_prefix(HTTP_ONLY) {
+            Some(line) => (line, true),
+            None => (line, false),
+        };
+        if line.trim().is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let fields: Vec<&str> = line.trim_end_matches(['\r', '\n']).split('\t').collect();
+        // the line itself has the cookie's value, it stays out of errors
+        let [domain, subdomains, path, secure, expires, name, value] = fields[..] else {
+            return Some(Err(Errors::Custom(format!(
+                "Cookie line has {} tab separated fields instead of 7",
+                fields.len()
+            ))));
+        };
+        let Ok(expires) = expires.parse::<u64>() else {
+            return Some(Err(Errors::Custom(format!(
+                "Expiry of cookie {} isn't a unix time: {}",
+                name, expires
+            ))));
+        };
+        Some(Ok(Cookie {
+            domain: domain.trim_start_matches('.').to_ascii_lowercase(),
+            subdomains: subdomains.eq_ignore_ascii_case("TRUE"),
+            path: path.to_owned(),
+            secure: secure.eq_ignore_ascii_case("TRUE"),
+            http_only,
+            expires: (expires != 0).then(|| UNIX_EPOCH + Duration::from_secs(expires)),
+            name: name.to_owned(),
+            value: value.to_owned(),
+        }))
+    }
+
+    /// The cookie as a cookies.txt line
+    pub fn line(&self) -> String {
+        let flag = |b: bool| if b { "TRUE" } else { "FALSE" };
+        format!(
+            "{}{}{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            if self.http_only { HTTP_ONLY } else { "" },
+            if self.subdomains { "." } else { "" },
+            self.domain,
+            flag(self.subdomains),
+            self.path,
+            flag(self.secure),
+            self.expires
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |since| since.as_secs()),
+            self.name,
+            self.value
+        )
+    }
+
+    /// A `Set-Cookie` header received from `url`, `None` if it's malformed
+    /// or the url isn't allowed to set it
+    pub fn parse_set_cookie(header: &str, url: &Url) -> Option<Cookie> {
+        let host = url.host_str()?.to_ascii_lowercase();
+        let mut attributes = header.split(';');
+        let (name, value) = attributes.next()?.split_once('=')?;
+        let name = name.trim();
+        if name.is_empty() {
+            return None;
+        }
+        let mut cookie = Cookie {
+            domain: host.clone(),
+            subdomains: false,
+            path: default_path(url),
+            secure: false,
+            http_only: false,
+            expires: None,
+            name: name.to_owned(),
+            value: value.trim().trim_matches('"').to_owned(),
+        };
+        let mut max_age = None;
+        for attribute in attributes {
+            let (key, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+            let value = value.trim();
+            match key.trim().to_ascii_lowercase().as_str() {
+                "domain" if !value.is_empty() => {
+                    let domain = value.trim_start_matches('.').to_ascii_lowercase();
+                    // no public suffix list, but a bare TLD is never allowed
+                    if !domain_matches(&host, &domain) || (domain != host && !domain.contains('.'))
+                    {
+                        debug!("{} can't set a cookie for {}", host, domain);
+                        return None;
+                    }
+                    cookie.domain = domain;
+                    cookie.subdomains = true;
+                }
+                "path" if value.starts_with('/') => cookie.path = value.to_owned(),
+                "expires" => cookie.expires = parse_http_date(&value.replace('-', " ")),
+                "max-age" => max_age = value.parse::<i64>().ok(),
+                "secure" => cookie.secure = true,
+                "httponly" => cookie.http_only = true,
+                _ => {}
+            }
+        }
+        // Max-Age wins over Expires, zero or less expires the cookie now
+        if let Some(secs) = max_age {
+            cookie.expires = Some(match u64::try_from(secs) {
+                Ok(secs) if secs > 0 => SystemTime::now() + Duration::from_secs(secs),
+                _ => UNIX_EPOCH,
+            });
+        }
+        if cookie.secure && url.scheme() != "https" {
+            return None;
+        }
+        Some(cookie)
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+
+    /// Whether the cookie goes with a request to `url`
+    pub fn matches(&self, url: &Url) -> bool {
+        let Some(host) = url.host_str() else {
+            return false;
+        };
+        let host = host.to_ascii_lowercase();
+        let domain = match self.subdomains {
+            true => domain_matches(&host, &self.domain),
+            false => host == self.domain,
+        };
+        domain && path_matches(url.path(), &self.path) && (!self.secure || url.scheme() == "https")
+    }
+}
+
+/// `host` is `domain` or a subdomain of it
+fn domain_matches(host: &str, domain: &str) -> bool {
+    host == domain
+        || host
+            .strip_suffix(domain)
+            .is_some_and(|sub| sub.ends_with('.'))
+}
+
+/// `/docs` matches `/docs`, `/docs/` and `/docs/a` but not `/docsets`
+fn path_matches(path: &str, cookie_path: &str) -> bool {
+    path == cookie_path
+        || path
+            .strip_prefix(cookie_path)
+            .is_some_and(|rest| cookie_path.ends_with('/') || rest.starts_with('/'))
+}
+
+/// The directory of the url's path, where a cookie without a Path applies
+fn default_path(url: &Url) -> String {
+    match url.path().rfind('/') {
+        Some(0) | None => "/".to_owned(),
+        Some(idx) => url.path()[..idx].to_owned(),
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct CookieJar {
+    cookies: Mutex<Vec<Cookie>>,
+}
+
+impl CookieJar {
+    /// Malformed lines are skipped with a warning, like curl does, one bad
+    /// line in a browser export shouldn't lose all the others
+    pub fn parse(text: &str) -> Self {
+        let cookies = text
+            .lines()
+            .enumerate()
+            .filter_map(|(no, line)| match Cookie::parse_line(line)? {
+                Ok(cookie) => Some(cookie),
+                Err(e) => {
+                    warn!("Skipping cookie on line {}: {:?}", no + 1, e);
+                    None
+                }
+            })
+            .collect();
+        CookieJar {
+            cookies: Mutex::new(cookies),
+        }
+    }
+
+    pub async fn load<P: AsRef<Path>>(path: P) -> DResult<Self> {
+        let path = path.as_ref();
+        let text = tokio::fs::read_to_string(path).await.map_err(|e| {
+            Errors::Custom(format!("Failed reading cookies from {:?}: {}", path, e))
+        })?;
+        Ok(Self::parse(&text))
+    }
+
+    /// Writes all cookies that haven't expired, session cookies included
+    pub async fn save<P: AsRef<Path>>(&self, path: P) -> DResult<()> {
+        let now = SystemTime::now();
+        let mut text = "# Netscape HTTP Cookie File\n".to_owned();
+        for cookie in self.all().iter().filter(|c| !c.is_expired(now)) {
+            text.push_str(&cookie.line());
+            text.push('\n');
+        }
+        let path = path.as_ref();
+        tokio::fs::write(path, text)
+            .await
+            .map_err(|e| Errors::Custom(format!("Failed saving cookies to {:?}: {}", path, e)))
+    }
+
+    pub fn all(&self) -> Vec<Cookie> {
+        self.cookies.lock().expect("cookie jar poisoned").clone()
+    }
+
+    /// Adds the cookie, replacing the one with the same name, domain and
+    /// path, an expired cookie just removes that one
+    pub fn insert(&self, cookie: Cookie) {
+        let mut cookies = self.cookies.lock().expect("cookie jar poisoned");
+        cookies.retain(|c| {
+            (c.name.as_str(), c.domain.as_str(), c.path.as_str())
+                != (
+                    cookie.name.as_str(),
+                    cookie.domain.as_str(),
+                    cookie.path.as_str(),
+                )
+        });
+        if !cookie.is_expired(SystemTime::now()) {
+            cookies.push(cookie);
+        }
+    }
+}
+
+impl CookieStore for CookieJar {
+    fn set_cookies(&self, cookie_headers: &mut dyn Iterator<Item = &HeaderValue>, url: &Url) {
+        for header in cookie_headers.filter_map(|header| header.to_str().ok()) {
+            if let Some(cookie) = Cookie::parse_set_cookie(header, url) {
+                debug!(
+                    "cookie {} set for {}{}",
+                    cookie.name, cookie.domain, cookie.path
+                );
+                self.insert(cookie);
+            }
+        }
+    }
+
+    fn cookies(&self, url: &Url) -> Option<HeaderValue> {
+        let now = SystemTime::now();
+        let mut cookies: Vec<Cookie> = self
+            .all()
+            .into_iter()
+            .filter(|cookie| !cookie.is_expired(now) && cookie.matches(url))
+            .collect();
+        if cookies.is_empty() {
+            return None;
+        }
+        // more specific paths first
+        cookies.sort_by_key(|cookie| std::cmp::Reverse(cookie.path.len()));
+        let header = cookies
+            .iter()
+            .map(|cookie| format!("{}={}", cookie.name, cookie.value))
+            .collect::<Vec<_>>()
+            .join("; ");
+        HeaderValue::from_str(&header).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(url: &str) -> Url {
+        Url::parse(url).unwrap()
+    }
+
+    const JAR: &str = "# Netscape HTTP Cookie File\n\
+        .example.org\tTRUE\t/\tFALSE\t0\tsession\tabc\n\
+        #HttpOnly_www.example.org\tFALSE\t/docs\tTRUE\t4102444800\ttheme\tdark\n";
+
+    #[test]
+    fn lines_round_trip() {
+        let jar = CookieJar::parse(JAR);
+        let cookies = jar.all();
+        assert_eq!(cookies.len(), 2);
+        assert_eq!(cookies[0].domain, "example.org");
+        assert!(cookies[0].subdomains);
+        assert_eq!(cookies[0].expires, None);
+        assert!(cookies[1].http_only && cookies[1].secure && !cookies[1].subdomains);
+        assert_eq!(
+            cookies[1].expires,
+            Some(UNIX_EPOCH + Duration::from_secs(4102444800))
+        );
+        let lines: Vec<String> = cookies.iter().map(Cookie::line).collect();
+        assert_eq!(lines, JAR.lines().skip(1).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn bad_lines_are_skipped() {
+        let text = format!(
+            "{}not a cookie\nexample.org\tFALSE\t/\tFALSE\tsoon\tx\ty\n",
+            JAR
+        );
+        assert_eq!(CookieJar::parse(&text).all().len(), 2);
+    }
+
+    #[test]
+    fn scoped_by_domain_path_and_scheme() {
+        let jar = CookieJar::parse(JAR);
+        let header = |u: &str| jar.cookies(&url(u)).map(|h| h.to_str().unwrap().to_owned());
+        assert_eq!(
+            header("https://www.example.org/docs/a").as_deref(),
+            Some("theme=dark; session=abc")
+        );
+        assert_eq!(
+            header("http://www.example.org/docs/a").as_deref(),
+            Some("session=abc")
+        );
+        assert_eq!(
+            header("https://www.example.org/docsets").as_deref(),
+            Some("session=abc")
+        );
+        assert_eq!(
+            header("https://sub.www.example.org/docs").as_deref(),
+            Some("session=abc")
+        );
+        assert_eq!(header("https://example.com/"), None);
+        assert_eq!(header("https://badexample.org/"), None);
+    }
+
+    #[test]
+    fn set_cookie_attributes() {
+        let from = url("https://a.example.org/files/x.iso");
+        let cookie = Cookie::parse_set_cookie("id=1; Domain=.example.org; Secure", &from).unwrap();
+        assert_eq!(cookie.domain, "example.org");
+        assert!(cookie.subdomains && cookie.secure);
+        assert_eq!(cookie.path, "/files");
+
+        let cookie = Cookie::parse_set_cookie("id=1; Path=/; Max-Age=60", &from).unwrap();
+        assert_eq!(cookie.domain, "a.example.org");
+        assert!(!cookie.subdomains);
+        assert!(cookie.expires.unwrap() > SystemTime::now());
+
+        let cookie = Cookie::parse_set_cookie("id=1; Max-Age=0", &from).unwrap();
+        assert!(cookie.is_expired(SystemTime::now()));
+        let cookie =
+            Cookie::parse_set_cookie("id=1; Expires=Wed, 21-Oct-2015 07:28:00 GMT", &from).unwrap();
+        assert!(cookie.is_expired(SystemTime::now()));
+    }
+
+    #[test]
+    fn set_cookie_refuses_other_domains() {
+        let from = url("https://a.example.org/");
+        assert_eq!(
+            Cookie::parse_set_cookie("id=1; Domain=example.com", &from),
+            None
+        );
+        assert_eq!(Cookie::parse_set_cookie("id=1; Domain=org", &from), None);
+        assert_eq!(
+            Cookie::parse_set_cookie("id=1; Domain=b.example.org", &from),
+            None
+        );
+        assert_eq!(Cookie::parse_set_cookie("=1", &from), None);
+        assert_eq!(
+            Cookie::parse_set_cookie("id=1; Secure", &url("http://a.example.org/")),
+            None
+        );
+    }
+
+    #[test]
+    fn insert_replaces_and_expires() {
+        let jar = CookieJar::parse(JAR);
+        let from = url("https://example.org/");
+        jar.set_cookies(
+            &mut [HeaderValue::from_static(
+                "session=new; Domain=example.org; Path=/",
+            )]
+            .iter(),
+            &from,
+        );
+        let sessions: Vec<_> = jar
+            .all()
+            .into_iter()
+            .filter(|c| c.name == "session")
+            .collect();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].value, "new");
+        jar.set_cookies(
+            &mut [HeaderValue::from_static(
+                "session=; Domain=example.org; Path=/; Max-Age=0",
+            )]
+            .iter(),
+            &from,
+        );
+        assert!(jar.all().iter().all(|c| c.name != "session"));
+    }
+}
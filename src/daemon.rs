@@ -0,0 +1,487 @@
+//! `donldr daemon`: a download queue that lives on disk and is driven over a
+//! unix socket by `donldr add/ls/pause/resume/rm`.
+//!
+//! The queue is rewritten to a json file on every change, so after a crash or
+//! reboot the daemon picks it back up and unfinished jobs resume from their
+//! `.part.state` files. Clients send one json `Request` per line and get one
+//! json `Response` line back.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{atomic::Ordering, Arc},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::{mpsc, oneshot},
+};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    auth::redact,
+    download::{determine_file_path, DownloadBuilder, DEFAULT_RETRIES},
+    engine::{self, Control},
+    limits::{HostRule, Limits},
+    DResult, Errors,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JobState {
+    Queued,
+    Running,
+    Paused,
+    Done,
+    Failed(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: u64,
+    pub url: String,
+    /// target file or directory, absolute since the daemon has its own cwd
+    pub path: String,
+    pub chunks: usize,
+    pub state: JobState,
+    /// where the finished file ended up
+    pub file: Option<PathBuf>,
+    pub written: u64,
+    pub size: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Request {
+    Add {
+        url: String,
+        path: String,
+        chunks: usize,
+    },
+    List,
+    Pause {
+        id: u64,
+    },
+    Resume {
+        id: u64,
+    },
+    Remove {
+        id: u64,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Response {
+    Added { id: u64 },
+    Jobs { jobs: Vec<Job> },
+    Ok,
+    Error { message: String },
+}
+
+/// What's kept in the queue file
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Queue {
+    next_id: u64,
+    jobs: Vec<Job>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub socket: PathBuf,
+    pub queue: PathBuf,
+    /// jobs downloading at once
+    pub max_jobs: usize,
+    /// connections across all running jobs
+    pub max_connections: Option<usize>,
+    /// connections per host for hosts without a rule
+    pub per_host: Option<usize>,
+    pub host_rules: Vec<HostRule>,
+    pub retries: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            socket: default_socket_path(),
+            queue: default_queue_path(),
+            max_jobs: 2,
+            max_connections: None,
+            per_host: None,
+            host_rules: vec![],
+            retries: DEFAULT_RETRIES,
+        }
+    }
+}
+
+/// `$XDG_RUNTIME_DIR/donldr.sock`, or `donldr-<user>.sock` in the temp dir
+pub fn default_socket_path() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("donldr.sock"),
+        None => {
+            let user = std::env::var("USER").unwrap_or_else(|_| "default".to_owned());
+            std::env::temp_dir().join(format!("donldr-{}.sock", user))
+        }
+    }
+}
+
+/// `$XDG_STATE_HOME/donldr/queue.json`, or under `~/.local/state`
+pub fn default_queue_path() -> PathBuf {
+    let state = std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state")))
+        .unwrap_or_else(std::env::temp_dir);
+    state.join("donldr").join("queue.json")
+}
+
+/// Sends a single request to a running daemon
+pub async fn request(socket: &Path, request: &Request) -> DResult<Response> {
+    let stream = UnixStream::connect(socket).await.map_err(|e| {
+        Errors::Custom(format!(
+            "Couldn't connect to the daemon at {:?}, is `donldr daemon` running? {}",
+            socket, e
+        ))
+    })?;
+    let (read, mut write) = stream.into_split();
+    let mut line = serde_json::to_vec(request)?;
+    line.push(b'\n');
+    write.write_all(&line).await?;
+
+    let mut lines = BufReader::new(read).lines();
+    match lines.next_line().await? {
+        Some(line) => Ok(serde_json::from_str(&line)?),
+        None => Err("Daemon closed the connection without answering".into()),
+    }
+}
+
+/// Runs the daemon until ctrl-c
+pub async fn serve(config: Config) -> DResult<()> {
+    if config.socket.exists() {
+        if UnixStream::connect(&config.socket).await.is_ok() {
+            return Err(Errors::Custom(format!(
+                "A daemon is already listening on {:?}",
+                config.socket
+            )));
+        }
+        debug!("removing stale socket {:?}", config.socket);
+        tokio::fs::remove_file(&config.socket).await?;
+    }
+    let listener = UnixListener::bind(&config.socket)?;
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&config.socket, std::fs::Permissions::from_mode(0o600))?;
+    }
+    info!("listening on {:?}", config.socket);
+
+    let (request_tx, mut request_rx) = mpsc::channel::<(Request, oneshot::Sender<Response>)>(16);
+    let (finished_tx, mut finished_rx) = mpsc::channel(16);
+    let mut daemon = Daemon::load(&config, finished_tx).await?;
+    daemon.schedule().await;
+
+    loop {
+        tokio::select! {
+            conn = listener.accept() => match conn {
+                Ok((stream, _)) => {
+                    tokio::spawn(handle_client(stream, request_tx.clone()));
+                }
+                Err(e) => warn!("accepting client failed: {}", e),
+            },
+            Some((request, reply)) = request_rx.recv() => {
+                let response = daemon.handle(request).await;
+                let _ = reply.send(response);
+            }
+            Some((id, res)) = finished_rx.recv() => {
+                daemon.finished(id, res).await;
+            }
+            _ = tokio::signal::ctrl_c() => {
+                info!("shutting down");
+                break;
+            }
+        }
+    }
+
+    // let the running jobs save their progress, they're queued again on restart
+    daemon.stopping = true;
+    for control in daemon.running.values() {
+        control.cancel.cancel();
+    }
+    while !daemon.running.is_empty() {
+        match finished_rx.recv().await {
+            Some((id, res)) => daemon.finished(id, res).await,
+            None => break,
+        }
+    }
+    daemon.save().await;
+    let _ = tokio::fs::remove_file(&config.socket).await;
+    Ok(())
+}
+
+async fn handle_client(
+    stream: UnixStream,
+    requests: mpsc::Sender<(Request, oneshot::Sender<Response>)>,
+) {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => {
+                debug!("request: {:?}", request);
+                let (reply_tx, reply_rx) = oneshot::channel();
+                if requests.send((request, reply_tx)).await.is_err() {
+                    break;
+                }
+                match reply_rx.await {
+                    Ok(response) => response,
+                    Err(_) => break,
+                }
+            }
+            Err(e) => Response::Error {
+                message: format!("Invalid request: {}", e),
+            },
+        };
+        let mut line = match serde_json::to_vec(&response) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Failed encoding response: {}", e);
+                break;
+            }
+        };
+        line.push(b'\n');
+        if write.write_all(&line).await.is_err() {
+            break;
+        }
+    }
+}
+
+struct Daemon {
+    queue_path: PathBuf,
+    queue: Queue,
+    running: HashMap<u64, Control>,
+    /// running jobs that were removed, their partial files go once they stop
+    removing: HashMap<u64, Job>,
+    /// set on shutdown so cancelled jobs aren't started again
+    stopping: bool,
+    max_jobs: usize,
+    retries: usize,
+    limits: Arc<Limits>,
+    finished_tx: mpsc::Sender<(u64, DResult<PathBuf>)>,
+}
+
+impl Daemon {
+    async fn load(
+        config: &Config,
+        finished_tx: mpsc::Sender<(u64, DResult<PathBuf>)>,
+    ) -> DResult<Self> {
+        let mut queue = match tokio::fs::read_to_string(&config.queue).await {
+            Ok(text) => serde_json::from_str::<Queue>(&text)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Queue::default(),
+            Err(e) => return Err(e.into()),
+        };
+        // jobs that were running when the daemon went down
+        for job in queue.jobs.iter_mut() {
+            if job.state == JobState::Running {
+                job.state = JobState::Queued;
+            }
+        }
+        info!("loaded {} jobs from {:?}", queue.jobs.len(), config.queue);
+
+        Ok(Daemon {
+            queue_path: config.queue.clone(),
+            queue,
+            running: HashMap::new(),
+            removing: HashMap::new(),
+            stopping: false,
+            max_jobs: config.max_jobs.max(1),
+            retries: config.retries,
+            limits: Arc::new(
+                config
+                    .host_rules
+                    .iter()
+                    .cloned()
+                    .fold(Limits::new(config.max_connections), Limits::rule)
+                    .per_host(config.per_host),
+            ),
+            finished_tx,
+        })
+    }
+
+    /// Writes the queue file, a failure is only logged so the daemon keeps going
+    async fn save(&self) {
+        let write = async {
+            if let Some(dir) = self.queue_path.parent() {
+                tokio::fs::create_dir_all(dir).await?;
+            }
+            let mut tmp = self.queue_path.as_os_str().to_owned();
+            tmp.push(".tmp");
+            tokio::fs::write(&tmp, serde_json::to_vec_pretty(&self.queue)?).await?;
+            tokio::fs::rename(&tmp, &self.queue_path).await?;
+            Ok::<_, Errors>(())
+        };
+        if let Err(e) = write.await {
+            error!("Failed saving queue to {:?}: {:?}", self.queue_path, e);
+        }
+    }
+
+    fn job_mut(&mut self, id: u64) -> Option<&mut Job> {
+        self.queue.jobs.iter_mut().find(|job| job.id == id)
+    }
+
+    async fn handle(&mut self, request: Request) -> Response {
+        let response = match request {
+            Request::Add { url, path, chunks } => {
+                let id = self.queue.next_id;
+                self.queue.next_id += 1;
+                self.queue.jobs.push(Job {
+                    id,
+                    url,
+                    path,
+                    chunks,
+                    state: JobState::Queued,
+                    file: None,
+                    written: 0,
+                    size: 0,
+                });
+                Response::Added { id }
+            }
+            Request::List => {
+                for job in self.queue.jobs.iter_mut() {
+                    if let Some(control) = self.running.get(&job.id) {
+                        job.written = control.written.load(Ordering::Relaxed);
+                        job.size = control.size.load(Ordering::Relaxed);
+                    }
+                }
+                return Response::Jobs {
+                    jobs: self.queue.jobs.clone(),
+                };
+            }
+            Request::Pause { id } => match self.job_mut(id) {
+                Some(job) if matches!(job.state, JobState::Queued | JobState::Running) => {
+                    job.state = JobState::Paused;
+                    if let Some(control) = self.running.get(&id) {
+                        control.cancel.cancel();
+                    }
+                    Response::Ok
+                }
+                Some(job) => Response::Error {
+                    message: format!("Job {} is {:?}, can't pause it", id, job.state),
+                },
+                None => no_such_job(id),
+            },
+            Request::Resume { id } => match self.job_mut(id) {
+                Some(job) if matches!(job.state, JobState::Paused | JobState::Failed(_)) => {
+                    // a paused job that hasn't stopped yet is picked up once it has
+                    job.state = JobState::Queued;
+                    Response::Ok
+                }
+                Some(job) => Response::Error {
+                    message: format!("Job {} is {:?}, can't resume it", id, job.state),
+                },
+                None => no_such_job(id),
+            },
+            Request::Remove { id } => {
+                let Some(idx) = self.queue.jobs.iter().position(|job| job.id == id) else {
+                    return no_such_job(id);
+                };
+                let job = self.queue.jobs.remove(idx);
+                if let Some(control) = self.running.get(&id) {
+                    control.cancel.cancel();
+                    self.removing.insert(id, job);
+                } else if job.state != JobState::Done {
+                    remove_partial(&job).await;
+                }
+                Response::Ok
+            }
+        };
+        self.save().await;
+        self.schedule().await;
+        response
+    }
+
+    async fn finished(&mut self, id: u64, res: DResult<PathBuf>) {
+        let control = self.running.remove(&id);
+        if let Some(job) = self.removing.remove(&id) {
+            if res.is_err() {
+                remove_partial(&job).await;
+            }
+            return;
+        }
+        let Some(job) = self.job_mut(id) else {
+            return;
+        };
+        if let Some(control) = control {
+            job.written = control.written.load(Ordering::Relaxed);
+            job.size = control.size.load(Ordering::Relaxed);
+        }
+        match res {
+            Ok(file) => {
+                info!("job {} done: {:?}", id, file);
+                job.state = JobState::Done;
+                job.file = Some(file);
+            }
+            // pausing set the state already, otherwise the daemon is shutting down
+            Err(Errors::Cancelled) => {
+                if job.state == JobState::Running {
+                    job.state = JobState::Queued;
+                }
+            }
+            Err(e) => {
+                error!("job {} failed: {:?}", id, e);
+                job.state = JobState::Failed(e.to_string());
+            }
+        }
+        self.save().await;
+        self.schedule().await;
+    }
+
+    /// Starts queued jobs in order until `max_jobs` are running
+    async fn schedule(&mut self) {
+        if self.stopping {
+            return;
+        }
+        let mut started = false;
+        for job in self.queue.jobs.iter_mut() {
+            if self.running.len() >= self.max_jobs {
+                break;
+            }
+            if job.state != JobState::Queued || self.running.contains_key(&job.id) {
+                continue;
+            }
+            info!("starting job {}: {}", job.id, redact(&job.url));
+            job.state = JobState::Running;
+            started = true;
+
+            let control = Control::default();
+            self.running.insert(job.id, control.clone());
+            let builder = DownloadBuilder::new(&job.url)
+                .path(&job.path)
+                .chunks(job.chunks)
+                .retries(self.retries);
+            let limits = self.limits.clone();
+            let finished_tx = self.finished_tx.clone();
+            let id = job.id;
+            tokio::spawn(async move {
+                let res = async {
+                    let download = builder.build().await?;
+                    engine::run_with(Arc::new(download), limits, control).await
+                }
+                .await;
+                let _ = finished_tx.send((id, res)).await;
+            });
+        }
+        if started {
+            self.save().await;
+        }
+    }
+}
+
+fn no_such_job(id: u64) -> Response {
+    Response::Error {
+        message: format!("No job with id {}", id),
+    }
+}
+
+async fn remove_partial(job: &Job) {
+    let file_path = determine_file_path(&job.path, &job.url);
+    if let Err(e) = engine::remove_partial(&file_path).await {
+        warn!("Failed removing partial files of job {}: {:?}", job.id, e);
+    }
+}
@@ -0,0 +1,832 @@
+//! MPEG-DASH: the representations of an `.mpd` manifest are turned into
+//! segment lists, from a `SegmentTemplate` (numbered or with a
+//! `SegmentTimeline`), a `SegmentList`, or a `SegmentBase` whose `sidx`
+//! index splits the single file into byte ranges. Every picked
+//! representation, the best video and audio ones unless asked for by id,
+//! is fetched as segments and saved as its own fMP4 file.
+
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+
+use futures::future::try_join_all;
+use quick_xml::{events::Event, XmlVersion};
+use reqwest::{
+    header::{HeaderMap, AUTHORIZATION},
+    Client, Method, Url,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    auth::{redacted, Auth},
+    download::determine_file_path,
+    limits::Limits,
+    segments::{self, Segment},
+    source::send_authorized,
+    DResult, Errors,
+};
+
+/// How representations are picked and fetched
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// ids of the representations to download, the best video and audio
+    /// ones when empty
+    pub representations: Vec<String>,
+    /// take the best video at or below this many bits/s instead of the
+    /// best one
+    pub max_bandwidth: Option<u64>,
+    /// segments downloaded at once, per track
+    pub connections: usize,
+    pub retries: usize,
+    /// logins for the manifest's host, left out when `headers` have an
+    /// `Authorization` of their own
+    pub auth: Option<Arc<Auth>>,
+}
+
+/// Whether `url` points at a DASH manifest
+pub fn is_manifest(url: &str) -> bool {
+    Url::parse(url).is_ok_and(|url| url.path().to_ascii_lowercase().ends_with(".mpd"))
+}
+
+/// An element of the manifest, namespaces dropped
+#[derive(Debug, Default)]
+struct Element {
+    name: String,
+    attrs: HashMap<String, String>,
+    children: Vec<Element>,
+    text: String,
+}
+
+impl Element {
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs.get(name).map(String::as_str)
+    }
+
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|c| c.name == name)
+    }
+
+    fn children<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Element> {
+        self.children.iter().filter(move |c| c.name == name)
+    }
+
+    /// `base` joined with this element's `BaseURL`, if it has one
+    fn base_url(&self, base: &Url) -> DResult<Url> {
+        match self.child("BaseURL").map(|b| b.text.trim()) {
+            Some(href) if !href.is_empty() => base
+                .join(href)
+                .map_err(|e| Errors::Custom(format!("Invalid BaseURL {}: {}", href, e))),
+            _ => Ok(base.clone()),
+        }
+    }
+}
+
+/// How a representation's segments are found
+#[derive(Debug, Clone)]
+enum Addressing {
+    /// `SegmentTemplate`, the attributes merged down from the period, and
+    /// the nearest `SegmentTimeline` as (t, d, r)
+    Template {
+        attrs: HashMap<String, String>,
+        timeline: Option<Vec<(Option<u64>, u64, i64)>>,
+    },
+    /// `SegmentList`, initialization first
+    List(Vec<Segment>),
+    /// `SegmentBase`, one file with an optional `sidx` at `index_range`
+    Base { index_range: Option<(u64, u64)> },
+}
+
+#[derive(Debug, Clone)]
+struct Track {
+    id: String,
+    /// `video`, `audio`, `text`...
+    kind: String,
+    bandwidth: u64,
+    mime_type: String,
+    codecs: String,
+    resolution: Option<String>,
+    lang: Option<String>,
+    url: Url,
+    addressing: Addressing,
+    /// of the period, for templates without a timeline
+    duration: Option<f64>,
+}
+
+/// Downloads the picked representations of the manifest at `url` next to
+/// `path`, each into its own file, returning their paths
+pub async fn download(
+    client: &Client,
+    url: &str,
+    path: &str,
+    headers: &HeaderMap,
+    options: &Options,
+    limits: Arc<Limits>,
+) -> DResult<Vec<PathBuf>> {
+    let mpd_url = Url::parse(url)
+        .map_err(|e| Errors::Custom(format!("Failed parsing url {}: {}", url, e)))?;
+    let auth = options
+        .auth
+        .clone()
+        .filter(|_| !headers.contains_key(AUTHORIZATION));
+    let res = send_authorized(
+        auth.as_deref(),
+        &Method::GET,
+        mpd_url.as_str(),
+        || client.get(mpd_url.clone()).headers(headers.clone()),
+        options.retries,
+    )
+    .await?;
+    // the manifest's relative urls are against where it ended up
+    let mpd_url = res.url().clone();
+    let mpd = parse_xml(&res.text().await?)?;
+    if mpd.name != "MPD" {
+        return Err(Errors::Custom(format!(
+            "{} isn't a DASH manifest",
+            redacted(&mpd_url)
+        )));
+    }
+
+    let tracks = tracks(&mpd, &mpd_url)?;
+    for track in &tracks {
+        info!(
+            "representation {}: {} {} {} bits/s{}{}",
+            track.id,
+            track.kind,
+            track.codecs,
+            track.bandwidth,
+            track
+                .resolution
+                .as_ref()
+                .map(|r| format!(" {}", r))
+                .unwrap_or_default(),
+            track
+                .lang
+                .as_ref()
+                .map(|l| format!(" [{}]", l))
+                .unwrap_or_default()
+        );
+    }
+    let picked = pick_tracks(tracks, options)?;
+
+    let base = determine_file_path(path, url);
+    let stem = base
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "stream".to_owned());
+    let transfer = &segments::Options {
+        connections: options.connections,
+        retries: options.retries,
+        auth: auth.clone(),
+    };
+    try_join_all(picked.into_iter().map(|track| {
+        let file_path = base.with_file_name(format!(
+            "{}.{}.{}",
+            stem,
+            safe_name(&track.id),
+            extension(&track)
+        ));
+        let (limits, auth) = (limits.clone(), auth.clone());
+        async move {
+            let segments = segments_of(
+                client,
+                auth.as_deref(),
+                &track,
+                headers,
+                options.retries,
+                &limits,
+            )
+            .await?;
+            info!(
+                "{} {} segments of representation {} into {:?}",
+                segments.len(),
+                track.kind,
+                track.id,
+                file_path
+            );
+            segments::download(client, segments, &file_path, headers, transfer, limits).await?;
+            Ok(file_path)
+        }
+    }))
+    .await
+}
+
+/// Every representation of the first period
+fn tracks(mpd: &Element, mpd_url: &Url) -> DResult<Vec<Track>> {
+    if mpd.attr("type") == Some("dynamic") {
+        return Err(Errors::Custom(
+            "Live DASH streams aren't supported, only on-demand ones".to_owned(),
+        ));
+    }
+    let mut periods = mpd.children("Period");
+    let period = periods
+        .next()
+        .ok_or_else(|| Errors::Custom("The manifest has no Period".to_owned()))?;
+    if periods.next().is_some() {
+        warn!("The manifest has several periods, only the first is downloaded");
+    }
+    let duration = period
+        .attr("duration")
+        .and_then(parse_duration)
+        .or_else(|| {
+            let total = mpd
+                .attr("mediaPresentationDuration")
+                .and_then(parse_duration)?;
+            let start = period.attr("start").and_then(parse_duration).unwrap_or(0.);
+            Some(total - start)
+        });
+
+    let period_url = period.base_url(&mpd.base_url(mpd_url)?)?;
+    let mut tracks = vec![];
+    for set in period.children("AdaptationSet") {
+        if set.child("ContentProtection").is_some() {
+            warn!(
+                "Adaptation set {} is DRM protected, its files will stay encrypted",
+                set.attr("id").unwrap_or("?")
+            );
+        }
+        let set_url = set.base_url(&period_url)?;
+        for rep in set.children("Representation") {
+            let inherited =
+                |name: &str| rep.attr(name).or_else(|| set.attr(name)).map(str::to_owned);
+            let mime_type = inherited("mimeType").unwrap_or_default();
+            let kind = set
+                .attr("contentType")
+                .map(str::to_owned)
+                .or_else(|| mime_type.split('/').next().map(str::to_owned))
+                .unwrap_or_default();
+            let id = rep
+                .attr("id")
+                .ok_or_else(|| Errors::Custom("A Representation has no id".to_owned()))?
+                .to_owned();
+            let url = rep.base_url(&set_url)?;
+            let levels = [period, set, rep];
+            tracks.push(Track {
+                addressing: addressing(&levels, &url)
+                    .map_err(|e| Errors::Custom(format!("Representation {}: {}", id, e)))?,
+                id,
+                kind,
+                bandwidth: rep
+                    .attr("bandwidth")
+                    .and_then(|b| b.parse().ok())
+                    .unwrap_or(0),
+                mime_type,
+                codecs: inherited("codecs").unwrap_or_default(),
+                resolution: inherited("width")
+                    .zip(inherited("height"))
+                    .map(|(w, h)| format!("{}x{}", w, h)),
+                lang: set.attr("lang").map(str::to_owned),
+                url,
+                duration,
+            });
+        }
+    }
+    Ok(tracks)
+}
+
+/// The segment addressing of the innermost of `levels` that has one, a
+/// template taking the attributes it doesn't set from the outer ones
+fn addressing(levels: &[&Element], url: &Url) -> DResult<Addressing> {
+    let innermost = levels.iter().rposition(|level| {
+        ["SegmentTemplate", "SegmentList", "SegmentBase"]
+            .iter()
+            .any(|name| level.child(name).is_some())
+    });
+    let Some(innermost) = innermost else {
+        return Ok(Addressing::Base { index_range: None });
+    };
+    let level = levels[innermost];
+
+    if level.child("SegmentTemplate").is_some() {
+        let templates: Vec<&Element> = levels[..=innermost]
+            .iter()
+            .filter_map(|level| level.child("SegmentTemplate"))
+            .collect();
+        let mut attrs = HashMap::new();
+        for template in &templates {
+            attrs.extend(template.attrs.clone());
+        }
+        let timeline = templates
+            .iter()
+            .rev()
+            .find_map(|t| t.child("SegmentTimeline"))
+            .map(|timeline| {
+                timeline
+                    .children("S")
+                    .map(|s| {
+                        let number = |name: &str| s.attr(name).and_then(|v| v.parse().ok());
+                        Ok((
+                            number("t"),
+                            number("d").ok_or_else(|| {
+                                Errors::Custom("SegmentTimeline S without d".to_owned())
+                            })?,
+                            s.attr("r").and_then(|r| r.parse().ok()).unwrap_or(0),
+                        ))
+                    })
+                    .collect::<DResult<Vec<_>>>()
+            })
+            .transpose()?;
+        return Ok(Addressing::Template { attrs, timeline });
+    }
+
+    if let Some(list) = level.child("SegmentList") {
+        let join = |href: Option<&str>| match href {
+            Some(href) => url
+                .join(href)
+                .map_err(|e| Errors::Custom(format!("Invalid url {}: {}", href, e))),
+            None => Ok(url.clone()),
+        };
+        let mut segments = vec![];
+        if let Some(init) = list.child("Initialization") {
+            segments.push(Segment {
+                url: join(init.attr("sourceURL"))?,
+                range: init.attr("range").map(parse_range).transpose()?,
+                aes128: None,
+            });
+        }
+        for segment in list.children("SegmentURL") {
+            segments.push(Segment {
+                url: join(segment.attr("media"))?,
+                range: segment.attr("mediaRange").map(parse_range).transpose()?,
+                aes128: None,
+            });
+        }
+        return Ok(Addressing::List(segments));
+    }
+
+    let index_range = level
+        .child("SegmentBase")
+        .and_then(|base| base.attr("indexRange"))
+        .map(parse_range)
+        .transpose()?;
+    Ok(Addressing::Base { index_range })
+}
+
+/// The best video within `max_bandwidth` and the best audio, or the
+/// representations asked for
+fn pick_tracks(tracks: Vec<Track>, options: &Options) -> DResult<Vec<Track>> {
+    if !options.representations.is_empty() {
+        return options
+            .representations
+            .iter()
+            .map(|id| {
+                tracks.iter().find(|t| t.id == *id).cloned().ok_or_else(|| {
+                    Errors::Custom(format!(
+                        "No representation {}, the manifest has {}",
+                        id,
+                        tracks
+                            .iter()
+                            .map(|t| t.id.as_str())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ))
+                })
+            })
+            .collect();
+    }
+
+    let limit = options.max_bandwidth.unwrap_or(u64::MAX);
+    fn of_kind<'a>(tracks: &'a [Track], kind: &'a str) -> impl Iterator<Item = &'a Track> {
+        tracks.iter().filter(move |t| t.kind == kind)
+    }
+    let video = of_kind(&tracks, "video")
+        .filter(|t| t.bandwidth <= limit)
+        .max_by_key(|t| t.bandwidth)
+        .or_else(|| of_kind(&tracks, "video").min_by_key(|t| t.bandwidth));
+    let audio = of_kind(&tracks, "audio").max_by_key(|t| t.bandwidth);
+    let picked: Vec<Track> = video.into_iter().chain(audio).cloned().collect();
+    if !picked.is_empty() {
+        return Ok(picked);
+    }
+    tracks
+        .iter()
+        .max_by_key(|t| t.bandwidth)
+        .map(|t| vec![t.clone()])
+        .ok_or_else(|| Errors::Custom("The manifest has no representations".to_owned()))
+}
+
+/// The track's segments in order, initialization first
+async fn segments_of(
+    client: &Client,
+    auth: Option<&Auth>,
+    track: &Track,
+    headers: &HeaderMap,
+    retries: usize,
+    limits: &Limits,
+) -> DResult<Vec<Segment>> {
+    let segment = |url: Url, range: Option<(u64, u64)>| Segment {
+        url,
+        range,
+        aes128: None,
+    };
+    match &track.addressing {
+        Addressing::List(segments) => Ok(segments.clone()),
+        Addressing::Template { attrs, timeline } => {
+            let join = |template: &str, number: u64, time: u64| {
+                let href = fill_template(template, &track.id, track.bandwidth, number, time)?;
+                track
+                    .url
+                    .join(&href)
+                    .map_err(|e| Errors::Custom(format!("Invalid url {}: {}", href, e)))
+            };
+            let number = |name: &str, default: u64| {
+                attrs
+                    .get(name)
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(default)
+            };
+            let media = attrs.get("media").ok_or_else(|| {
+                Errors::Custom(format!("Representation {} has no media template", track.id))
+            })?;
+            let timescale = number("timescale", 1).max(1);
+            let mut next_number = number("startNumber", 1);
+
+            let mut segments = vec![];
+            if let Some(init) = attrs.get("initialization") {
+                segments.push(segment(join(init, 0, 0)?, None));
+            }
+            match timeline {
+                Some(timeline) => {
+                    let mut time = 0;
+                    for (i, (t, d, r)) in timeline.iter().enumerate() {
+                        time = t.unwrap_or(time);
+                        let repeat = match *r {
+                            // until the next S, or the end of the period
+                            r if r < 0 => {
+                                let end = match timeline.get(i + 1).and_then(|next| next.0) {
+                                    Some(next) => next,
+                                    None => {
+                                        let duration = track.duration.ok_or_else(|| {
+                                            Errors::Custom(
+                                                "Open-ended SegmentTimeline without a period duration"
+                                                    .to_owned(),
+                                            )
+                                        })?;
+                                        (duration * timescale as f64).round() as u64
+                                    }
+                                };
+                                end.saturating_sub(time)
+                                    .div_ceil((*d).max(1))
+                                    .saturating_sub(1)
+                            }
+                            r => r as u64,
+                        };
+                        for _ in 0..=repeat {
+                            segments.push(segment(join(media, next_number, time)?, None));
+                            time += d;
+                            next_number += 1;
+                        }
+                    }
+                }
+                None => {
+                    let duration = number("duration", 0);
+                    let period = track.duration.ok_or_else(|| {
+                        Errors::Custom("Numbered SegmentTemplate without a duration".to_owned())
+                    })?;
+                    if duration == 0 {
+                        return Err(Errors::Custom(format!(
+                            "Representation {} has neither a SegmentTimeline nor a duration",
+                            track.id
+                        )));
+                    }
+                    let count = (period * timescale as f64 / duration as f64).ceil() as u64;
+                    for i in 0..count {
+                        segments.push(segment(join(media, next_number + i, i * duration)?, None));
+                    }
+                }
+            }
+            Ok(segments)
+        }
+        Addressing::Base {
+            index_range: Some((from, to)),
+        } => {
+            let index = segments::fetch(
+                client,
+                auth,
+                &segment(track.url.clone(), Some((*from, *to))),
+                headers,
+                limits,
+                retries,
+            )
+            .await?;
+            let Some(subsegments) = parse_sidx(&index, *from) else {
+                warn!(
+                    "Couldn't read the segment index of {}, downloading it whole",
+                    redacted(&track.url)
+                );
+                return Ok(vec![segment(track.url.clone(), None)]);
+            };
+            debug!(
+                "{} subsegments in {}",
+                subsegments.len(),
+                redacted(&track.url)
+            );
+            // everything up to the end of the index, then every subsegment
+            let mut segments = vec![segment(track.url.clone(), Some((0, *to)))];
+            let mut start = to + 1;
+            for (_, end) in subsegments {
+                segments.push(segment(track.url.clone(), Some((start, end))));
+                start = end + 1;
+            }
+            Ok(segments)
+        }
+        Addressing::Base { index_range: None } => Ok(vec![segment(track.url.clone(), None)]),
+    }
+}
+
+/// Byte ranges of the subsegments a `sidx` box starting at `offset`
+/// indexes, `None` for ones pointing at further indexes
+fn parse_sidx(data: &[u8], offset: u64) -> Option<Vec<(u64, u64)>> {
+    let u32_at = |at: usize| -> Option<u64> {
+        Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?).into())
+    };
+    let u64_at = |at: usize| -> Option<u64> {
+        Some(u64::from_be_bytes(data.get(at..at + 8)?.try_into().ok()?))
+    };
+    if data.get(4..8)? != b"sidx" {
+        return None;
+    }
+    // a size of 1 means a 64-bit largesize follows the type
+    let (size, header) = match u32_at(0)? {
+        1 => (u64_at(8)?, 16),
+        0 => return None,
+        size => (size, 8),
+    };
+    let version = *data.get(header)?;
+    // after version, flags, reference id, timescale and earliest
+    // presentation time
+    let (first_offset, mut at) = match version {
+        0 => (u32_at(header + 16)?, header + 20),
+        _ => (u64_at(header + 20)?, header + 28),
+    };
+    let count = u16::from_be_bytes(data.get(at + 2..at + 4)?.try_into().ok()?);
+    at += 4;
+
+    let mut start = offset.checked_add(size)?.checked_add(first_offset)?;
+    let mut ranges = vec![];
+    for _ in 0..count {
+        let reference = u32_at(at)?;
+        let len = reference & 0x7fff_ffff;
+        // references to other sidx boxes, or to nothing
+        if reference & 0x8000_0000 != 0 || len == 0 {
+            return None;
+        }
+        ranges.push((start, start.checked_add(len - 1)?));
+        start += len;
+        at += 12;
+    }
+    Some(ranges)
+}
+
+/// `$RepresentationID$`, `$Bandwidth$`, `$Number$` and `$Time$`, the last
+/// two with an optional `%0Nd` width, and `$$`
+fn fill_template(
+    template: &str,
+    id: &str,
+    bandwidth: u64,
+    number: u64,
+    time: u64,
+) -> DResult<String> {
+    let invalid = || Errors::Custom(format!("Invalid segment template {}", template));
+    let mut out = String::new();
+    let mut parts = template.split('$');
+    out.push_str(parts.next().unwrap_or_default());
+    while let Some(ident) = parts.next() {
+        let (name, width) = match ident.split_once('%') {
+            Some((name, format)) => {
+                let width = format
+                    .strip_prefix('0')
+                    .unwrap_or(format)
+                    .strip_suffix('d')
+                    .and_then(|w| w.parse::<usize>().ok())
+                    .ok_or_else(invalid)?;
+                (name, width)
+            }
+            None => (ident, 0),
+        };
+        match name {
+            "" => out.push('$'),
+            "RepresentationID" => out.push_str(id),
+            "Bandwidth" => out.push_str(&format!("{:0w$}", bandwidth, w = width)),
+            "Number" => out.push_str(&format!("{:0w$}", number, w = width)),
+            "Time" => out.push_str(&format!("{:0w$}", time, w = width)),
+            _ => return Err(invalid()),
+        }
+        out.push_str(parts.next().ok_or_else(invalid)?);
+    }
+    Ok(out)
+}
+
+/// `PT1H2M3.5S`, in seconds
+fn parse_duration(value: &str) -> Option<f64> {
+    let rest = value.strip_prefix('P')?;
+    let (date, time) = rest.split_once('T').unwrap_or((rest, ""));
+    let mut seconds = 0.;
+    for (part, units) in [
+        (date, &[('D', 86400.)][..]),
+        (time, &[('H', 3600.), ('M', 60.), ('S', 1.)][..]),
+    ] {
+        let mut rest = part;
+        for (unit, scale) in units {
+            if let Some((n, next)) = rest.split_once(*unit) {
+                seconds += n.parse::<f64>().ok()? * scale;
+                rest = next;
+            }
+        }
+        if !rest.is_empty() {
+            return None;
+        }
+    }
+    Some(seconds)
+}
+
+/// `from-to`
+fn parse_range(value: &str) -> DResult<(u64, u64)> {
+    value
+        .split_once('-')
+        .and_then(|(from, to)| Some((from.trim().parse().ok()?, to.trim().parse().ok()?)))
+        .filter(|(from, to)| from <= to)
+        .ok_or_else(|| Errors::Custom(format!("Invalid byte range {}", value)))
+}
+
+fn extension(track: &Track) -> &'static str {
+    match (track.kind.as_str(), track.mime_type.as_str()) {
+        (_, mime) if mime.ends_with("/webm") => "webm",
+        ("audio", _) => "m4a",
+        ("text", _) if track.mime_type == "text/vtt" => "vtt",
+        _ => "mp4",
+    }
+}
+
+/// A representation id usable in a file name
+fn safe_name(id: &str) -> String {
+    id.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+fn parse_xml(xml: &str) -> DResult<Element> {
+    let invalid = |e: quick_xml::Error| Errors::Custom(format!("Invalid manifest: {}", e));
+    let mut reader = quick_xml::Reader::from_str(xml);
+    reader.config_mut().trim_text(true);
+
+    let element = |start: &quick_xml::events::BytesStart| -> DResult<Element> {
+        let mut attrs = HashMap::new();
+        for attr in start.attributes() {
+            let attr = attr.map_err(|e| invalid(e.into()))?;
+            let value = attr
+                .normalized_value(XmlVersion::Implicit1_0)
+                .map_err(invalid)?;
+            attrs.insert(
+                attr.key.local_name().as_ref().to_owned(),
+                value.into_owned(),
+            );
+        }
+        Ok(Element {
+            name: start.local_name().as_ref().to_owned(),
+            attrs,
+            ..Default::default()
+        })
+    };
+    // the elements still open, innermost last
+    let mut open = vec![Element::default()];
+    loop {
+        match reader.read_event().map_err(invalid)? {
+            Event::Start(start) => open.push(element(&start)?),
+            Event::Empty(empty) => {
+                let element = element(&empty)?;
+                if let Some(parent) = open.last_mut() {
+                    parent.children.push(element);
+                }
+            }
+            Event::Text(t) => {
+                if let Some(current) = open.last_mut() {
+                    current.text.push_str(&t.xml10_content());
+                }
+            }
+            Event::GeneralRef(r) => {
+                if let Some(current) = open.last_mut() {
+                    match r.resolve_char_ref().map_err(invalid)? {
+                        Some(c) => current.text.push(c),
+                        None => current.text.push_str(match &*r {
+                            "amp" => "&",
+                            "lt" => "<",
+                            "gt" => ">",
+                            "quot" => "\"",
+                            "apos" => "'",
+                            _ => "",
+                        }),
+                    }
+                }
+            }
+            Event::End(_) => {
+                let done = open.pop().filter(|_| !open.is_empty());
+                match (done, open.last_mut()) {
+                    (Some(done), Some(parent)) => parent.children.push(done),
+                    _ => {
+                        return Err(Errors::Custom(
+                            "Invalid manifest: unbalanced tags".to_owned(),
+                        ))
+                    }
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    open.pop()
+        .filter(|_| open.is_empty())
+        .and_then(|document| document.children.into_iter().next())
+        .ok_or_else(|| Errors::Custom("Invalid manifest: unclosed tags".to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A version 0 `sidx` box with the given reference sizes, `largesize`
+    /// writes its size as the 64-bit variant
+    fn sidx(references: &[u32], first_offset: u32, largesize: bool) -> Vec<u8> {
+        let mut body = vec![0, 0, 0, 0]; // version and flags
+        body.extend(1u32.to_be_bytes()); // reference id
+        body.extend(1000u32.to_be_bytes()); // timescale
+        body.extend(0u32.to_be_bytes()); // earliest presentation time
+        body.extend(first_offset.to_be_bytes());
+        body.extend(0u16.to_be_bytes());
+        body.extend((references.len() as u16).to_be_bytes());
+        for size in references {
+            body.extend(size.to_be_bytes());
+            body.extend([0; 8]); // duration and SAP
+        }
+        let mut data = vec![];
+        match largesize {
+            true => {
+                data.extend(1u32.to_be_bytes());
+                data.extend(b"sidx");
+                data.extend((16 + body.len() as u64).to_be_bytes());
+            }
+            false => {
+                data.extend((8 + body.len() as u32).to_be_bytes());
+                data.extend(b"sidx");
+            }
+        }
+        data.extend(body);
+        data
+    }
+
+    #[test]
+    fn sidx_ranges_follow_the_box() {
+        let data = sidx(&[100, 50], 0, false);
+        let end = 1000 + data.len() as u64;
+        assert_eq!(
+            parse_sidx(&data, 1000),
+            Some(vec![(end, end + 99), (end + 100, end + 149)])
+        );
+        let data = sidx(&[100], 10, false);
+        let start = 1000 + data.len() as u64 + 10;
+        assert_eq!(parse_sidx(&data, 1000), Some(vec![(start, start + 99)]));
+    }
+
+    #[test]
+    fn sidx_largesize() {
+        let data = sidx(&[100, 50], 0, true);
+        let end = data.len() as u64;
+        assert_eq!(
+            parse_sidx(&data, 0),
+            Some(vec![(end, end + 99), (end + 100, end + 149)])
+        );
+    }
+
+    #[test]
+    fn sidx_refuses_empty_and_nested_references() {
+        assert_eq!(parse_sidx(&sidx(&[100, 0], 0, false), 0), None);
+        assert_eq!(parse_sidx(&sidx(&[0x8000_0010], 0, false), 0), None);
+        assert_eq!(parse_sidx(b"\0\0\0\x08moov", 0), None);
+        assert_eq!(parse_sidx(&sidx(&[100], 0, false)[..20], 0), None);
+    }
+
+    #[test]
+    fn templates() {
+        assert_eq!(
+            fill_template("$RepresentationID$/seg-$Number%05d$.m4s", "v1", 0, 42, 0).unwrap(),
+            "v1/seg-00042.m4s"
+        );
+        assert_eq!(
+            fill_template("t$Time$-$Bandwidth$$$.mp4", "v1", 800000, 1, 9000).unwrap(),
+            "t9000-800000$.mp4"
+        );
+        assert!(fill_template("$Nope$", "v1", 0, 1, 0).is_err());
+        assert!(fill_template("$Number", "v1", 0, 1, 0).is_err());
+        assert!(fill_template("$Number%5x$", "v1", 0, 1, 0).is_err());
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("PT1H2M3.5S"), Some(3723.5));
+        assert_eq!(parse_duration("P1DT1S"), Some(86401.));
+        assert_eq!(parse_duration("PT0S"), Some(0.));
+        assert_eq!(parse_duration("PT10.25S"), Some(10.25));
+        assert_eq!(parse_duration("1H"), None);
+        assert_eq!(parse_duration("PT1X"), None);
+    }
+}
@@ -0,0 +1,831 @@
+//! The chunked mmap engine: every range in `Info::ranges` is opened
+//! concurrently from the download's `Source` for the url's scheme and
+//! streamed straight into its own region of a memory mapped `.part` file,
+//! which is moved into place once it's complete.
+//!
+//! Connections are drawn from shared `Limits` so several downloads running
+//! at once stay within one total and per host budget.
+//!
+//! A piece that fails is tried again on the same url, `retries` times
+//! with a growing pause. After that the url has failed for good and its
+//! pieces move to its mirrors, and once there are none left, to the first
+//! fallback url serving the same file.
+//!
+//! Next to the `.part` file a `.part.state` file keeps how much of every
+//! range is on disk, so an interrupted download picks up where it stopped.
+//!
+//! When the ranges are hashed pieces, each one is checked as it finishes
+//! and one that doesn't match is fetched again, counting as a failure of
+//! the source it came from.
+
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::{fs::File, sync::mpsc, task::JoinSet, time::Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    auth::redact,
+    download::{determine_file_path, host_of, part_file_path, Download},
+    limits::Limits,
+    signature, DResult, Errors,
+};
+
+/// How often progress is flushed to disk and the state file
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Handles to a running download from outside the engine
+#[derive(Debug, Clone, Default)]
+pub struct Control {
+    /// stops the download, keeping what's on disk for a later resume
+    pub cancel: CancellationToken,
+    /// bytes downloaded so far, including resumed ones
+    pub written: Arc<AtomicU64>,
+    /// total size, set once the download starts
+    pub size: Arc<AtomicU64>,
+}
+
+/// A url the pieces are downloaded from, the primary one or a mirror
+struct Source {
+    url: String,
+    host: String,
+    /// connections this source may have open
+    slots: usize,
+    busy: usize,
+    /// gave up after retries, it isn't handed pieces anymore
+    failed: bool,
+    bytes: u64,
+}
+
+struct Piece {
+    idx: usize,
+    source: usize,
+}
+
+struct PieceError {
+    idx: usize,
+    source: usize,
+    error: Errors,
+    /// what the piece got is wrong, it starts over instead of resuming
+    discard: bool,
+}
+
+/// What's in a `.part.state` file
+#[derive(Debug, Serialize, Deserialize)]
+struct ResumeState {
+    /// without the password, the file stays on disk
+    url: String,
+    size: u64,
+    validator: Option<String>,
+    ranges: Vec<(u64, u64)>,
+    /// bytes on disk from the start of each range
+    done: Vec<u64>,
+}
+
+impl ResumeState {
+    fn path(part_path: &Path) -> PathBuf {
+        let mut path = part_path.as_os_str().to_owned();
+        path.push(".state");
+        PathBuf::from(path)
+    }
+
+    /// The state left behind by an earlier run, if it belongs to the same
+    /// remote file and has the same chunk plan
+    async fn load(part_path: &Path, download: &Download) -> Option<Vec<u64>> {
+        if !part_path.is_file() {
+            return None;
+        }
+        let text = tokio::fs::read_to_string(Self::path(part_path))
+            .await
+            .ok()?;
+        let state: ResumeState = match serde_json::from_str(&text) {
+            Ok(state) => state,
+            Err(e) => {
+                warn!("Ignoring unreadable resume state: {}", e);
+                return None;
+            }
+        };
+        if state.size != download.info.size
+            || state.validator != download.info.validator()
+            || state.ranges != download.info.ranges
+        {
+            warn!("Remote file or chunks changed since the last run, starting over");
+            return None;
+        }
+        Some(state.done)
+    }
+
+    async fn save(part_path: &Path, download: &Download, done: &[u64]) -> DResult<()> {
+        let state = ResumeState {
+            url: redact(&download.url),
+            size: download.info.size,
+            validator: download.info.validator(),
+            ranges: download.info.ranges.clone(),
+            done: done.to_vec(),
+        };
+        let path = Self::path(part_path);
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        tokio::fs::write(&tmp, serde_json::to_vec(&state)?).await?;
+        tokio::fs::rename(&tmp, &path).await?;
+        Ok(())
+    }
+}
+
+/// Removes the `.part` file and its resume state of an unfinished download
+pub async fn remove_partial(file_path: &Path) -> DResult<()> {
+    let part_path = part_file_path(file_path);
+    for path in [ResumeState::path(&part_path), part_path] {
+        match tokio::fs::remove_file(&path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => debug!("removed {:?}", path),
+        }
+    }
+    Ok(())
+}
+
+/// Downloads every range of `download` into its `.part` file, verifies it
+/// if a signature was given and moves it to the final path, which is
+/// returned. Each range holds a connection from `limits` while its
+/// request is in flight.
+pub async fn run(download: Arc<Download>, limits: Arc<Limits>) -> DResult<PathBuf> {
+    run_with(download, limits, Control::default()).await
+}
+
+/// `run` that can be cancelled and watched through `control`
+pub async fn run_with(
+    download: Arc<Download>,
+    limits: Arc<Limits>,
+    control: Control,
+) -> DResult<PathBuf> {
+    let file_path = determine_file_path(&download.path, &download.url);
+    let part_path = part_file_path(&file_path);
+    debug!("downloading into: {:?}", part_path);
+    if download.skip_unchanged && is_unchanged(&file_path, &download).await {
+        info!("{:?} is up to date, skipping", file_path);
+        return Ok(file_path);
+    }
+    if let Some(dir) = file_path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+
+    let chunks = download.info.chunks;
+    let mut done = match ResumeState::load(&part_path, &download).await {
+        Some(done) => {
+            info!("resuming {:?}: {:?}", part_path, done);
+            done
+        }
+        None => vec![0; chunks],
+    };
+
+    let file = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&part_path)
+        .await?;
+
+    file.set_len(download.info.size).await?;
+
+    // memmap2 maps an empty file too, a 0 byte download has no ranges
+    let mut mmap: memmap2::MmapMut = unsafe { memmap2::MmapMut::map_mut(&file)? };
+
+    let lens: Vec<usize> = download
+        .info
+        .ranges
+        .iter()
+        .map(|(from, to)| (to - from + 1) as usize)
+        .collect();
+    debug!("lens   {:?}", lens);
+    // pieces finished by an earlier run are checked again, the state file
+    // may have been saved before they were verified
+    if let Some(pieces) = &download.info.pieces {
+        for (idx, (from, to)) in download.info.ranges.iter().enumerate() {
+            if done[idx] == lens[idx] as u64 {
+                if let Err(e) = pieces.verify(idx, &mmap[*from as usize..=*to as usize]) {
+                    warn!("{:?}, fetching it again", e);
+                    done[idx] = 0;
+                }
+            }
+        }
+    }
+    let mut stats = Status::new(lens.clone());
+    for (idx, done) in done.iter().enumerate() {
+        stats.add_to(idx, *done as usize);
+    }
+    debug!("stats: {:?}", stats);
+    control.size.store(download.info.size, Ordering::Relaxed);
+    control
+        .written
+        .store(done.iter().sum::<u64>(), Ordering::Relaxed);
+
+    // every source gets an even share of the connections and pulls the next
+    // piece whenever one of its connections frees up, so faster sources end
+    // up with more pieces
+    let urls = download.sources();
+    let mut sources: Vec<Source> = urls
+        .iter()
+        .enumerate()
+        .map(|(i, url)| Source {
+            url: url.clone(),
+            host: host_of(url),
+            slots: (download.connections / urls.len()
+                + usize::from(i < download.connections % urls.len()))
+            .max(1),
+            busy: 0,
+            failed: false,
+            bytes: 0,
+        })
+        .collect();
+    let mut fallbacks = download.fallbacks.iter();
+    // tries of every piece on the source it's on, since it last got there
+    let mut attempts = vec![0; chunks];
+    let mut queue: VecDeque<usize> = (0..chunks)
+        .filter(|idx| done[*idx] < lens[*idx] as u64)
+        .collect();
+
+    let memory = Memory::new(mmap.as_mut_ptr(), mmap.len());
+    let mut downloaders: JoinSet<Result<Piece, PieceError>> = JoinSet::new();
+    let (progress_tx, mut progress_rx) = mpsc::channel(chunks.max(1) * 2);
+    let spawn_piece = |downloaders: &mut JoinSet<_>,
+                       source: usize,
+                       url: String,
+                       host: String,
+                       idx: usize,
+                       skip: u64,
+                       delay: Duration| {
+        let (from, _) = download.get_ranges(idx);
+        let mut chunk = memory.region(from as usize + skip as usize, lens[idx] - skip as usize);
+        let p_tx = progress_tx.clone();
+        let limits = limits.clone();
+        let download = download.clone();
+        downloaders.spawn(async move {
+            let fail = |error: Errors| PieceError {
+                idx,
+                source,
+                error,
+                discard: false,
+            };
+            tokio::time::sleep(delay).await;
+            let _permit = limits.acquire(&host).await.map_err(fail)?;
+            let mut stream = download.open_range(&url, idx, skip).await.map_err(fail)?;
+            let mut written = 0;
+            while let Some(chunk_result) = stream.next().await {
+                let respchunk = chunk_result.map_err(fail)?;
+                let n = std::cmp::min(respchunk.len(), chunk.len - written);
+                chunk.write_at(written, respchunk.as_ptr(), n);
+                written += n;
+                p_tx.send((idx, source, n))
+                    .await
+                    .map_err(|e| fail(Errors::Custom(format!("{:?}", e))))?;
+            }
+            if written < chunk.len {
+                return Err(fail(Errors::Custom(format!(
+                    "Connection closed after {} of {} bytes",
+                    written, chunk.len
+                ))));
+            }
+            Ok(Piece { idx, source })
+        });
+    };
+
+    let start_time = Instant::now();
+    //TODO: indicatif
+    let size = download.info.size as usize;
+    let name = file_path.display().to_string();
+    let mut write_checkp = 0;
+    let mut total_written = done.iter().sum::<u64>() as usize;
+    let mut save_tick = tokio::time::interval(SAVE_INTERVAL);
+    let mut failure = None;
+    loop {
+        // hand queued pieces to free connections, best performing source first
+        let mut order: Vec<usize> = (0..sources.len()).collect();
+        order.sort_by_key(|i| std::cmp::Reverse(sources[*i].bytes));
+        for i in order {
+            let source = &mut sources[i];
+            while !source.failed && source.busy < source.slots {
+                let Some(idx) = queue.pop_front() else { break };
+                source.busy += 1;
+                spawn_piece(
+                    &mut downloaders,
+                    i,
+                    source.url.clone(),
+                    source.host.clone(),
+                    idx,
+                    done[idx],
+                    Duration::ZERO,
+                );
+            }
+        }
+
+        tokio::select! {
+            Some((idx, source, written)) = progress_rx.recv() => {
+                stats.add_to(idx, written);
+                done[idx] += written as u64;
+                sources[source].bytes += written as u64;
+                total_written += written;
+                control.written.fetch_add(written as u64, Ordering::Relaxed);
+                if ((total_written - write_checkp) as f32 / size as f32) * 100. > 1. {
+                    write_checkp = total_written;
+                    info!("{}\n{}", name, stats);
+                }
+            }
+            joined = downloaders.join_next() => match joined
+                .map(|joined| joined.map(|res| res.and_then(|piece| verify_piece(&download, &mmap, piece))))
+            {
+                None => break,
+                Some(Ok(Ok(piece))) => {
+                    info!("done {}", piece.idx);
+                    sources[piece.source].busy -= 1;
+                }
+                Some(Ok(Err(e))) => {
+                    // what the piece got so far is counted before it's picked up again
+                    while let Ok((idx, source, written)) = progress_rx.try_recv() {
+                        stats.add_to(idx, written);
+                        done[idx] += written as u64;
+                        sources[source].bytes += written as u64;
+                        total_written += written;
+                        control.written.fetch_add(written as u64, Ordering::Relaxed);
+                    }
+                    if e.discard {
+                        stats.reset(e.idx);
+                        total_written -= done[e.idx] as usize;
+                        control.written.fetch_sub(done[e.idx], Ordering::Relaxed);
+                        done[e.idx] = 0;
+                    }
+                    // the piece keeps its connection to the source for another try
+                    if attempts[e.idx] < download.retries && !sources[e.source].failed {
+                        attempts[e.idx] += 1;
+                        let source = &sources[e.source];
+                        debug!(
+                            "piece {} failed on {} with {:?}, retrying [{}/{}]",
+                            e.idx,
+                            redact(&source.url),
+                            e.error,
+                            attempts[e.idx],
+                            download.retries
+                        );
+                        spawn_piece(
+                            &mut downloaders,
+                            e.source,
+                            source.url.clone(),
+                            source.host.clone(),
+                            e.idx,
+                            done[e.idx],
+                            Duration::from_millis(250 * attempts[e.idx] as u64),
+                        );
+                        continue;
+                    }
+                    attempts[e.idx] = 0;
+                    let source = &mut sources[e.source];
+                    source.busy -= 1;
+                    source.failed = true;
+                    if sources.iter().all(|source| source.failed) {
+                        let Some(url) = next_fallback(&download, &mut fallbacks).await else {
+                            error!("Chunk failed after retries: {:?}", e.error);
+                            failure = Some(e.error);
+                            break;
+                        };
+                        warn!(
+                            "{} failed after retries, continuing from {}: {:?}",
+                            redact(&sources[e.source].url),
+                            redact(&url),
+                            e.error
+                        );
+                        sources.push(Source {
+                            host: host_of(&url),
+                            url,
+                            slots: download.connections,
+                            busy: 0,
+                            failed: false,
+                            bytes: 0,
+                        });
+                    } else {
+                        warn!(
+                            "{} failed after retries, moving its pieces to the other sources: {:?}",
+                            redact(&sources[e.source].url),
+                            e.error
+                        );
+                    }
+                    queue.push_front(e.idx);
+                }
+                Some(Err(e)) => {
+                    failure = Some(Errors::Custom(format!("{:?}", e)));
+                    break;
+                }
+            },
+            _ = control.cancel.cancelled() => {
+                info!("cancelled {}", name);
+                failure = Some(Errors::Cancelled);
+                break;
+            }
+            _ = save_tick.tick() => {
+                // only what's counted before the flush is known to be on disk
+                let snapshot = done.clone();
+                let saved = match mmap.flush() {
+                    Ok(()) => ResumeState::save(&part_path, &download, &snapshot).await,
+                    Err(e) => Err(e.into()),
+                };
+                if let Err(e) = saved {
+                    failure = Some(e);
+                    break;
+                }
+            }
+        }
+    }
+
+    if let Some(e) = failure {
+        // the tasks write into the mmap, wait for all of them to stop before it's unmapped
+        downloaders.shutdown().await;
+        while let Ok((idx, _, written)) = progress_rx.try_recv() {
+            done[idx] += written as u64;
+        }
+        mmap.flush()?;
+        ResumeState::save(&part_path, &download, &done).await?;
+        debug!("kept progress of {:?} for a resume", part_path);
+        return Err(e);
+    }
+    while let Ok((idx, source, written)) = progress_rx.try_recv() {
+        stats.add_to(idx, written);
+        done[idx] += written as u64;
+        sources[source].bytes += written as u64;
+        control.written.fetch_add(written as u64, Ordering::Relaxed);
+    }
+
+    if sources.len() > 1 {
+        let secs = start_time.elapsed().as_secs_f64().max(f64::EPSILON);
+        for source in &sources {
+            info!(
+                "{}: {:.2} MiB at {:.2} MiB/s{}",
+                redact(&source.url),
+                source.bytes as f64 / 1048576.,
+                source.bytes as f64 / 1048576. / secs,
+                if source.failed { " (failed)" } else { "" }
+            );
+        }
+    }
+    // to compare HTTP/2 streams with the HTTP/1.1 fan-out
+    if download.http.requests.load(Ordering::Relaxed) > 0 {
+        info!("{}: {}", name, download.http);
+    }
+
+    debug!("Mem download finished in {:?}", start_time.elapsed());
+    debug!("all tasks finished and returned");
+
+    let disk_time = Instant::now();
+    mmap.flush()?;
+    debug!("mmap flushed, took {:?}", disk_time.elapsed());
+    debug!("Total download finished in {:?}", start_time.elapsed());
+    if done
+        .iter()
+        .zip(&lens)
+        .any(|(done, len)| *done != *len as u64)
+    {
+        ResumeState::save(&part_path, &download, &done).await?;
+        return Err(Errors::Custom(format!(
+            "Server sent less than requested for {}, got {:?} of {:?}",
+            name, done, lens
+        )));
+    }
+
+    // a complete file that fails verification can't be fixed by resuming it
+    let _ = tokio::fs::remove_file(ResumeState::path(&part_path)).await;
+
+    if let (Some(pubkey), Some(sig)) = (&download.pubkey, &download.signature) {
+        let sig = signature::load_signature(&download, sig).await?;
+        if let Err(e) = signature::verify(pubkey, &sig, &mmap) {
+            error!(
+                "Signature verification failed, leaving {:?} in place: {:?}",
+                part_path, e
+            );
+            return Err(e);
+        }
+    }
+
+    if let Some(checksum) = &download.checksum {
+        if let Err(e) = checksum.verify(&mmap) {
+            error!("{:?}, leaving {:?} in place", e, part_path);
+            return Err(e);
+        }
+    }
+
+    drop(mmap);
+    // stamped with the remote time, so a later run can tell it's unchanged
+    if let Some(modified) = download.info.last_modified() {
+        file.into_std().await.set_modified(modified)?;
+    }
+    tokio::fs::rename(&part_path, &file_path).await?;
+    debug!("moved {:?} -> {:?}", part_path, file_path);
+
+    Ok(file_path)
+}
+
+/// `piece` if it matches its hash, or when the download has none
+fn verify_piece(download: &Download, mmap: &[u8], piece: Piece) -> Result<Piece, PieceError> {
+    let Some(pieces) = &download.info.pieces else {
+        return Ok(piece);
+    };
+    let (from, to) = download.get_ranges(piece.idx);
+    match pieces.verify(piece.idx, &mmap[from as usize..=to as usize]) {
+        Ok(()) => Ok(piece),
+        Err(error) => Err(PieceError {
+            idx: piece.idx,
+            source: piece.source,
+            error,
+            discard: true,
+        }),
+    }
+}
+
+/// Whether `file_path` already holds the remote file, going by its size and
+/// modification time
+async fn is_unchanged(file_path: &Path, download: &Download) -> bool {
+    is_up_to_date(file_path, download.info.size, download.info.last_modified()).await
+}
+
+/// Whether the file at `file_path` has `size` and was stamped with the
+/// remote `modified` time when it finished downloading
+pub async fn is_up_to_date(file_path: &Path, size: u64, modified: Option<SystemTime>) -> bool {
+    let (Ok(metadata), Some(modified)) = (tokio::fs::metadata(file_path).await, modified) else {
+        return false;
+    };
+    metadata.is_file()
+        && metadata.len() == size
+        && metadata.modified().is_ok_and(|local| local == modified)
+}
+
+/// The next fallback that serves the same file, skipping the ones that don't
+async fn next_fallback(
+    download: &Download,
+    fallbacks: &mut std::slice::Iter<'_, String>,
+) -> Option<String> {
+    for url in fallbacks {
+        match download.check_same_file(url).await {
+            Ok(()) => return Some(url.clone()),
+            Err(e) => warn!("Not failing over to {}: {}", redact(url), e),
+        }
+    }
+    None
+}
+
+/// SAFETY:
+///  This type's mutating functions are UNSAFE
+///  it's suppossed to be used with MMAPd memory
+///  address with a known length. If the length
+///  provided is not right or ptr is not a valid
+///  memory region with required permissions it
+///  will cause issues.
+#[allow(dead_code)]
+struct Memory {
+    inner: *mut u8,
+    len: usize,
+    cursor: usize,
+}
+
+impl Memory {
+    ///SAFETY:
+    /// This type assumes it's a mmap memory region
+    /// with required permissions, and a valid len.
+    fn new(ptr: *mut u8, len: usize) -> Self {
+        Memory {
+            inner: ptr,
+            len,
+            cursor: 0,
+        }
+    }
+
+    ///SAFETY:
+    /// This type assumes it's a mmap memory region
+    /// with required permissions.
+    /// If the src to be copied from has length
+    /// less than self.len this function will try
+    /// to access beyond it's length:
+    ///     SIGSEGV ADDRESS BOUNDARY ERROR
+    /// consider using:
+    ///     copy_fill with min(self.len, src.len)
+    #[allow(dead_code)]
+    fn copy_fill_from(&mut self, src: *const u8) {
+        unsafe {
+            self.inner.copy_from(src, self.len);
+        }
+    }
+
+    ///SAFETY:
+    /// If the len doesnt go out of bounds for both
+    /// src and self, assuming this types usecase
+    /// as a mmap memory region and src an accessible
+    /// memory address, it should be safe.
+    /// len should be: min(self.len, src.len)
+    #[allow(dead_code)]
+    fn copy_fill(&mut self, src: *const u8, len: usize) {
+        assert!(
+            len <= self.len,
+            "Can't give a length larger than allocated memory length"
+        );
+        unsafe { self.inner.copy_from(src, len) }
+    }
+
+    /// A sub region of this one, to be handed to a single writer.
+    /// Regions handed out at the same time must not overlap.
+    fn region(&self, offset: usize, len: usize) -> Memory {
+        assert!(
+            offset + len <= self.len,
+            "Region out of bounds, offset+len can't be larger than self.len"
+        );
+        Memory::new(unsafe { self.inner.add(offset) }, len)
+    }
+
+    fn write_at(&mut self, offset: usize, src: *const u8, len: usize) {
+        assert!(
+            offset + len <= self.len,
+            "Writes out of bounds, offset+len can't be larger than self.len"
+        );
+        unsafe { self.inner.add(offset).copy_from(src, len) }
+    }
+}
+
+unsafe impl Send for Memory {}
+unsafe impl Sync for Memory {}
+
+#[derive(Debug)]
+struct Status {
+    progs: Vec<Progress>,
+}
+impl Status {
+    /// total size list must be index ordered
+    fn new(total_size_list: Vec<usize>) -> Self {
+        Status {
+            progs: total_size_list
+                .iter()
+                .map(|chunk_sz| Progress::new(0, *chunk_sz))
+                .collect(),
+        }
+    }
+
+    fn add_to(&mut self, idx: usize, written: usize) {
+        assert!(idx < self.progs.len(), "Indexing beyond existing progs?");
+        self.progs.get_mut(idx).unwrap().add_prog(written);
+    }
+
+    fn reset(&mut self, idx: usize) {
+        self.progs[idx].current = 0;
+    }
+
+    #[allow(dead_code)]
+    fn get_total_written(&self) -> usize {
+        self.progs.iter().fold(0, |a, b| a + b.current)
+    }
+}
+
+/// More ranges than this are shown as a count of finished ones
+const STATUS_COLUMNS: usize = 32;
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.progs.len() > STATUS_COLUMNS {
+            let finished = self.progs.iter().filter(|p| p.current >= p.total).count();
+            let current = self.progs.iter().map(|p| p.current).sum::<usize>();
+            let total = self.progs.iter().map(|p| p.total).sum::<usize>();
+            return write!(
+                f,
+                "[{}/{} pieces, {:.1}%]",
+                finished,
+                self.progs.len(),
+                current as f32 / total.max(1) as f32 * 100.
+            );
+        }
+        write!(f, "[")?;
+        for pg in 0..self.progs.len() - 1 {
+            write!(f, "{:^4}|", pg)?;
+        }
+        writeln!(f, "|{:^4}]", self.progs.len() - 1)?;
+        write!(f, "[")?;
+        for pg in 0..self.progs.len() - 1 {
+            write!(
+                f,
+                "{:^4}%|",
+                self.progs.get(pg).unwrap().percentage() * 100.
+            )?;
+        }
+        write!(
+            f,
+            "|{:^4}%]",
+            self.progs.last().unwrap().percentage() * 100.
+        )
+    }
+}
+
+#[derive(Debug)]
+struct Progress {
+    current: usize,
+    total: usize,
+}
+
+impl Progress {
+    fn new(current: usize, total: usize) -> Self {
+        Progress { current, total }
+    }
+    fn percentage(&self) -> f32 {
+        self.current as f32 / self.total as f32
+    }
+    fn add_prog(&mut self, written: usize) {
+        if self.current + written > self.total {
+            // error!("Progress overflow?, got more chunks than expected?: {}/{} wanted to write {} more -> !{}<={}", self.current, self.total, written, written+self.current, self.total);
+        }
+        // assert!(written+self.current <= self.total, "Progress overflow?, got more chunks than expected");
+        self.current += written
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use bytes::Bytes;
+    use futures::{future::BoxFuture, stream, FutureExt};
+
+    use super::*;
+    use crate::{
+        download::DownloadBuilder,
+        source::{ByteStream, Meta, Source},
+    };
+
+    /// Serves `data`, breaking off the first range it's asked for halfway
+    #[derive(Debug)]
+    struct Flaky {
+        data: Bytes,
+        opened: AtomicUsize,
+    }
+
+    impl Source for Flaky {
+        fn probe<'a>(&'a self, url: &'a str) -> BoxFuture<'a, DResult<Meta>> {
+            async move {
+                Ok(Meta {
+                    url: url.to_owned(),
+                    size: self.data.len() as u64,
+                    accept_ranges: Some(true),
+                    ..Meta::default()
+                })
+            }
+            .boxed()
+        }
+
+        fn open_range<'a>(
+            &'a self,
+            _url: &'a str,
+            (from, to): (u64, u64),
+        ) -> BoxFuture<'a, DResult<ByteStream>> {
+            async move {
+                let range = self.data.slice(from as usize..=to as usize);
+                let stream: ByteStream = match self.opened.fetch_add(1, Ordering::SeqCst) {
+                    0 => stream::iter([
+                        Ok(range.slice(..range.len() / 2)),
+                        Err(Errors::Custom("connection reset".to_owned())),
+                    ])
+                    .boxed(),
+                    _ => stream::iter([Ok(range)]).boxed(),
+                };
+                Ok(stream)
+            }
+            .boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn a_failed_piece_is_retried_on_the_same_source() {
+        let dir = std::env::temp_dir().join(format!("donldr-engine-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let data: Bytes = (0..64u8).collect::<Vec<_>>().into();
+        let source = Arc::new(Flaky {
+            data: data.clone(),
+            opened: AtomicUsize::new(0),
+        });
+        let download = DownloadBuilder::new("flaky://example/file.bin")
+            .source("flaky", source.clone())
+            .path(dir.to_string_lossy())
+            .chunks(4)
+            .retries(1)
+            .build()
+            .await
+            .unwrap();
+
+        let path = run(Arc::new(download), Arc::new(Limits::default()))
+            .await
+            .unwrap();
+        assert_eq!(tokio::fs::read(&path).await.unwrap(), data);
+        // every piece once, and the broken one again
+        assert_eq!(source.opened.load(Ordering::SeqCst), 5);
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,189 @@
+//! `ftp://` downloads. Every piece opens its own control and data
+//! connection, skips to its start with `REST` and reads its length off a
+//! `RETR`, so FTP files are split into ranges like HTTP ones.
+//!
+//! The probe reads the size with `SIZE` and the modification time with
+//! `MDTM`, which is what the range plan and the resume state are built from.
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use futures::{future::BoxFuture, FutureExt, StreamExt, TryStreamExt};
+use percent_encoding::percent_decode_str;
+use reqwest::Url;
+use suppaftp::{
+    async_native_tls::TlsConnector,
+    tokio::{
+        AsyncNativeTlsConnector, AsyncNativeTlsFtpStream, AsyncNativeTlsStream, TransferStream,
+    },
+    types::FileType,
+    FtpError,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+use tokio_util::io::ReaderStream;
+use tracing::debug;
+
+use crate::{
+    auth::redacted,
+    download::format_http_date,
+    source::{ByteStream, Meta, Source},
+    DResult, Errors,
+};
+
+/// How the control and data connections are made
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Options {
+    /// switch to TLS with `AUTH TLS` after connecting (explicit FTPS)
+    pub explicit_tls: bool,
+    /// let the server connect back for data instead of passive mode
+    pub active: bool,
+}
+
+/// Logged in, binary mode session and the path of the file on the server
+async fn connect(url: &Url, options: Options) -> DResult<(AsyncNativeTlsFtpStream, String)> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| Errors::Custom(format!("No host in {}", url)))?;
+    let port = url.port().unwrap_or(21);
+    let mut ftp = AsyncNativeTlsFtpStream::connect((host, port)).await?;
+    if options.explicit_tls {
+        ftp = ftp
+            .into_secure(AsyncNativeTlsConnector::from(TlsConnector::new()), host)
+            .await?;
+    }
+    if options.active {
+        ftp = ftp.active_mode(Duration::from_secs(30));
+    }
+    let decode = |s: &str| percent_decode_str(s).decode_utf8_lossy().into_owned();
+    let (user, password) = match url.username() {
+        "" => ("anonymous".to_owned(), "anonymous@".to_owned()),
+        user => (decode(user), decode(url.password().unwrap_or(""))),
+    };
+    ftp.login(&user, &password).await?;
+    ftp.transfer_type(FileType::Binary).await?;
+    Ok((ftp, decode(url.path())))
+}
+
+/// Runs `f` again on connection errors and transient (4xx) replies, like
+/// `send_with_retry` does for HTTP. Permanent (5xx) replies such as
+/// `550 No such file` fail right away.
+async fn with_retry<T, F, Fut>(retries: usize, f: F) -> DResult<T>
+where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = DResult<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        match f().await {
+            Ok(value) => return Ok(value),
+            Err(Errors::Ftp(FtpError::UnexpectedResponse(res))) if res.status.code() >= 500 => {
+                return Err(Errors::Ftp(FtpError::UnexpectedResponse(res)))
+            }
+            Err(e) if attempt >= retries => return Err(e),
+            Err(e) => {
+                attempt += 1;
+                debug!("FTP failed with {}, retrying [{}/{}]", e, attempt, retries);
+                tokio::time::sleep(Duration::from_millis(250 * attempt as u64)).await;
+            }
+        }
+    }
+}
+
+/// [`Source`] for `ftp://` urls
+#[derive(Debug, Clone, Copy)]
+pub struct Ftp {
+    options: Options,
+    retries: usize,
+}
+
+impl Ftp {
+    pub fn new(options: Options, retries: usize) -> Self {
+        Ftp { options, retries }
+    }
+
+    /// `SIZE` and `MDTM` of the file
+    pub async fn probe(&self, url: &str) -> DResult<Meta> {
+        let url = Url::parse(url)
+            .map_err(|e| Errors::Custom(format!("Failed parsing url {}: {}", url, e)))?;
+        with_retry(self.retries, || async {
+            let (mut ftp, path) = connect(&url, self.options).await?;
+            let size = ftp.size(&path).await?;
+            // not every server has MDTM, the file is only harder to resume without it
+            let modified = ftp.mdtm(&path).await.ok();
+            let _ = ftp.quit().await;
+            debug!(
+                "{}: {} bytes, modified {:?}",
+                redacted(&url),
+                size,
+                modified
+            );
+
+            let modified = modified
+                .and_then(|modified| u64::try_from(modified.and_utc().timestamp()).ok())
+                .map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+            Ok(Meta {
+                url: url.to_string(),
+                size: size as u64,
+                accept_ranges: Some(true),
+                validator: modified.map(format_http_date),
+                last_modified: modified,
+                headers: Default::default(),
+            })
+        })
+        .await
+    }
+
+    /// The inclusive byte range `from..=to` of the file
+    pub async fn open_range(&self, url: &str, (from, to): (u64, u64)) -> DResult<ByteStream> {
+        let url = Url::parse(url)
+            .map_err(|e| Errors::Custom(format!("Failed parsing url {}: {}", url, e)))?;
+        let transfer = with_retry(self.retries, || async {
+            let (mut ftp, path) = connect(&url, self.options).await?;
+            if from > 0 {
+                ftp.resume_transfer(from as usize).await?;
+            }
+            let data = ftp.retr_as_stream(&path).await?;
+            Ok(Transfer { data, _ftp: ftp })
+        })
+        .await?;
+        // RETR sends everything to the end of the file, both connections
+        // are dropped once the range is read
+        Ok(ReaderStream::new(transfer.take(to - from + 1))
+            .map_err(Errors::from)
+            .boxed())
+    }
+}
+
+impl Source for Ftp {
+    fn probe<'a>(&'a self, url: &'a str) -> BoxFuture<'a, DResult<Meta>> {
+        self.probe(url).boxed()
+    }
+
+    fn open_range<'a>(
+        &'a self,
+        url: &'a str,
+        range: (u64, u64),
+    ) -> BoxFuture<'a, DResult<ByteStream>> {
+        self.open_range(url, range).boxed()
+    }
+}
+
+/// A `RETR` in progress, keeping its control connection up until it's
+/// dropped
+struct Transfer {
+    data: TransferStream<AsyncNativeTlsStream>,
+    _ftp: AsyncNativeTlsFtpStream,
+}
+
+impl AsyncRead for Transfer {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.data).poll_read(cx, buf)
+    }
+}
@@ -0,0 +1,562 @@
+//! HLS streams: an `.m3u8` master playlist is narrowed down to one variant,
+//! the highest bandwidth one unless capped, and the media segments of its
+//! playlist are downloaded in parallel, decrypted when they're AES-128
+//! encrypted and joined in order into a single `.ts` file.
+
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+
+use reqwest::{
+    header::{HeaderMap, AUTHORIZATION},
+    Client, Method, Url,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    auth::{redacted, Auth},
+    download::determine_file_path,
+    limits::Limits,
+    segments,
+    source::send_authorized,
+    DResult, Errors,
+};
+
+/// How a stream is picked and fetched
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// take the best variant at or below this many bits/s instead of the
+    /// best one
+    pub max_bandwidth: Option<u64>,
+    /// segments downloaded at once
+    pub connections: usize,
+    pub retries: usize,
+    /// logins for the playlist's host, left out when `headers` have an
+    /// `Authorization` of their own
+    pub auth: Option<Arc<Auth>>,
+}
+
+/// Whether `url` points at an HLS playlist
+pub fn is_playlist(url: &str) -> bool {
+    Url::parse(url).is_ok_and(|url| url.path().to_ascii_lowercase().ends_with(".m3u8"))
+}
+
+/// A stream of a master playlist
+#[derive(Debug, Clone)]
+struct Variant {
+    url: Url,
+    /// peak bits/s
+    bandwidth: u64,
+    resolution: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Key {
+    url: Url,
+    /// the segment's media sequence number is the IV without one
+    iv: Option<[u8; 16]>,
+}
+
+#[derive(Debug, Clone)]
+struct Segment {
+    url: Url,
+    /// inclusive, for segments that are a part of a bigger file
+    range: Option<(u64, u64)>,
+    key: Option<Key>,
+    sequence: u64,
+}
+
+struct Media {
+    /// in playback order, initialization sections included
+    segments: Vec<Segment>,
+    /// `EXT-X-ENDLIST`, a live playlist keeps growing
+    ended: bool,
+}
+
+/// Downloads the stream at `url` into a `.ts` file under `path`, which is
+/// returned. Every segment holds a connection from `limits` while it's
+/// being fetched.
+pub async fn download(
+    client: &Client,
+    url: &str,
+    path: &str,
+    headers: &HeaderMap,
+    options: &Options,
+    limits: Arc<Limits>,
+) -> DResult<PathBuf> {
+    let mut playlist_url = Url::parse(url)
+        .map_err(|e| Errors::Custom(format!("Failed parsing url {}: {}", url, e)))?;
+    let auth = options
+        .auth
+        .clone()
+        .filter(|_| !headers.contains_key(AUTHORIZATION));
+    let mut playlist = fetch_text(
+        client,
+        auth.as_deref(),
+        &playlist_url,
+        headers,
+        options.retries,
+    )
+    .await?;
+    if !playlist.starts_with("#EXTM3U") {
+        return Err(Errors::Custom(format!(
+            "{} isn't an HLS playlist",
+            redacted(&playlist_url)
+        )));
+    }
+    if playlist.contains("#EXT-X-STREAM-INF") {
+        if playlist
+            .lines()
+            .any(|line| line.starts_with("#EXT-X-MEDIA:") && line.contains("URI="))
+        {
+            warn!(
+                "{} has separate audio or subtitle renditions, only the variant's own segments are downloaded",
+                redacted(&playlist_url)
+            );
+        }
+        let variant = pick_variant(
+            parse_master(&playlist, &playlist_url),
+            options.max_bandwidth,
+        )
+        .ok_or_else(|| Errors::Custom(format!("{} lists no streams", redacted(&playlist_url))))?;
+        info!(
+            "picked the {} bits/s{} variant {}",
+            variant.bandwidth,
+            variant
+                .resolution
+                .map(|r| format!(" {}", r))
+                .unwrap_or_default(),
+            redacted(&variant.url)
+        );
+        playlist_url = variant.url;
+        playlist = fetch_text(
+            client,
+            auth.as_deref(),
+            &playlist_url,
+            headers,
+            options.retries,
+        )
+        .await?;
+    }
+
+    let media = parse_media(&playlist, &playlist_url)?;
+    if media.segments.is_empty() {
+        return Err(Errors::Custom(format!("{} has no segments", playlist_url)));
+    }
+    if !media.ended {
+        warn!(
+            "{} is live, downloading the {} segments it lists now",
+            playlist_url,
+            media.segments.len()
+        );
+    }
+    let keys = fetch_keys(
+        client,
+        auth.as_deref(),
+        &media.segments,
+        headers,
+        options.retries,
+    )
+    .await?;
+    let segments = media
+        .segments
+        .into_iter()
+        .map(|segment| segments::Segment {
+            aes128: segment.key.map(|key| {
+                let iv = key
+                    .iv
+                    .unwrap_or_else(|| u128::from(segment.sequence).to_be_bytes());
+                (keys[&key.url], iv)
+            }),
+            url: segment.url,
+            range: segment.range,
+        })
+        .collect();
+
+    let mut file_path = determine_file_path(path, url);
+    if file_path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("m3u8"))
+    {
+        file_path.set_extension("ts");
+    }
+    let transfer = segments::Options {
+        connections: options.connections,
+        retries: options.retries,
+        auth,
+    };
+    segments::download(client, segments, &file_path, headers, &transfer, limits).await?;
+    Ok(file_path)
+}
+
+async fn fetch_text(
+    client: &Client,
+    auth: Option<&Auth>,
+    url: &Url,
+    headers: &HeaderMap,
+    retries: usize,
+) -> DResult<String> {
+    debug!("fetching playlist {}", redacted(url));
+    Ok(send_authorized(
+        auth,
+        &Method::GET,
+        url.as_str(),
+        || client.get(url.clone()).headers(headers.clone()),
+        retries,
+    )
+    .await?
+    .text()
+    .await?)
+}
+
+/// The best variant within `max_bandwidth`, or the leanest one when all
+/// of them are over it
+fn pick_variant(variants: Vec<Variant>, max_bandwidth: Option<u64>) -> Option<Variant> {
+    let limit = max_bandwidth.unwrap_or(u64::MAX);
+    let lowest = variants.iter().min_by_key(|v| v.bandwidth).cloned();
+    let best = variants
+        .into_iter()
+        .filter(|v| v.bandwidth <= limit)
+        .max_by_key(|v| v.bandwidth);
+    if best.is_none() {
+        warn!("Every variant is over {} bits/s, taking the lowest", limit);
+    }
+    best.or(lowest)
+}
+
+fn parse_master(text: &str, base: &Url) -> Vec<Variant> {
+    let mut variants = vec![];
+    let mut pending: Option<HashMap<String, String>> = None;
+    for line in text.lines().map(str::trim) {
+        if let Some(list) = line.strip_prefix("#EXT-X-STREAM-INF:") {
+            pending = Some(attributes(list));
+        } else if line.is_empty() || line.starts_with('#') {
+            continue;
+        } else if let Some(attrs) = pending.take() {
+            let Ok(url) = base.join(line) else {
+                warn!("Skipping variant with invalid uri {}", line);
+                continue;
+            };
+            variants.push(Variant {
+                url,
+                bandwidth: attrs
+                    .get("BANDWIDTH")
+                    .and_then(|b| b.parse().ok())
+                    .unwrap_or(0),
+                resolution: attrs.get("RESOLUTION").cloned(),
+            });
+        }
+    }
+    debug!("variants: {:#?}", variants);
+    variants
+}
+
+fn parse_media(text: &str, base: &Url) -> DResult<Media> {
+    let invalid =
+        |what: &str, line: &str| Errors::Custom(format!("Invalid {} in playlist: {}", what, line));
+    let join = |uri: &str| {
+        base.join(uri)
+            .map_err(|e| Errors::Custom(format!("Invalid uri {} in playlist: {}", uri, e)))
+    };
+
+    let mut media = Media {
+        segments: vec![],
+        ended: false,
+    };
+    let mut sequence = 0;
+    let mut key = None;
+    let mut map: Option<Segment> = None;
+    let mut range: Option<(u64, Option<u64>)> = None;
+    // where the last sub-range ended, a range without offset continues there
+    let mut last_end: Option<(Url, u64)> = None;
+    for line in text.lines().map(str::trim) {
+        if let Some(n) = line.strip_prefix("#EXT-X-MEDIA-SEQUENCE:") {
+            sequence = n.parse().map_err(|_| invalid("media sequence", line))?;
+        } else if let Some(list) = line.strip_prefix("#EXT-X-KEY:") {
+            let attrs = attributes(list);
+            key = match attrs.get("METHOD").map(String::as_str) {
+                Some("NONE") => None,
+                Some("AES-128") => Some(Key {
+                    url: join(attrs.get("URI").ok_or_else(|| invalid("key", line))?)?,
+                    iv: attrs
+                        .get("IV")
+                        .map(|iv| parse_iv(iv).ok_or_else(|| invalid("IV", line)))
+                        .transpose()?,
+                }),
+                Some(method) => {
+                    return Err(Errors::Custom(format!(
+                        "{} encryption isn't supported, only AES-128",
+                        method
+                    )))
+                }
+                None => return Err(invalid("key", line)),
+            };
+        } else if let Some(list) = line.strip_prefix("#EXT-X-MAP:") {
+            let attrs = attributes(list);
+            let segment = Segment {
+                url: join(attrs.get("URI").ok_or_else(|| invalid("map", line))?)?,
+                range: attrs
+                    .get("BYTERANGE")
+                    .map(|r| match parse_byterange(r) {
+                        Some((len, Some(offset))) if len > 0 => offset
+                            .checked_add(len - 1)
+                            .map(|to| (offset, to))
+                            .ok_or_else(|| invalid("map byte range", line)),
+                        _ => Err(invalid("map byte range", line)),
+                    })
+                    .transpose()?,
+                key: key.clone(),
+                sequence,
+            };
+            // repeated before every segment by some packagers, only a new one goes in
+            let changed = map
+                .as_ref()
+                .is_none_or(|m| m.url != segment.url || m.range != segment.range);
+            if changed {
+                media.segments.push(segment.clone());
+                map = Some(segment);
+            }
+        } else if let Some(r) = line.strip_prefix("#EXT-X-BYTERANGE:") {
+            range = Some(parse_byterange(r).ok_or_else(|| invalid("byte range", line))?);
+        } else if line == "#EXT-X-ENDLIST" {
+            media.ended = true;
+        } else if line.is_empty() || line.starts_with('#') {
+            continue;
+        } else {
+            let url = join(line)?;
+            let range = match range.take() {
+                Some((0, _)) => return Err(invalid("byte range", line)),
+                Some((len, offset)) => {
+                    let from = match (offset, &last_end) {
+                        (Some(offset), _) => offset,
+                        (None, Some((last, end))) if *last == url => *end,
+                        _ => return Err(invalid("byte range without offset", line)),
+                    };
+                    let to = from
+                        .checked_add(len - 1)
+                        .ok_or_else(|| invalid("byte range", line))?;
+                    Some((from, to))
+                }
+                None => None,
+            };
+            last_end = range.map(|(_, to)| (url.clone(), to.saturating_add(1)));
+            media.segments.push(Segment {
+                url,
+                range,
+                key: key.clone(),
+                sequence,
+            });
+            sequence += 1;
+        }
+    }
+    Ok(media)
+}
+
+/// `NAME=value,NAME="quoted, value"` attribute lists
+fn attributes(list: &str) -> HashMap<String, String> {
+    let mut attrs = HashMap::new();
+    let mut rest = list.trim();
+    while let Some((name, value)) = rest.split_once('=') {
+        let (value, next) = match value.strip_prefix('"') {
+            Some(quoted) => match quoted.split_once('"') {
+                Some((value, next)) => (value, next),
+                None => (quoted, ""),
+            },
+            None => value
+                .split_once(',')
+                .map_or((value, ""), |(v, next)| (v, next)),
+        };
+        attrs.insert(name.trim().to_owned(), value.to_owned());
+        rest = next.trim_start_matches(',').trim();
+    }
+    attrs
+}
+
+/// `length[@offset]`
+fn parse_byterange(value: &str) -> Option<(u64, Option<u64>)> {
+    match value.split_once('@') {
+        Some((len, offset)) => Some((len.parse().ok()?, Some(offset.parse().ok()?))),
+        None => Some((value.parse().ok()?, None)),
+    }
+}
+
+/// A `0x`-prefixed 128 bit hex IV
+fn parse_iv(value: &str) -> Option<[u8; 16]> {
+    let hex = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))?;
+    let hex = format!("{:0>32}", hex);
+    hex::decode(hex).ok()?.try_into().ok()
+}
+
+/// Every key the segments are encrypted with, by url
+async fn fetch_keys(
+    client: &Client,
+    auth: Option<&Auth>,
+    segments: &[Segment],
+    headers: &HeaderMap,
+    retries: usize,
+) -> DResult<HashMap<Url, [u8; 16]>> {
+    let mut keys = HashMap::new();
+    for key in segments.iter().filter_map(|s| s.key.as_ref()) {
+        if keys.contains_key(&key.url) {
+            continue;
+        }
+        debug!("fetching key {}", redacted(&key.url));
+        let bytes = send_authorized(
+            auth,
+            &Method::GET,
+            key.url.as_str(),
+            || client.get(key.url.clone()).headers(headers.clone()),
+            retries,
+        )
+        .await?
+        .bytes()
+        .await?;
+        let value = <[u8; 16]>::try_from(bytes.as_ref()).map_err(|_| {
+            Errors::Custom(format!(
+                "Key {} is {} bytes, AES-128 keys are 16",
+                redacted(&key.url),
+                bytes.len()
+            ))
+        })?;
+        keys.insert(key.url.clone(), value);
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base() -> Url {
+        Url::parse("https://cdn.example.org/live/master.m3u8").unwrap()
+    }
+
+    const MASTER: &str = "#EXTM3U
+#EXT-X-STREAM-INF:BANDWIDTH=1280000,RESOLUTION=640x360,CODECS=\"avc1.4d401e,mp4a.40.2\"
+low/index.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=5000000,RESOLUTION=1920x1080
+https://other.example.org/high/index.m3u8
+#EXT-X-STREAM-INF:RESOLUTION=1280x720,BANDWIDTH=2560000
+mid/index.m3u8
+";
+
+    #[test]
+    fn master_variants() {
+        let variants = parse_master(MASTER, &base());
+        let urls: Vec<&str> = variants.iter().map(|v| v.url.as_str()).collect();
+        assert_eq!(
+            urls,
+            [
+                "https://cdn.example.org/live/low/index.m3u8",
+                "https://other.example.org/high/index.m3u8",
+                "https://cdn.example.org/live/mid/index.m3u8",
+            ]
+        );
+        assert_eq!(variants[0].bandwidth, 1280000);
+        assert_eq!(variants[2].bandwidth, 2560000);
+        assert_eq!(variants[2].resolution.as_deref(), Some("1280x720"));
+    }
+
+    #[test]
+    fn picks_best_variant_within_bandwidth() {
+        let variants = || parse_master(MASTER, &base());
+        let pick = |max| pick_variant(variants(), max).unwrap().bandwidth;
+        assert_eq!(pick(None), 5000000);
+        assert_eq!(pick(Some(3000000)), 2560000);
+        // all of them over it, the leanest one
+        assert_eq!(pick(Some(1000)), 1280000);
+        assert!(pick_variant(vec![], None).is_none());
+    }
+
+    #[test]
+    fn media_segments_keys_and_maps() {
+        let text = "#EXTM3U
+#EXT-X-MEDIA-SEQUENCE:7
+#EXT-X-MAP:URI=\"init.mp4\"
+#EXT-X-KEY:METHOD=AES-128,URI=\"key1\",IV=0x1
+#EXTINF:4.0,
+seg7.m4s
+#EXT-X-MAP:URI=\"init.mp4\"
+#EXTINF:4.0,
+seg8.m4s
+#EXT-X-KEY:METHOD=NONE
+#EXTINF:4.0,
+seg9.m4s
+#EXT-X-ENDLIST
+";
+        let media = parse_media(text, &base()).unwrap();
+        assert!(media.ended);
+        let names: Vec<&str> = media
+            .segments
+            .iter()
+            .map(|s| s.url.path_segments().unwrap().next_back().unwrap())
+            .collect();
+        // the repeated map only goes in once
+        assert_eq!(names, ["init.mp4", "seg7.m4s", "seg8.m4s", "seg9.m4s"]);
+        let sequences: Vec<u64> = media.segments.iter().map(|s| s.sequence).collect();
+        assert_eq!(sequences, [7, 7, 8, 9]);
+        let key = media.segments[1].key.as_ref().unwrap();
+        assert_eq!(key.url.as_str(), "https://cdn.example.org/live/key1");
+        let mut iv = [0; 16];
+        iv[15] = 1;
+        assert_eq!(key.iv, Some(iv));
+        assert_eq!(media.segments[2].key, media.segments[1].key);
+        assert_eq!(media.segments[3].key, None);
+    }
+
+    #[test]
+    fn byte_ranges_continue_on_the_same_file() {
+        let text = "#EXT-X-MAP:URI=\"main.mp4\",BYTERANGE=\"720@0\"
+#EXT-X-BYTERANGE:1000@720
+main.mp4
+#EXT-X-BYTERANGE:500
+main.mp4
+main.mp4
+";
+        let media = parse_media(text, &base()).unwrap();
+        assert!(!media.ended);
+        let ranges: Vec<_> = media.segments.iter().map(|s| s.range).collect();
+        assert_eq!(
+            ranges,
+            [Some((0, 719)), Some((720, 1719)), Some((1720, 2219)), None]
+        );
+    }
+
+    #[test]
+    fn bad_media_playlists_are_refused() {
+        for text in [
+            "#EXT-X-KEY:METHOD=SAMPLE-AES,URI=\"k\"\nseg.ts\n",
+            "#EXT-X-KEY:URI=\"k\"\nseg.ts\n",
+            "#EXT-X-BYTERANGE:0@0\nseg.ts\n",
+            "#EXT-X-BYTERANGE:10\nseg.ts\n",
+            "#EXT-X-BYTERANGE:10@0\na.ts\n#EXT-X-BYTERANGE:10\nb.ts\n",
+            "#EXT-X-BYTERANGE:2@18446744073709551615\nseg.ts\n",
+            "#EXT-X-MAP:URI=\"init.mp4\",BYTERANGE=\"0@0\"\n",
+            "#EXT-X-MEDIA-SEQUENCE:first\n",
+        ] {
+            assert!(parse_media(text, &base()).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn attribute_lists() {
+        let attrs = attributes(r#"METHOD=AES-128,URI="https://k.example.org/a?b=1,c=2",IV=0x0A"#);
+        assert_eq!(attrs["METHOD"], "AES-128");
+        assert_eq!(attrs["URI"], "https://k.example.org/a?b=1,c=2");
+        assert_eq!(attrs["IV"], "0x0A");
+    }
+
+    #[test]
+    fn byteranges_and_ivs() {
+        assert_eq!(parse_byterange("1000@720"), Some((1000, Some(720))));
+        assert_eq!(parse_byterange("500"), Some((500, None)));
+        assert_eq!(parse_byterange("a@1"), None);
+        assert_eq!(
+            parse_iv("0X000102030405060708090a0b0c0d0e0f"),
+            Some([0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15])
+        );
+        assert_eq!(parse_iv("000102"), None);
+        assert_eq!(parse_iv("0x0g"), None);
+        assert_eq!(parse_iv(&format!("0x{}", "00".repeat(17))), None);
+    }
+}
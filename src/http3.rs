@@ -0,0 +1,381 @@
+//! HTTP/3 for the `Http` source: the ranges of a file are streams of one
+//! QUIC connection, so a lost packet only holds up the range it belongs to
+//! instead of everything queued behind it on a TCP connection.
+//!
+//! An origin is switched over once it advertises `h3` in `Alt-Svc`, or
+//! right away when HTTP/3 is forced. Whenever QUIC doesn't get through
+//! (UDP blocked, handshake failing, connection lost) the origin is left
+//! to TCP for the rest of the download, and a range cut off halfway is
+//! finished over TCP.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use bytes::{Buf, Bytes};
+use futures::{
+    future::{self, BoxFuture},
+    stream, StreamExt,
+};
+use reqwest::{
+    cookie::CookieStore,
+    header::{HeaderMap, COOKIE, SET_COOKIE},
+    Method, StatusCode, Url,
+};
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+use crate::{
+    auth::redact,
+    cookies::CookieJar,
+    source::{headers_meta, ByteStream, HttpStats, Meta},
+    DResult, Errors,
+};
+
+/// How long a QUIC handshake gets before the origin is left to TCP, where
+/// UDP is dropped it would otherwise wait out the idle timeout
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(3);
+/// Silence after which a connection counts as lost, kept off idle
+/// connections with keep-alives
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+const KEEP_ALIVE: Duration = Duration::from_secs(3);
+
+type SendRequest = h3::client::SendRequest<h3_quinn::OpenStreams, Bytes>;
+type RequestStream = h3::client::RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>;
+
+/// The HTTP/3 endpoint in an `Alt-Svc` header, e.g. `h3=":443"; ma=86400`,
+/// an empty host being the origin's own
+pub fn alt_svc(headers: &HeaderMap, origin: &Url) -> Option<(String, u16)> {
+    headers
+        .get_all("alt-svc")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(|service| {
+            let (protocol, authority) = service.split(';').next()?.split_once('=')?;
+            if protocol.trim() != "h3" {
+                return None;
+            }
+            let (host, port) = authority.trim().trim_matches('"').rsplit_once(':')?;
+            let host = match host.trim_matches(['[', ']']) {
+                "" => origin.host_str()?,
+                host => host,
+            };
+            Some((host.to_owned(), port.parse().ok()?))
+        })
+}
+
+enum Origin {
+    /// advertised or forced, not connected yet
+    Known(String, u16),
+    Connected(quinn::Connection, SendRequest),
+    /// QUIC failed here, requests go over TCP
+    Broken,
+}
+
+/// What became of a request sent over HTTP/3
+enum Sent {
+    Response(http::Response<()>, Box<RequestStream>),
+    /// the server answered with an error that TCP would get too
+    Failed(Errors),
+    /// no HTTP/3 for this request, send it over TCP
+    Fallback,
+}
+
+pub struct Http3 {
+    config: quinn::ClientConfig,
+    /// by origin (`scheme://host:port`), locked while connecting so the
+    /// ranges starting together don't open a connection each
+    origins: Arc<Mutex<HashMap<String, Origin>>>,
+    stats: Arc<HttpStats>,
+    /// the TCP clients' jar, so cookies carry over between the two
+    cookies: Option<Arc<CookieJar>>,
+}
+
+impl std::fmt::Debug for Http3 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Http3").finish_non_exhaustive()
+    }
+}
+
+impl Http3 {
+    /// Trusts the system's root certificates (`SSL_CERT_FILE` and
+    /// `SSL_CERT_DIR` too), like the TCP clients
+    pub fn new(stats: Arc<HttpStats>, cookies: Option<Arc<CookieJar>>) -> DResult<Self> {
+        let mut roots = rustls::RootCertStore::empty();
+        let native = rustls_native_certs::load_native_certs();
+        for e in native.errors {
+            debug!("loading a root certificate failed: {}", e);
+        }
+        roots.add_parsable_certificates(native.certs);
+        let mut tls = rustls::ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(|e| Errors::Custom(format!("Failed setting up TLS for HTTP/3: {}", e)))?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+        tls.alpn_protocols = vec![b"h3".to_vec()];
+        let crypto = quinn::crypto::rustls::QuicClientConfig::try_from(tls)
+            .map_err(|e| Errors::Custom(format!("Failed setting up TLS for HTTP/3: {}", e)))?;
+        let mut transport = quinn::TransportConfig::default();
+        transport
+            .max_idle_timeout(IDLE_TIMEOUT.try_into().ok())
+            .keep_alive_interval(Some(KEEP_ALIVE));
+        let mut config = quinn::ClientConfig::new(Arc::new(crypto));
+        config.transport_config(Arc::new(transport));
+        Ok(Http3 {
+            config,
+            origins: Arc::default(),
+            stats,
+            cookies,
+        })
+    }
+
+    /// Sends the url's requests to `host:port` over HTTP/3 from now on,
+    /// unless it's already known
+    pub async fn learn(&self, url: &Url, (host, port): (String, u16)) {
+        self.origins
+            .lock()
+            .await
+            .entry(url.origin().ascii_serialization())
+            .or_insert_with(|| {
+                debug!(
+                    "{} has HTTP/3 at {}:{}",
+                    url.origin().ascii_serialization(),
+                    host,
+                    port
+                );
+                Origin::Known(host, port)
+            });
+    }
+
+    /// `None` when the url isn't (or is no longer) on HTTP/3
+    pub async fn probe(&self, url: &str, headers: &HeaderMap) -> Option<DResult<Meta>> {
+        match self.send(url, Method::HEAD, headers).await {
+            Sent::Response(res, _) => Some(headers_meta(url, res.headers())),
+            Sent::Failed(e) => Some(Err(e)),
+            Sent::Fallback => None,
+        }
+    }
+
+    /// `None` when the url isn't (or is no longer) on HTTP/3. If the stream
+    /// breaks, `resume` is handed the first byte still missing and the
+    /// rest of the range comes from the stream it opens.
+    pub async fn open_range<F>(
+        &self,
+        url: &str,
+        headers: &HeaderMap,
+        (from, to): (u64, u64),
+        resume: F,
+    ) -> Option<DResult<ByteStream>>
+    where
+        F: FnOnce(u64) -> BoxFuture<'static, DResult<ByteStream>> + Send + 'static,
+    {
+        let mut headers = headers.clone();
+        headers.insert(
+            "range",
+            format!("bytes={}-{}", from, to)
+                .parse()
+                .expect("a range is a valid header value"),
+        );
+        match self.send(url, Method::GET, &headers).await {
+            Sent::Response(res, stream) if res.status() == StatusCode::PARTIAL_CONTENT => {
+                let origin = Url::parse(url).ok()?.origin().ascii_serialization();
+                Some(Ok(body(
+                    stream,
+                    (from, to),
+                    resume,
+                    self.origins.clone(),
+                    origin,
+                )))
+            }
+            Sent::Response(res, _) => Some(Err(Errors::Custom(format!(
+                "{} answered a range request with {}",
+                url,
+                res.status()
+            )))),
+            Sent::Failed(e) => Some(Err(e)),
+            Sent::Fallback => None,
+        }
+    }
+
+    async fn send(&self, url: &str, method: Method, headers: &HeaderMap) -> Sent {
+        let Ok(parsed) = Url::parse(url) else {
+            return Sent::Fallback;
+        };
+        let origin = parsed.origin().ascii_serialization();
+        let Some(mut send) = self.connection(&parsed, &origin).await else {
+            return Sent::Fallback;
+        };
+        let mut request = http::Request::builder().method(method).uri(url);
+        if let Some(request_headers) = request.headers_mut() {
+            request_headers.extend(headers.clone());
+            // like the TCP clients, a Cookie header given explicitly wins
+            if let (Some(cookies), false) = (&self.cookies, request_headers.contains_key(COOKIE)) {
+                if let Some(cookie) = cookies.cookies(&parsed) {
+                    request_headers.insert(COOKIE, cookie);
+                }
+            }
+        }
+        let Ok(request) = request.body(()) else {
+            return Sent::Fallback;
+        };
+        let response = async {
+            let mut stream = send.send_request(request).await?;
+            stream.finish().await?;
+            let res = stream.recv_response().await?;
+            Ok::<_, h3::error::StreamError>((res, stream))
+        };
+        match response.await {
+            Ok((res, stream)) => {
+                if let Some(cookies) = &self.cookies {
+                    cookies.set_cookies(&mut res.headers().get_all(SET_COOKIE).iter(), &parsed);
+                }
+                self.stats.requests.fetch_add(1, Ordering::Relaxed);
+                self.stats.quic.fetch_add(1, Ordering::Relaxed);
+                if res.status().is_success() {
+                    Sent::Response(res, Box::new(stream))
+                } else if res.status().is_server_error() || res.status() == StatusCode::UNAUTHORIZED
+                {
+                    // a challenge is answered over TCP, the answer then
+                    // goes with the later requests here too
+                    debug!("{} over HTTP/3: {}, trying TCP", redact(url), res.status());
+                    Sent::Fallback
+                } else {
+                    Sent::Failed(Errors::Custom(format!(
+                        "HTTP status client error ({}) for url ({})",
+                        res.status(),
+                        url
+                    )))
+                }
+            }
+            Err(e) => {
+                give_up(&self.origins, &origin, &e.to_string()).await;
+                Sent::Fallback
+            }
+        }
+    }
+
+    /// The origin's connection, opened if it's known but not connected yet
+    async fn connection(&self, url: &Url, origin: &str) -> Option<SendRequest> {
+        let mut origins = self.origins.lock().await;
+        let (host, port) = match origins.get(origin)? {
+            Origin::Connected(quic, send) if quic.close_reason().is_none() => {
+                return Some(send.clone())
+            }
+            // closed while idle, open another one
+            Origin::Connected(quic, _) => {
+                let addr = quic.remote_address();
+                (addr.ip().to_string(), addr.port())
+            }
+            Origin::Known(host, port) => (host.clone(), *port),
+            Origin::Broken => return None,
+        };
+        match self.connect(url, &host, port).await {
+            Ok((quic, send)) => {
+                self.stats.connections.fetch_add(1, Ordering::Relaxed);
+                origins.insert(origin.to_owned(), Origin::Connected(quic, send.clone()));
+                Some(send)
+            }
+            Err(e) => {
+                warn!("HTTP/3 to {} failed, using TCP: {}", origin, e);
+                origins.insert(origin.to_owned(), Origin::Broken);
+                None
+            }
+        }
+    }
+
+    async fn connect(
+        &self,
+        url: &Url,
+        host: &str,
+        port: u16,
+    ) -> DResult<(quinn::Connection, SendRequest)> {
+        let addr = tokio::net::lookup_host((host, port))
+            .await?
+            .next()
+            .ok_or_else(|| Errors::Custom(format!("{} has no address", host)))?;
+        let bind: SocketAddr = match addr {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+        let mut endpoint = quinn::Endpoint::client(bind)?;
+        endpoint.set_default_client_config(self.config.clone());
+        // the certificate has to be the origin's, wherever Alt-Svc points
+        let server_name = url.host_str().ok_or("Url without a host")?;
+        let connecting = endpoint
+            .connect(addr, server_name)
+            .map_err(|e| Errors::Custom(e.to_string()))?;
+        let quic = tokio::time::timeout(HANDSHAKE_TIMEOUT, connecting)
+            .await
+            .map_err(|_| Errors::Custom("handshake timed out".into()))?
+            .map_err(|e| Errors::Custom(e.to_string()))?;
+        let (mut driver, send) = h3::client::new(h3_quinn::Connection::new(quic.clone()))
+            .await
+            .map_err(|e| Errors::Custom(e.to_string()))?;
+        tokio::spawn(async move {
+            let e = future::poll_fn(|cx| driver.poll_close(cx)).await;
+            debug!("HTTP/3 connection closed: {}", e);
+        });
+        debug!("HTTP/3 connection to {} ({})", server_name, addr);
+        Ok((quic, send))
+    }
+}
+
+async fn give_up(origins: &Mutex<HashMap<String, Origin>>, origin: &str, reason: &str) {
+    let mut origins = origins.lock().await;
+    if !matches!(origins.get(origin), Some(Origin::Broken)) {
+        warn!("HTTP/3 to {} failed, using TCP: {}", origin, reason);
+        origins.insert(origin.to_owned(), Origin::Broken);
+    }
+}
+
+enum Body<F> {
+    /// next byte to arrive and the range's last
+    Quic(Box<RequestStream>, u64, u64, F),
+    Resumed(ByteStream),
+}
+
+fn body<F>(
+    stream: Box<RequestStream>,
+    (from, to): (u64, u64),
+    resume: F,
+    origins: Arc<Mutex<HashMap<String, Origin>>>,
+    origin: String,
+) -> ByteStream
+where
+    F: FnOnce(u64) -> BoxFuture<'static, DResult<ByteStream>> + Send + 'static,
+{
+    stream::unfold(Some(Body::Quic(stream, from, to, resume)), move |body| {
+        let origins = origins.clone();
+        let origin = origin.clone();
+        async move {
+            let mut resumed = match body? {
+                Body::Quic(mut stream, next, to, resume) => match stream.recv_data().await {
+                    Ok(Some(mut data)) => {
+                        let data = data.copy_to_bytes(data.remaining());
+                        let next = next + data.len() as u64;
+                        return Some((Ok(data), Some(Body::Quic(stream, next, to, resume))));
+                    }
+                    Ok(None) => return None,
+                    Err(_) if next > to => return None,
+                    Err(e) => {
+                        give_up(&origins, &origin, &e.to_string()).await;
+                        match resume(next).await {
+                            Ok(resumed) => resumed,
+                            Err(e) => return Some((Err(e), None)),
+                        }
+                    }
+                },
+                Body::Resumed(resumed) => resumed,
+            };
+            let item = resumed.next().await?;
+            Some((item, Some(Body::Resumed(resumed))))
+        }
+    })
+    .boxed()
+}
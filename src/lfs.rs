@@ -0,0 +1,426 @@
+//! Git LFS objects fetched through the batch API instead of `git lfs pull`.
+//! The oids come from pointer files in the worktree, or are given
+//! directly, a batch request per hundred of them says where each object
+//! lives and every object is then a regular download into
+//! `.git/lfs/objects`, checked against its oid.
+//!
+//! The endpoint is found the way git-lfs finds it, from `lfs.url`, the
+//! remote's `lfsurl`, `.lfsconfig` or else the remote url itself. A `401`
+//! from it is answered with what `git credential fill` has for the host.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    process::Stdio,
+    str::FromStr,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use reqwest::{
+    header::{HeaderName, HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_TYPE},
+    Client, StatusCode, Url,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncWriteExt, process::Command};
+use tracing::{debug, info};
+
+use crate::{
+    auth::{redacted, sensitive},
+    checksum::{Algorithm, Checksum},
+    download::{send_with_retry, DownloadBuilder},
+    DResult, Errors,
+};
+
+const POINTER_VERSION: &str = "version https://git-lfs.github.com/spec/v1";
+const MEDIA_TYPE: &str = "application/vnd.git-lfs+json";
+/// objects per batch request, what servers commonly cap it at
+const BATCH_SIZE: usize = 100;
+/// pointer files are well under this, anything bigger is real content
+const MAX_POINTER_SIZE: u64 = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct Pointer {
+    /// hex sha256 of the object
+    pub oid: String,
+    pub size: u64,
+}
+
+impl Pointer {
+    /// The pointer a pointer file's text holds
+    pub fn parse(text: &str) -> Option<Pointer> {
+        let mut lines = text.lines();
+        if lines.next()? != POINTER_VERSION {
+            return None;
+        }
+        let (mut oid, mut size) = (None, None);
+        for line in lines {
+            match line.split_once(' ') {
+                Some(("oid", value)) => oid = value.strip_prefix("sha256:"),
+                Some(("size", value)) => size = value.parse().ok(),
+                _ => {}
+            }
+        }
+        let pointer = Pointer {
+            oid: oid?.to_ascii_lowercase(),
+            size: size?,
+        };
+        pointer.valid().then_some(pointer)
+    }
+
+    fn valid(&self) -> bool {
+        self.oid.len() == 64 && self.oid.bytes().all(|b| b.is_ascii_hexdigit())
+    }
+}
+
+impl FromStr for Pointer {
+    type Err = Errors;
+
+    /// `oid:size`, with or without the oid's `sha256:`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Errors::Custom(format!("Invalid LFS object {}, expected oid:size", s));
+        let (oid, size) = s.rsplit_once(':').ok_or_else(invalid)?;
+        let pointer = Pointer {
+            oid: oid
+                .strip_prefix("sha256:")
+                .unwrap_or(oid)
+                .to_ascii_lowercase(),
+            size: size.parse().map_err(|_| invalid())?,
+        };
+        match pointer.valid() {
+            true => Ok(pointer),
+            false => Err(invalid()),
+        }
+    }
+}
+
+/// The pointers in the files under `paths`, each once
+pub async fn pointers(paths: &[PathBuf]) -> DResult<Vec<Pointer>> {
+    let mut seen = HashSet::new();
+    let mut found = vec![];
+    let mut stack = paths.to_vec();
+    while let Some(path) = stack.pop() {
+        let meta = tokio::fs::symlink_metadata(&path).await?;
+        if meta.is_dir() {
+            if path.file_name().is_some_and(|name| name == ".git") {
+                continue;
+            }
+            let mut entries = tokio::fs::read_dir(&path).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                stack.push(entry.path());
+            }
+        } else if meta.is_file() && meta.len() <= MAX_POINTER_SIZE {
+            let Some(pointer) = tokio::fs::read_to_string(&path)
+                .await
+                .ok()
+                .as_deref()
+                .and_then(Pointer::parse)
+            else {
+                continue;
+            };
+            debug!("{:?} points to {}", path, pointer.oid);
+            if seen.insert(pointer.clone()) {
+                found.push(pointer);
+            }
+        }
+    }
+    Ok(found)
+}
+
+pub struct Lfs {
+    /// `lfs/objects` in the repository's git directory
+    pub objects: PathBuf,
+    pub endpoint: Url,
+    authorization: Option<HeaderValue>,
+}
+
+impl Lfs {
+    /// The object store of the repository around the current directory
+    /// and its LFS endpoint, `endpoint` or the one configured for `remote`
+    pub async fn open(remote: &str, endpoint: Option<Url>) -> DResult<Lfs> {
+        let git_dir = git(&["rev-parse", "--git-common-dir"])
+            .await?
+            .ok_or_else(|| Errors::Custom("Not inside a git repository".to_owned()))?;
+        let endpoint = match endpoint {
+            Some(endpoint) => endpoint,
+            None => configured_endpoint(remote).await?,
+        };
+        info!("LFS endpoint {}", redacted(&endpoint));
+        Ok(Lfs {
+            objects: PathBuf::from(git_dir).join("lfs").join("objects"),
+            endpoint,
+            authorization: None,
+        })
+    }
+
+    /// A download for every object that isn't in the store yet, or the
+    /// reason the server gave for not having it
+    pub async fn builders(
+        &mut self,
+        client: &Client,
+        pointers: &[Pointer],
+        retries: usize,
+    ) -> DResult<Vec<DResult<DownloadBuilder>>> {
+        let mut missing = vec![];
+        for pointer in pointers {
+            // objects are named by their oid, the right size is enough
+            if tokio::fs::metadata(self.object_path(&pointer.oid))
+                .await
+                .is_ok_and(|m| m.len() == pointer.size)
+            {
+                debug!("{} is already in the store", pointer.oid);
+            } else {
+                missing.push(pointer);
+            }
+        }
+
+        let mut builders = vec![];
+        for objects in missing.chunks(BATCH_SIZE) {
+            let response = self.batch(client, objects, retries).await?;
+            for object in response.objects {
+                builders.push(self.builder(object).await);
+            }
+        }
+        Ok(builders)
+    }
+
+    async fn builder(&self, object: BatchObject) -> DResult<DownloadBuilder> {
+        if let Some(error) = object.error {
+            return Err(Errors::Custom(format!(
+                "LFS object {}: {} {}",
+                object.oid, error.code, error.message
+            )));
+        }
+        let action = object
+            .actions
+            .and_then(|actions| actions.download)
+            .ok_or_else(|| {
+                Errors::Custom(format!("No download offered for LFS object {}", object.oid))
+            })?;
+        let pointer = Pointer {
+            oid: object.oid.to_ascii_lowercase(),
+            size: object.size,
+        };
+        if !pointer.valid() {
+            return Err(Errors::Custom(format!("Invalid LFS oid {}", object.oid)));
+        }
+
+        let path = self.object_path(&pointer.oid);
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let mut builder = DownloadBuilder::new(action.href.as_str())
+            .path(path.to_string_lossy())
+            .checksum(Checksum::new(
+                Algorithm::Sha256,
+                hex::decode(&pointer.oid).map_err(|e| Errors::Custom(e.to_string()))?,
+            ))
+            .metalink(false);
+        if action.header.is_empty() {
+            // the endpoint's own credentials, but only on its own host
+            let same_host = Url::parse(&action.href)
+                .is_ok_and(|href| href.host_str() == self.endpoint.host_str());
+            if let Some(value) = self.authorization.clone().filter(|_| same_host) {
+                builder = builder.header(AUTHORIZATION, value);
+            }
+        }
+        for (name, value) in action.header {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|e| Errors::Custom(format!("Invalid header {}: {}", name, e)))?;
+            // usually the href's own authorization
+            let value = sensitive(&value)
+                .map_err(|e| Errors::Custom(format!("Invalid value for {}: {:?}", name, e)))?;
+            builder = builder.header(name, value);
+        }
+        Ok(builder)
+    }
+
+    async fn batch(
+        &mut self,
+        client: &Client,
+        objects: &[&Pointer],
+        retries: usize,
+    ) -> DResult<BatchResponse> {
+        let url = Url::parse(&format!(
+            "{}/objects/batch",
+            self.endpoint.as_str().trim_end_matches('/')
+        ))
+        .map_err(|e| Errors::Custom(format!("Invalid LFS endpoint: {}", e)))?;
+        let body = serde_json::to_vec(&serde_json::json!({
+            "operation": "download",
+            "transfers": ["basic"],
+            "objects": objects,
+        }))?;
+        let request = |authorization: Option<&HeaderValue>| {
+            let request = client
+                .post(url.clone())
+                .header(ACCEPT, MEDIA_TYPE)
+                .header(CONTENT_TYPE, MEDIA_TYPE)
+                .body(body.clone());
+            match authorization {
+                Some(value) => request.header(AUTHORIZATION, value.clone()),
+                None => request,
+            }
+        };
+        let first = request(self.authorization.as_ref()).send().await?;
+        let res = if first.status().is_success() {
+            first
+        } else {
+            if first.status() == StatusCode::UNAUTHORIZED && self.authorization.is_none() {
+                self.authorization = Some(credential(&url).await?);
+            }
+            send_with_retry(|| request(self.authorization.as_ref()), retries).await?
+        };
+        debug!("batch of {} objects from {}", objects.len(), redacted(&url));
+        Ok(serde_json::from_slice(&res.bytes().await?)?)
+    }
+
+    fn object_path(&self, oid: &str) -> PathBuf {
+        self.objects.join(&oid[..2]).join(&oid[2..4]).join(oid)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchResponse {
+    objects: Vec<BatchObject>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchObject {
+    oid: String,
+    size: u64,
+    actions: Option<Actions>,
+    error: Option<ObjectError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Actions {
+    download: Option<Action>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Action {
+    href: String,
+    #[serde(default)]
+    header: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ObjectError {
+    code: u16,
+    message: String,
+}
+
+/// `git` run in the current directory, its trimmed output or `None` when
+/// it fails, like `git config` does for a key that isn't set
+async fn git(args: &[&str]) -> DResult<Option<String>> {
+    let out = Command::new("git")
+        .args(args)
+        .stderr(Stdio::null())
+        .output()
+        .await?;
+    Ok(out
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&out.stdout).trim().to_owned()))
+}
+
+async fn configured_endpoint(remote: &str) -> DResult<Url> {
+    let lfsconfig = match git(&["rev-parse", "--show-toplevel"]).await? {
+        Some(top) => PathBuf::from(top).join(".lfsconfig"),
+        None => PathBuf::from(".lfsconfig"),
+    };
+    let lfsconfig = lfsconfig.to_string_lossy();
+    let lfsurl = format!("remote.{}.lfsurl", remote);
+    for args in [
+        vec!["config", "--get", "lfs.url"],
+        vec!["config", "--get", &lfsurl],
+        vec!["config", "--file", &lfsconfig, "--get", "lfs.url"],
+    ] {
+        if let Some(url) = git(&args).await? {
+            return Url::parse(&url)
+                .map_err(|e| Errors::Custom(format!("Invalid LFS url {}: {}", url, e)));
+        }
+    }
+    let remote_url = git(&["remote", "get-url", remote])
+        .await?
+        .ok_or_else(|| Errors::Custom(format!("No remote {} to find the LFS endpoint", remote)))?;
+    remote_endpoint(&remote_url)
+}
+
+/// `<remote>.git/info/lfs`, over https for ssh remotes
+fn remote_endpoint(remote_url: &str) -> DResult<Url> {
+    let https = if let Some(rest) = remote_url.strip_prefix("ssh://") {
+        let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+        let host = authority.rsplit('@').next().unwrap_or(authority);
+        let host = host.split(':').next().unwrap_or(host);
+        format!("https://{}/{}", host, path)
+    } else if remote_url.contains("://") {
+        remote_url.to_owned()
+    } else if let Some((authority, path)) = remote_url.split_once(':') {
+        // scp-like user@host:path
+        let host = authority.rsplit('@').next().unwrap_or(authority);
+        format!("https://{}/{}", host, path.trim_start_matches('/'))
+    } else {
+        return Err(Errors::Custom(format!(
+            "Remote {} has no LFS endpoint, pass one with --endpoint",
+            remote_url
+        )));
+    };
+    let https = https.trim_end_matches('/');
+    let base = match https.ends_with(".git") {
+        true => https.to_owned(),
+        false => format!("{}.git", https),
+    };
+    Url::parse(&format!("{}/info/lfs", base))
+        .map_err(|e| Errors::Custom(format!("Invalid remote url {}: {}", remote_url, e)))
+}
+
+/// `Basic` auth from `git credential fill`, which asks the configured
+/// helpers without prompting
+async fn credential(url: &Url) -> DResult<HeaderValue> {
+    let host = match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        (host, None) => host.unwrap_or_default().to_owned(),
+        (None, Some(_)) => String::new(),
+    };
+    let mut input = format!("protocol={}\nhost={}\n", url.scheme(), host);
+    if !url.username().is_empty() {
+        input.push_str(&format!("username={}\n", url.username()));
+    }
+    input.push('\n');
+
+    let mut child = Command::new("git")
+        .args(["credential", "fill"])
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input.as_bytes()).await?;
+    }
+    let out = child.wait_with_output().await?;
+    let no_credentials = || {
+        Errors::Custom(format!(
+            "{} wants credentials and git has none for {}",
+            redacted(url),
+            host
+        ))
+    };
+    if !out.status.success() {
+        return Err(no_credentials());
+    }
+    let fields: HashMap<&str, &str> = std::str::from_utf8(&out.stdout)
+        .map_err(|e| Errors::Custom(e.to_string()))?
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .collect();
+    let (Some(username), Some(password)) = (fields.get("username"), fields.get("password")) else {
+        return Err(no_credentials());
+    };
+    debug!("using the git credentials of {} for {}", username, host);
+    sensitive(&format!(
+        "Basic {}",
+        STANDARD.encode(format!("{}:{}", username, password))
+    ))
+}
@@ -1,69 +1,162 @@
 use tracing::subscriber::{self, SetGlobalDefaultError};
 
+pub mod auth;
+pub mod autoindex;
+pub mod batch;
+pub mod checksum;
+pub mod cookies;
+pub mod daemon;
+pub mod dash;
+pub mod engine;
+pub mod ftp;
+pub mod hls;
+pub mod http3;
+pub mod lfs;
+pub mod limits;
+pub mod metalink;
+pub mod mirrors;
+pub mod oci;
+pub mod s3;
+pub mod segments;
+pub mod sftp;
+pub mod signature;
+pub mod source;
+pub mod torrent;
+pub mod webdav;
+
 pub mod download {
-    use std::path::PathBuf;
+    use std::{
+        path::PathBuf,
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
 
-    use reqwest::{Client, Response};
+    use minisign_verify::PublicKey;
+    use reqwest::{
+        header::{
+            HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION,
+            REFERER, USER_AGENT,
+        },
+        Client, Method, RequestBuilder, Response,
+    };
     use tracing::{debug, error, warn};
 
-    use crate::Errors;
+    use crate::{
+        auth::{redact, redacted, Auth},
+        checksum::{Checksum, Pieces},
+        ftp,
+        metalink::{self, Duplicate},
+        sftp,
+        source::{
+            partial_content, send_authorized, ByteStream, HttpOptions, HttpStats, Meta, Registry,
+            Source,
+        },
+        DResult, Errors,
+    };
+
+    /// How many times a failed request is retried before giving up
+    pub const DEFAULT_RETRIES: usize = 5;
+
+    /// With mirrors the file is split finer than the number of connections,
+    /// so faster mirrors can take more of the pieces
+    pub const PIECES_PER_CONNECTION: usize = 4;
 
     pub struct Info {
-        pub headers: Response,
+        /// what the probe found out about the file
+        pub meta: Meta,
         pub chunks: usize,
 
-        /// content-length
         pub size: u64,
         pub chunk_size: u64,
         pub ranges: Vec<(u64, u64)>,
+        /// hashes every range is checked against as it finishes, the
+        /// ranges being their pieces
+        pub pieces: Option<Pieces>,
     }
 
     impl Info {
-        fn new(headers: Response, chunks: usize) -> Self {
-            let size = headers
-                .headers()
-                .get("content-length")
-                .expect("Failed to get content length")
-                .to_str()
-                .unwrap()
-                .parse::<u64>()
-                .expect("Failed parsing content-length");
+        fn new(meta: Meta, chunks: usize) -> Self {
+            let size = meta.size;
             debug!("size: {}", size);
-            let chunk_size = size / chunks as u64;
-            //? if chunks>size?
+            // can't have more chunks than bytes, an empty file has none
+            let chunks = match size {
+                0 => 0,
+                _ => chunks.clamp(1, size as usize),
+            };
+            let chunk_size = size / chunks.max(1) as u64;
             debug!("chunk size: {}", chunk_size);
-            let mut ranges = (0..size)
-                .step_by(chunk_size as usize)
-                .map(|from| (from, from + chunk_size - 1))
+            let mut ranges = (0..chunks as u64)
+                .map(|idx| (idx * chunk_size, (idx + 1) * chunk_size - 1))
                 .collect::<Vec<_>>();
-            ranges.last_mut().expect("Failed getting last range").1 = size;
+            // last chunk takes the remainder, ranges are inclusive
+            if let Some(last) = ranges.last_mut() {
+                last.1 = size - 1;
+            }
             debug!("ranges:\n{:?}", ranges);
 
             Info {
-                headers,
+                meta,
                 chunks,
                 size,
                 chunk_size,
                 ranges,
+                pieces: None,
+            }
+        }
+
+        /// A range for every piece in `pieces`
+        fn with_pieces(meta: Meta, pieces: Pieces) -> DResult<Self> {
+            let size = meta.size;
+            if !pieces.fits(size) {
+                return Err(Errors::Custom(format!(
+                    "{} pieces of {} bytes don't make up the {} byte file",
+                    pieces.sha1.len(),
+                    pieces.length,
+                    size
+                )));
             }
+            let ranges = (0..pieces.sha1.len() as u64)
+                .map(|idx| {
+                    let from = idx * pieces.length;
+                    (from, (from + pieces.length).min(size) - 1)
+                })
+                .collect::<Vec<_>>();
+            Ok(Info {
+                meta,
+                chunks: ranges.len(),
+                size,
+                chunk_size: pieces.length,
+                ranges,
+                pieces: Some(pieces),
+            })
+        }
+        /// ETag, or Last-Modified when there's no ETag, used to tell whether
+        /// a partial file still belongs to the same remote file
+        pub fn validator(&self) -> Option<String> {
+            self.meta.validator.clone()
         }
+
+        /// Last-Modified of the remote file
+        pub fn last_modified(&self) -> Option<SystemTime> {
+            self.meta.last_modified
+        }
+
+        /// Mirrors from the response's `Link: rel=duplicate` headers, by
+        /// priority, preferring country `geo`
+        pub fn duplicates(&self, geo: Option<&str>) -> Vec<Duplicate> {
+            metalink::duplicates(&self.meta.headers, geo)
+        }
+
+        /// Whole-file digest from the response's `Digest` header
+        pub fn digest(&self) -> Option<Checksum> {
+            metalink::digest(&self.meta.headers)
+        }
+
         pub fn check_accept_ranges(&self) -> bool {
-            match self
-                .headers
-                .headers()
-                .get("accept-ranges")
-                .map(|x| x.to_str())
-            {
-                Some(Ok(accept_ranges)) => {
+            match self.meta.accept_ranges {
+                Some(accept_ranges) => {
                     debug!("accept ranges: {:?}", accept_ranges);
-                    match accept_ranges {
-                        "none" => false,
-                        _ => true,
-                    }
-                }
-                Some(Err(_)) => {
-                    error!("Failed converting accept-ranges header to str. aborting");
-                    false
+                    accept_ranges
                 }
                 None => {
                     warn!("Couldn't find accept-ranges, trying chunked download regardless..");
@@ -73,54 +166,640 @@ pub mod download {
         }
     }
 
+    /// ETag, or Last-Modified when there's no ETag
+    pub fn validator(headers: &HeaderMap) -> Option<String> {
+        headers
+            .get("etag")
+            .or_else(|| headers.get("last-modified"))
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_owned())
+    }
+
+    /// An HTTP date in its preferred form, `Sun, 06 Nov 1994 08:49:37 GMT`
+    pub fn parse_http_date(date: &str) -> Option<SystemTime> {
+        const MONTHS: [&str; 12] = [
+            "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+        ];
+        let mut parts = date.split_whitespace().skip(1);
+        let day: i64 = parts.next()?.parse().ok()?;
+        let month = parts.next()?;
+        let month = MONTHS.iter().position(|m| *m == month)? as i64 + 1;
+        let year: i64 = parts.next()?.parse().ok()?;
+        let mut time = parts.next()?.split(':').map(|n| n.parse::<i64>().ok());
+        let (hour, min, sec) = (time.next()??, time.next()??, time.next()??);
+
+        let secs = days_from_civil(year, month, day) * 86400 + hour * 3600 + min * 60 + sec;
+        Some(SystemTime::UNIX_EPOCH + Duration::from_secs(u64::try_from(secs).ok()?))
+    }
+
+    /// Days since 1970-01-01 of a proleptic gregorian date
+    pub(crate) fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+        let (y, m) = if month <= 2 {
+            (year - 1, month + 9)
+        } else {
+            (year, month - 3)
+        };
+        let era = y.div_euclid(400);
+        let yoe = y - era * 400;
+        let doy = (153 * m + 2) / 5 + day - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        era * 146097 + doe - 719468
+    }
+
+    /// `(year, month, day)` of a day count from [`days_from_civil`]
+    pub(crate) fn civil_from_days(days: i64) -> (i64, i64, i64) {
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        (yoe + era * 400 + i64::from(month <= 2), month, day)
+    }
+
+    /// `time` the way [`parse_http_date`] reads it, for sources that report
+    /// modification times as timestamps
+    pub fn format_http_date(time: SystemTime) -> String {
+        const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+        const MONTHS: [&str; 12] = [
+            "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+        ];
+        let secs = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()) as i64;
+        let (days, rem) = (secs / 86400, secs % 86400);
+        let (year, month, day) = civil_from_days(days);
+
+        format!(
+            "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+            DAYS[(days % 7) as usize],
+            day,
+            MONTHS[month as usize - 1],
+            year,
+            rem / 3600,
+            rem % 3600 / 60,
+            rem % 60
+        )
+    }
+
     pub struct Download {
+        /// for extra resources like signatures, the pieces come from `sources`
         pub client: Client,
+        /// what the url and its mirrors are downloaded with, by scheme
+        pub sources: Registry,
         pub url: String,
+        /// other urls serving the same file, checked to have the same size
+        /// and validator as `url`
+        pub mirrors: Vec<String>,
+        /// urls the remaining pieces move to once the url and all mirrors
+        /// failed, checked like mirrors right before they're used
+        pub fallbacks: Vec<String>,
         pub path: String,
         pub info: Info,
+        /// connections to open across all sources, `info.chunks` when
+        /// there's a single source and the file isn't split into pieces
+        pub connections: usize,
+        pub retries: usize,
+        /// sent with the probe and every ranged GET
+        pub headers: HeaderMap,
+        /// logins for [`Download::fetch`], none when `headers` has its own
+        /// `Authorization`
+        pub auth: Option<Arc<Auth>>,
+        /// detached minisign signature (url or path) checked before the
+        /// `.part` file is moved into place
+        pub signature: Option<String>,
+        pub pubkey: Option<PublicKey>,
+        /// expected digest of the whole file, checked like the signature
+        pub checksum: Option<Checksum>,
+        /// leave the file alone if it's already there with the remote size
+        /// and Last-Modified
+        pub skip_unchanged: bool,
+        /// connections and requests of the `http(s)://` source
+        pub http: Arc<HttpStats>,
     }
 
-    impl Download {
-        pub async fn new<S: AsRef<str>>(url: S, path: S, chunks: usize) -> Result<Self, Errors> {
-            let url = reqwest::Url::parse(url.as_ref()).expect("Failed parsing url");
-            debug!("parsed url:\n{:#?}", &url);
-
-            let client = reqwest::Client::new();
-            if let Ok(headers) = client.head(url.as_str()).send().await {
-                debug!("headers at target url:\n{:#?}", headers);
-                let info = Info::new(headers, chunks);
-
-                if info.check_accept_ranges() {
-                    Ok(Download {
-                        client,
-                        url: url.as_str().to_owned(),
-                        path: path.as_ref().to_owned(),
-                        info,
-                    })
-                } else {
-                    Err(
+    /// Everything about a download that has to be known before the probe
+    /// request is sent. `build` sends the probe and returns the `Download`.
+    #[derive(Debug, Clone)]
+    pub struct DownloadBuilder {
+        url: String,
+        mirrors: Vec<String>,
+        best_mirrors: Option<usize>,
+        fallbacks: Vec<String>,
+        metalink: bool,
+        geo: Option<String>,
+        skip_unchanged: bool,
+        http: HttpOptions,
+        ftp: ftp::Options,
+        sftp: sftp::Options,
+        sources: Vec<(String, Arc<dyn Source>)>,
+        path: String,
+        chunks: usize,
+        retries: usize,
+        headers: HeaderMap,
+        signature: Option<String>,
+        pubkey: Option<PublicKey>,
+        checksum: Option<Checksum>,
+        pieces: Option<Pieces>,
+    }
+
+    impl DownloadBuilder {
+        pub fn new<S: AsRef<str>>(url: S) -> Self {
+            DownloadBuilder {
+                url: url.as_ref().to_owned(),
+                mirrors: vec![],
+                best_mirrors: None,
+                fallbacks: vec![],
+                metalink: true,
+                geo: None,
+                skip_unchanged: false,
+                http: HttpOptions::default(),
+                ftp: ftp::Options::default(),
+                sftp: sftp::Options::default(),
+                sources: vec![],
+                path: "./".to_owned(),
+                chunks: 8,
+                retries: DEFAULT_RETRIES,
+                headers: HeaderMap::new(),
+                signature: None,
+                pubkey: None,
+                checksum: None,
+                pieces: None,
+            }
+        }
+
+        pub fn url(&self) -> &str {
+            &self.url
+        }
+
+        /// Another url serving the same file, ranges are spread across the
+        /// url and all its mirrors
+        pub fn mirror<S: AsRef<str>>(mut self, url: S) -> Self {
+            self.mirrors.push(url.as_ref().to_owned());
+            self
+        }
+
+        /// Ranks the url and its mirrors with a small ranged GET each and
+        /// only downloads from the fastest `n`
+        pub fn best_mirrors(mut self, n: usize) -> Self {
+            self.best_mirrors = Some(n.max(1));
+            self
+        }
+
+        /// Whether mirrors from `Link: rel=duplicate` headers and the
+        /// `Digest` header of the probe response are used, on by default
+        pub fn metalink(mut self, metalink: bool) -> Self {
+            self.metalink = metalink;
+            self
+        }
+
+        /// Country code preferred among `Link` mirrors of the same priority
+        pub fn geo<S: AsRef<str>>(mut self, geo: S) -> Self {
+            self.geo = Some(geo.as_ref().to_owned());
+            self
+        }
+
+        /// Skip the download when the file is already there with the remote
+        /// size and Last-Modified, which finished downloads are stamped with
+        pub fn skip_unchanged(mut self, skip_unchanged: bool) -> Self {
+            self.skip_unchanged = skip_unchanged;
+            self
+        }
+
+        /// HTTP version and number of HTTP/2 connections for `http(s)://`
+        /// urls
+        pub fn http(mut self, options: HttpOptions) -> Self {
+            self.http = options;
+            self
+        }
+
+        /// Explicit TLS and active mode for `ftp://` urls
+        pub fn ftp(mut self, options: ftp::Options) -> Self {
+            self.ftp = options;
+            self
+        }
+
+        /// Key files and host key policy for `sftp://` urls
+        pub fn sftp(mut self, options: sftp::Options) -> Self {
+            self.sftp = options;
+            self
+        }
+
+        /// Downloads `scheme` urls from `source` instead of the built-in
+        /// backend, or adds a scheme there's none for
+        pub fn source<S: AsRef<str>>(mut self, scheme: S, source: Arc<dyn Source>) -> Self {
+            self.sources.push((scheme.as_ref().to_owned(), source));
+            self
+        }
+
+        /// A url to continue from if the url and its mirrors fail for good,
+        /// e.g. start answering 404. Tried in the order they're added.
+        pub fn fallback<S: AsRef<str>>(mut self, url: S) -> Self {
+            self.fallbacks.push(url.as_ref().to_owned());
+            self
+        }
+
+        /// Target file, or directory to save into under the url's filename
+        pub fn path<S: AsRef<str>>(mut self, path: S) -> Self {
+            self.path = path.as_ref().to_owned();
+            self
+        }
+
+        pub fn chunks(mut self, chunks: usize) -> Self {
+            self.chunks = chunks;
+            self
+        }
+
+        pub fn retries(mut self, retries: usize) -> Self {
+            self.retries = retries;
+            self
+        }
+
+        /// Adds a header to the probe and every ranged GET, repeated names
+        /// are all sent
+        pub fn header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+            self.headers.append(name, value);
+            self
+        }
+
+        /// Adds the headers the builder doesn't have one of by the same name
+        /// yet, so headers given for many downloads at once don't override
+        /// the ones set for a single download
+        pub fn default_headers(mut self, headers: &HeaderMap) -> Self {
+            for name in headers.keys() {
+                if !self.headers.contains_key(name) {
+                    for value in headers.get_all(name) {
+                        self.headers.append(name.clone(), value.clone());
+                    }
+                }
+            }
+            self
+        }
+
+        pub fn user_agent(mut self, user_agent: HeaderValue) -> Self {
+            self.headers.insert(USER_AGENT, user_agent);
+            self
+        }
+
+        pub fn referer(mut self, referer: HeaderValue) -> Self {
+            self.headers.insert(REFERER, referer);
+            self
+        }
+
+        /// `name=value` pairs (`a=1; b=2`) for the `Cookie` header, joined
+        /// to the ones already given since there may only be one
+        pub fn cookie(mut self, cookie: HeaderValue) -> Self {
+            let mut cookie = match self.headers.get(COOKIE) {
+                Some(cookies) => HeaderValue::from_bytes(
+                    &[cookies.as_bytes(), b"; ", cookie.as_bytes()].concat(),
+                )
+                .expect("joined cookies are a valid header value"),
+                None => cookie,
+            };
+            cookie.set_sensitive(true);
+            self.headers.insert(COOKIE, cookie);
+            self
+        }
+
+        pub fn signature<S: AsRef<str>>(mut self, signature: S, pubkey: PublicKey) -> Self {
+            self.signature = Some(signature.as_ref().to_owned());
+            self.pubkey = Some(pubkey);
+            self
+        }
+
+        pub fn checksum(mut self, checksum: Checksum) -> Self {
+            self.checksum = Some(checksum);
+            self
+        }
+
+        /// Splits the file into these pieces instead of `chunks` and checks
+        /// each against its hash as it finishes, refetching the ones that
+        /// don't match from another source
+        pub fn pieces(mut self, pieces: Pieces) -> Self {
+            self.pieces = Some(pieces);
+            self
+        }
+
+        /// Probes the url (a HEAD request for HTTP) to plan the chunks
+        pub async fn build(mut self) -> Result<Download, Errors> {
+            let url = reqwest::Url::parse(&self.url)
+                .map_err(|e| Errors::Custom(format!("Failed parsing url {}: {}", self.url, e)))?;
+            debug!("parsed url:\n{:#?}", redacted(&url));
+
+            let client = match &self.http.cookies {
+                Some(cookies) => Client::builder().cookie_provider(cookies.clone()).build()?,
+                None => Client::new(),
+            };
+            let sources = self.sources.iter().fold(
+                Registry::standard(
+                    client.clone(),
+                    self.headers.clone(),
+                    self.retries,
+                    self.http.clone(),
+                    self.ftp,
+                    self.sftp.clone(),
+                ),
+                |registry, (scheme, source)| registry.register(scheme, source.clone()),
+            );
+            match sources.probe(url.as_str()).await {
+                Ok(meta) => {
+                    debug!(
+                        "probed target url:\n{:#?}",
+                        Meta {
+                            url: redact(&meta.url),
+                            ..meta.clone()
+                        }
+                    );
+                    let (mut info, connections) = match self.pieces.take() {
+                        Some(pieces) => (Info::with_pieces(meta, pieces)?, self.chunks),
+                        None => {
+                            let info = Info::new(meta, self.chunks);
+                            let connections = info.chunks;
+                            (info, connections)
+                        }
+                    };
+
+                    if self.metalink {
+                        self.add_duplicates(&url, &info);
+                    }
+
+                    let mut url = url.as_str().to_owned();
+                    let mut mirrors = vec![];
+                    for mirror in &self.mirrors {
+                        match check_same_file(&sources, mirror, &info, self.checksum.as_ref()).await
+                        {
+                            Ok(()) => mirrors.push(mirror.clone()),
+                            Err(e) => warn!("Not using mirror {}: {}", redact(mirror), e),
+                        }
+                    }
+                    if let (Some(n), false) = (self.best_mirrors, mirrors.is_empty()) {
+                        // the file keeps the primary url's name whichever mirrors win
+                        self.path = determine_file_path(&self.path, &url)
+                            .to_string_lossy()
+                            .into_owned();
+                        let candidates = std::iter::once(url.clone())
+                            .chain(mirrors)
+                            .collect::<Vec<_>>();
+                        let sample = crate::mirrors::DEFAULT_SAMPLE.min(info.size);
+                        let best = crate::mirrors::rank(&sources, &candidates, sample)
+                            .await
+                            .into_iter()
+                            .filter(|probe| probe.sample.is_ok())
+                            .take(n)
+                            .map(|probe| probe.url)
+                            .collect::<Vec<_>>();
+                        debug!(
+                            "best mirrors: {:?}",
+                            best.iter().map(|url| redact(url)).collect::<Vec<_>>()
+                        );
+                        let (first, rest) = best
+                            .split_first()
+                            .ok_or("Couldn't reach the url or any mirror")?;
+                        url = first.clone();
+                        mirrors = rest.to_vec();
+                    }
+                    if !mirrors.is_empty() && info.pieces.is_none() {
+                        info = Info::new(info.meta, self.chunks * PIECES_PER_CONNECTION);
+                    }
+
+                    if info.check_accept_ranges() {
+                        let auth = self
+                            .http
+                            .auth
+                            .filter(|_| !self.headers.contains_key(AUTHORIZATION));
+                        Ok(Download {
+                            client,
+                            sources,
+                            url,
+                            mirrors,
+                            fallbacks: self.fallbacks,
+                            path: self.path,
+                            info,
+                            connections,
+                            retries: self.retries,
+                            headers: self.headers,
+                            auth,
+                            signature: self.signature,
+                            pubkey: self.pubkey,
+                            checksum: self.checksum,
+                            skip_unchanged: self.skip_unchanged,
+                            http: self.http.stats,
+                        })
+                    } else {
+                        Err(
                         "Target url doesn't have a valid accept-range header for chunked requests"
                             .into(),
                     )
+                    }
                 }
-            } else {
-                Err("Failed getting headers".into())
+                Err(e) => Err(Errors::Custom(format!("Failed getting headers: {}", e))),
+            }
+        }
+    }
+
+    impl DownloadBuilder {
+        /// Adds the probe response's `Link` mirrors, as many as there are
+        /// connections left to give them, and its `Digest` unless a checksum
+        /// was given
+        fn add_duplicates(&mut self, url: &reqwest::Url, info: &Info) {
+            let room = self.chunks.saturating_sub(1 + self.mirrors.len());
+            let Ok(base) = reqwest::Url::parse(&info.meta.url) else {
+                return;
+            };
+            let linked = info
+                .duplicates(self.geo.as_deref())
+                .into_iter()
+                .filter_map(|link| base.join(&link.url).ok())
+                .map(|link| link.to_string())
+                .filter(|link| link != url.as_str() && !self.mirrors.contains(link))
+                .take(room)
+                .collect::<Vec<_>>();
+            if !linked.is_empty() {
+                debug!(
+                    "mirrors from link headers: {:?}",
+                    linked.iter().map(|url| redact(url)).collect::<Vec<_>>()
+                );
+            }
+            self.mirrors.extend(linked);
+            if self.checksum.is_none() {
+                self.checksum = info.digest();
             }
         }
+    }
+
+    impl Download {
+        pub async fn new<S: AsRef<str>>(url: S, path: S, chunks: usize) -> Result<Self, Errors> {
+            Download::builder(url)
+                .path(path)
+                .chunks(chunks)
+                .build()
+                .await
+        }
+
+        pub fn builder<S: AsRef<str>>(url: S) -> DownloadBuilder {
+            DownloadBuilder::new(url)
+        }
+
+        /// Host of the target url, the key for per host limits
+        pub fn host(&self) -> String {
+            host_of(&self.url)
+        }
 
         pub fn get_ranges(&self, idx: usize) -> (u64, u64) {
-            (
-                self.info.ranges[idx as usize].0,
-                self.info.ranges[idx as usize].1,
+            (self.info.ranges[idx].0, self.info.ranges[idx].1)
+        }
+
+        /// The url followed by its mirrors
+        pub fn sources(&self) -> Vec<String> {
+            std::iter::once(self.url.clone())
+                .chain(self.mirrors.iter().cloned())
+                .collect()
+        }
+
+        /// Whether `url` serves the same file as the download's url, see
+        /// `check_same_file`
+        pub async fn check_same_file(&self, url: &str) -> DResult<()> {
+            check_same_file(&self.sources, url, &self.info, self.checksum.as_ref()).await
+        }
+
+        /// The rest of the given chunk from `source` (the url or one of its
+        /// mirrors), skipping the first `done` bytes that were already
+        /// downloaded
+        pub async fn open_range(&self, source: &str, idx: usize, done: u64) -> DResult<ByteStream> {
+            let (from, to) = self.get_ranges(idx);
+            self.sources.open_range(source, (from + done, to)).await
+        }
+
+        /// GET a whole (small) resource with the same client and retry policy
+        /// as the download itself, e.g. a detached signature
+        pub async fn fetch<S: AsRef<str>>(&self, url: S) -> Result<Response, Errors> {
+            let url = url.as_ref();
+            send_authorized(
+                self.auth.as_deref(),
+                &Method::GET,
+                url,
+                || self.client.get(url).headers(self.headers.clone()),
+                self.retries,
             )
+            .await
+        }
+    }
+
+    /// A mirror or fallback is only used if it serves the same file as the
+    /// primary url: same size, same validator, and it takes range requests.
+    /// When `checksum`, what the finished file is checked against, is the
+    /// primary's `Digest`, the validator doesn't have to match: mirrors
+    /// seldom share ETags.
+    pub async fn check_same_file(
+        sources: &Registry,
+        url: &str,
+        info: &Info,
+        checksum: Option<&Checksum>,
+    ) -> DResult<()> {
+        let meta = sources.probe(url).await?;
+        if meta.size != info.size {
+            return Err(Errors::Custom(format!(
+                "size {} differs from {}",
+                meta.size, info.size
+            )));
+        }
+        // with piece hashes a different file can't slip in, and the sources
+        // of a torrent don't share validators
+        let verified =
+            info.pieces.is_some() || checksum.is_some_and(|c| info.digest().as_ref() == Some(c));
+        if !verified && meta.validator != info.validator() {
+            return Err(Errors::Custom(format!(
+                "validator {:?} differs from {:?}",
+                meta.validator,
+                info.validator()
+            )));
+        }
+        if meta.accept_ranges == Some(false) {
+            return Err("doesn't accept range requests".into());
+        }
+        Ok(())
+    }
+
+    /// Host part of a url, empty if it has none
+    pub fn host_of(url: &str) -> String {
+        reqwest::Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(|host| host.to_owned()))
+            .unwrap_or_default()
+    }
+
+    /// GET the inclusive byte range `from..=to` of `url`, retrying on failure
+    pub async fn get_range(
+        client: &Client,
+        url: &str,
+        headers: &HeaderMap,
+        (from, to): (u64, u64),
+        retries: usize,
+    ) -> Result<Response, Errors> {
+        send_with_retry(
+            || {
+                client
+                    .get(url)
+                    .headers(headers.clone())
+                    .header("Range", format!("bytes={}-{}", from, to))
+            },
+            retries,
+        )
+        .await
+        .and_then(|res| partial_content(res, (from, to)))
+    }
+
+    /// Parses a `Name: value` header line
+    pub fn parse_header(line: &str) -> Result<(HeaderName, HeaderValue), Errors> {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| Errors::Custom(format!("Header isn't `Name: value`: {}", line)))?;
+        let name = HeaderName::from_bytes(name.trim().as_bytes())
+            .map_err(|e| Errors::Custom(format!("Invalid header name {:?}: {}", name, e)))?;
+        let mut value = HeaderValue::from_str(value.trim())
+            .map_err(|e| Errors::Custom(format!("Invalid header value for {}: {}", name, e)))?;
+        // kept out of the Debug output, and so the logs
+        value.set_sensitive(matches!(name, AUTHORIZATION | PROXY_AUTHORIZATION | COOKIE));
+        Ok((name, value))
+    }
+
+    /// Sends the request built by `make_request`, rebuilding and resending it
+    /// on connection errors and 5xx responses up to `retries` times.
+    /// 4xx responses aren't retried, they turn into an error right away.
+    pub async fn send_with_retry<F>(make_request: F, retries: usize) -> Result<Response, Errors>
+    where
+        F: Fn() -> RequestBuilder,
+    {
+        let mut attempt = 0;
+        loop {
+            let err: Errors = match make_request().send().await {
+                Ok(res) if res.status().is_success() => return Ok(res),
+                Ok(res) if res.status().is_server_error() => {
+                    Errors::Custom(format!("Server responded with {}", res.status()))
+                }
+                Ok(res) => return Err(res.error_for_status().unwrap_err().into()),
+                Err(e) => e.into(),
+            };
+            if attempt >= retries {
+                error!("Request failed after {} retries: {:?}", retries, err);
+                return Err(err);
+            }
+            attempt += 1;
+            debug!(
+                "Request failed with {:?}, retrying [{}/{}]",
+                err, attempt, retries
+            );
+            tokio::time::sleep(Duration::from_millis(250 * attempt as u64)).await;
         }
     }
 
     pub fn determine_file_path(path: &str, url: &str) -> PathBuf {
         let mut p = PathBuf::from(path);
-        let filename = match url.rsplit_once("/") {
+        let filename = match url.rsplit_once('/') {
             None => "download.bin",
-            Some((s1, s2)) => s2,
+            Some((_, s2)) => s2,
         };
         debug!("filename: {}", filename);
         if p.is_dir() {
@@ -141,6 +820,122 @@ pub mod download {
             }
         }
     }
+
+    /// Path the file is downloaded into before it's complete (and verified),
+    /// `file.iso` -> `file.iso.part`
+    pub fn part_file_path(path: &std::path::Path) -> PathBuf {
+        let mut part = path.as_os_str().to_owned();
+        part.push(".part");
+        PathBuf::from(part)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use base64::Engine;
+        use bytes::Bytes;
+        use futures::{future::BoxFuture, stream, FutureExt, StreamExt};
+
+        use super::*;
+        use crate::checksum::Algorithm;
+
+        /// `data` with an ETag of its own, and a `Digest` of `digest`
+        #[derive(Debug)]
+        struct Fixed {
+            data: Bytes,
+            etag: &'static str,
+            digest: Option<Bytes>,
+        }
+
+        impl Source for Fixed {
+            fn probe<'a>(&'a self, url: &'a str) -> BoxFuture<'a, DResult<Meta>> {
+                async move {
+                    let mut headers = HeaderMap::new();
+                    if let Some(digest) = &self.digest {
+                        let sha = Algorithm::Sha256.digest(digest);
+                        let value = base64::engine::general_purpose::STANDARD.encode(sha);
+                        headers.insert(
+                            "digest",
+                            HeaderValue::from_str(&format!("SHA-256={}", value)).unwrap(),
+                        );
+                    }
+                    Ok(Meta {
+                        url: url.to_owned(),
+                        size: self.data.len() as u64,
+                        accept_ranges: Some(true),
+                        validator: Some(self.etag.to_owned()),
+                        headers,
+                        ..Meta::default()
+                    })
+                }
+                .boxed()
+            }
+
+            fn open_range<'a>(
+                &'a self,
+                _url: &'a str,
+                (from, to): (u64, u64),
+            ) -> BoxFuture<'a, DResult<ByteStream>> {
+                let range = self.data.slice(from as usize..=to as usize);
+                async move { Ok(stream::iter([Ok(range)]).boxed()) }.boxed()
+            }
+        }
+
+        fn builder(digest: bool, mirror: &'static [u8]) -> DownloadBuilder {
+            let data = Bytes::from_static(b"the same sixteen");
+            let primary = Fixed {
+                data: data.clone(),
+                etag: "\"primary\"",
+                digest: digest.then_some(data),
+            };
+            let mirror = Fixed {
+                data: Bytes::from_static(mirror),
+                etag: "\"mirror\"",
+                digest: None,
+            };
+            DownloadBuilder::new("primary://example/file.bin")
+                .source("primary", Arc::new(primary))
+                .source("mirror", Arc::new(mirror))
+                .mirror("mirror://example/file.bin")
+                .chunks(2)
+        }
+
+        #[tokio::test]
+        async fn mirrors_need_the_validator_without_a_digest() {
+            let download = builder(false, b"the same sixteen").build().await.unwrap();
+            assert!(download.mirrors.is_empty());
+            assert_eq!(download.checksum, None);
+        }
+
+        #[tokio::test]
+        async fn mirrors_need_only_the_size_with_a_digest() {
+            let download = builder(true, b"the same sixteen").build().await.unwrap();
+            assert_eq!(download.mirrors, ["mirror://example/file.bin"]);
+            assert_eq!(
+                download.checksum.map(|c| c.algorithm),
+                Some(Algorithm::Sha256)
+            );
+        }
+
+        #[tokio::test]
+        async fn a_different_file_fails_the_digest() {
+            let dir = std::env::temp_dir().join(format!("donldr-digest-{}", std::process::id()));
+            tokio::fs::create_dir_all(&dir).await.unwrap();
+            let download = builder(true, b"another sixteen!")
+                .path(dir.to_string_lossy())
+                .retries(0)
+                .build()
+                .await
+                .unwrap();
+            assert_eq!(download.mirrors.len(), 1);
+            let ran = crate::engine::run(
+                Arc::new(download),
+                Arc::new(crate::limits::Limits::default()),
+            )
+            .await;
+            tokio::fs::remove_dir_all(&dir).await.unwrap();
+            assert!(matches!(ran, Err(Errors::Custom(e)) if e.starts_with("Checksum mismatch")));
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -148,9 +943,35 @@ pub enum Errors {
     Tracing(tracing::subscriber::SetGlobalDefaultError),
     Io(std::io::Error),
     Reqwest(reqwest::Error),
+    Signature(minisign_verify::Error),
+    Json(serde_json::Error),
+    Ftp(suppaftp::FtpError),
+    Ssh(russh::Error),
+    Sftp(russh_sftp::client::error::Error),
+    /// the download was stopped from outside, its progress is kept
+    Cancelled,
     Custom(String),
 }
 
+impl std::fmt::Display for Errors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Errors::Tracing(e) => write!(f, "{}", e),
+            Errors::Io(e) => write!(f, "{}", e),
+            Errors::Reqwest(e) => write!(f, "{}", e),
+            Errors::Signature(e) => write!(f, "{}", e),
+            Errors::Json(e) => write!(f, "{}", e),
+            Errors::Ftp(e) => write!(f, "{}", e),
+            Errors::Ssh(e) => write!(f, "{}", e),
+            Errors::Sftp(e) => write!(f, "{}", e),
+            Errors::Cancelled => write!(f, "cancelled"),
+            Errors::Custom(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Errors {}
+
 impl From<SetGlobalDefaultError> for Errors {
     fn from(value: SetGlobalDefaultError) -> Self {
         Errors::Tracing(value)
@@ -166,6 +987,31 @@ impl From<reqwest::Error> for Errors {
         Errors::Reqwest(value)
     }
 }
+impl From<minisign_verify::Error> for Errors {
+    fn from(value: minisign_verify::Error) -> Self {
+        Errors::Signature(value)
+    }
+}
+impl From<serde_json::Error> for Errors {
+    fn from(value: serde_json::Error) -> Self {
+        Errors::Json(value)
+    }
+}
+impl From<suppaftp::FtpError> for Errors {
+    fn from(value: suppaftp::FtpError) -> Self {
+        Errors::Ftp(value)
+    }
+}
+impl From<russh::Error> for Errors {
+    fn from(value: russh::Error) -> Self {
+        Errors::Ssh(value)
+    }
+}
+impl From<russh_sftp::client::error::Error> for Errors {
+    fn from(value: russh_sftp::client::error::Error) -> Self {
+        Errors::Sftp(value)
+    }
+}
 impl From<&str> for Errors {
     fn from(value: &str) -> Self {
         Errors::Custom(value.to_owned())
@@ -0,0 +1,255 @@
+//! Connection limits shared by every download running at once: a total
+//! budget, a cap per host and an optional minimum interval between requests
+//! to the same host, so a mirror doesn't see `chunks` connections from each
+//! file at the same time.
+//!
+//! Per host limits come from rules like `*.example.org=4/250ms`, the first
+//! rule whose pattern matches the host wins, hosts without one get the
+//! default per host cap.
+
+use std::{collections::HashMap, str::FromStr, sync::Arc, time::Duration};
+
+use tokio::{
+    sync::{Mutex, OwnedSemaphorePermit, Semaphore},
+    time::Instant,
+};
+use tracing::debug;
+
+use crate::{DResult, Errors};
+
+/// Limits for the hosts matching `pattern`, `*` matches any run of characters
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostRule {
+    pub pattern: String,
+    pub max_connections: Option<usize>,
+    pub min_interval: Option<Duration>,
+}
+
+impl HostRule {
+    pub fn matches(&self, host: &str) -> bool {
+        glob_match(
+            &self.pattern.to_ascii_lowercase(),
+            &host.to_ascii_lowercase(),
+        )
+    }
+}
+
+impl FromStr for HostRule {
+    type Err = Errors;
+
+    /// `<pattern>=<connections>[/<interval>]`, connections can be left out
+    /// to only space requests: `mirror.example.org=/1s`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Errors::Custom(format!("Host rule isn't pattern=N[/interval]: {}", s));
+        let (pattern, spec) = s.split_once('=').ok_or_else(invalid)?;
+        let (connections, interval) = match spec.split_once('/') {
+            Some((connections, interval)) => (connections, Some(interval)),
+            None => (spec, None),
+        };
+        let max_connections = match connections.trim() {
+            "" => None,
+            n => Some(n.parse::<usize>().map_err(|_| invalid())?.max(1)),
+        };
+        let min_interval = interval.map(parse_duration).transpose()?;
+        Ok(HostRule {
+            pattern: pattern.trim().to_owned(),
+            max_connections,
+            min_interval,
+        })
+    }
+}
+
+/// `250ms`, `2s`, or a bare number of milliseconds
+pub fn parse_duration(s: &str) -> DResult<Duration> {
+    let s = s.trim();
+    let invalid = || Errors::Custom(format!("Invalid duration: {}", s));
+    if let Some(ms) = s.strip_suffix("ms") {
+        Ok(Duration::from_millis(ms.parse().map_err(|_| invalid())?))
+    } else if let Some(secs) = s.strip_suffix('s') {
+        // negative, NaN or huge seconds aren't a duration either
+        Duration::try_from_secs_f64(secs.parse().map_err(|_| invalid())?).map_err(|_| invalid())
+    } else {
+        Ok(Duration::from_millis(s.parse().map_err(|_| invalid())?))
+    }
+}
+
+/// Whether `text` matches `pattern`, where `*` matches any run of characters
+pub(crate) fn glob_match(pattern: &str, text: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == text,
+        Some((prefix, rest)) => {
+            let Some(text) = text.strip_prefix(prefix) else {
+                return false;
+            };
+            (0..=text.len())
+                .filter(|i| text.is_char_boundary(*i))
+                .any(|i| glob_match(rest, &text[i..]))
+        }
+    }
+}
+
+struct Host {
+    connections: Option<Arc<Semaphore>>,
+    min_interval: Option<Duration>,
+    /// earliest time the next request to this host may start
+    next_request: Mutex<Instant>,
+}
+
+/// Held while a request is in flight, dropping it frees the connection
+pub struct Permit {
+    _host: Option<OwnedSemaphorePermit>,
+    _total: OwnedSemaphorePermit,
+}
+
+pub struct Limits {
+    total: Arc<Semaphore>,
+    per_host: Option<usize>,
+    rules: Vec<HostRule>,
+    hosts: std::sync::Mutex<HashMap<String, Arc<Host>>>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits::new(None)
+    }
+}
+
+impl Limits {
+    /// `max_connections` across all hosts, `None` for no limit
+    pub fn new(max_connections: Option<usize>) -> Self {
+        Limits {
+            total: Arc::new(Semaphore::new(
+                max_connections.map_or(Semaphore::MAX_PERMITS, |n| n.max(1)),
+            )),
+            per_host: None,
+            rules: vec![],
+            hosts: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Cap for hosts that no rule matches
+    pub fn per_host(mut self, max_connections: Option<usize>) -> Self {
+        self.per_host = max_connections.map(|n| n.max(1));
+        self
+    }
+
+    pub fn rule(mut self, rule: HostRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    fn host(&self, host: &str) -> Arc<Host> {
+        let mut hosts = self.hosts.lock().expect("host limits lock poisoned");
+        hosts
+            .entry(host.to_owned())
+            .or_insert_with(|| {
+                let rule = self.rules.iter().find(|rule| rule.matches(host));
+                debug!("limits for {}: {:?}", host, rule);
+                let max_connections = rule.and_then(|r| r.max_connections).or(self.per_host);
+                Arc::new(Host {
+                    connections: max_connections.map(|n| Arc::new(Semaphore::new(n))),
+                    min_interval: rule.and_then(|r| r.min_interval),
+                    next_request: Mutex::new(Instant::now()),
+                })
+            })
+            .clone()
+    }
+
+    /// Waits for a free connection to `host` and in total, and for the
+    /// host's minimum interval since the last request
+    pub async fn acquire(&self, host: &str) -> DResult<Permit> {
+        let slot = self.host(host);
+        // the host first, so waiting on a busy host doesn't hold a connection others could use
+        let host_permit = match &slot.connections {
+            Some(connections) => Some(
+                connections
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .map_err(|e| Errors::Custom(format!("{:?}", e)))?,
+            ),
+            None => None,
+        };
+        let total = self
+            .total
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|e| Errors::Custom(format!("{:?}", e)))?;
+
+        if let Some(interval) = slot.min_interval {
+            let start = {
+                let mut next_request = slot.next_request.lock().await;
+                let start = (*next_request).max(Instant::now());
+                *next_request = start + interval;
+                start
+            };
+            tokio::time::sleep_until(start).await;
+        }
+
+        Ok(Permit {
+            _host: host_permit,
+            _total: total,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs() {
+        assert!(glob_match("example.org", "example.org"));
+        assert!(!glob_match("example.org", "www.example.org"));
+        assert!(glob_match("*.example.org", "www.example.org"));
+        assert!(glob_match("*.example.org", "a.b.example.org"));
+        assert!(!glob_match("*.example.org", "example.org"));
+        assert!(glob_match("mirror*.example.*", "mirror2.example.net"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("a**b", "ab"));
+        assert!(!glob_match("*.iso", "image.iso.sig"));
+        assert!(glob_match("*ü*", "grüße"));
+    }
+
+    #[test]
+    fn rules_match_hosts_in_any_case() {
+        let rule: HostRule = "*.Example.ORG=2".parse().unwrap();
+        assert!(rule.matches("CDN.example.org"));
+        assert!(!rule.matches("example.com"));
+    }
+
+    #[test]
+    fn host_rules() {
+        let rule: HostRule = " *.example.org = 4/250ms".parse().unwrap();
+        assert_eq!(
+            rule,
+            HostRule {
+                pattern: "*.example.org".to_owned(),
+                max_connections: Some(4),
+                min_interval: Some(Duration::from_millis(250)),
+            }
+        );
+        let rule: HostRule = "mirror.example.org=/1s".parse().unwrap();
+        assert_eq!(rule.max_connections, None);
+        assert_eq!(rule.min_interval, Some(Duration::from_secs(1)));
+        let rule: HostRule = "slow.example.org=0".parse().unwrap();
+        assert_eq!(rule.max_connections, Some(1));
+        assert_eq!(rule.min_interval, None);
+
+        for invalid in ["example.org", "example.org=x", "example.org=2/soon", "=-1"] {
+            assert!(invalid.parse::<HostRule>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+        assert_eq!(parse_duration("2s").unwrap(), Duration::from_secs(2));
+        assert_eq!(parse_duration("1.5s").unwrap(), Duration::from_millis(1500));
+        assert_eq!(parse_duration(" 40 ").unwrap(), Duration::from_millis(40));
+        for invalid in ["", "s", "1m", "-1s", "NaNs", "infs", "1e400s", "-5ms"] {
+            assert!(parse_duration(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
@@ -1,50 +1,260 @@
 use std::{
-    borrow::Borrow,
-    fmt::write,
-    io::{Read, Write},
-    path::{Display, PathBuf},
-    ptr,
-    sync::atomic::AtomicUsize,
+    path::{Path, PathBuf},
+    sync::Arc,
 };
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use colored::Colorize;
 use donldr::{
-    download::{self, determine_file_path, Download},
-    set_tracing, DResult, Errors,
+    auth::{redact, Auth, Credentials, Netrc, Secret},
+    autoindex, batch,
+    checksum::Checksum,
+    cookies::CookieJar,
+    daemon::{self, JobState, Request, Response},
+    dash,
+    download::{parse_header, DownloadBuilder, DEFAULT_RETRIES},
+    engine, ftp, hls, lfs,
+    limits::{HostRule, Limits},
+    mirrors, oci, s3, set_tracing, sftp, signature,
+    source::{HttpOptions, HttpVersion, Registry},
+    torrent, webdav, DResult, Errors,
 };
-use futures::{stream, FutureExt, StreamExt, TryFutureExt};
-use reqwest::{Client, Error};
-use tokio::{
-    fs::File,
-    sync::mpsc::{self, Sender},
-    task::{JoinHandle, JoinSet},
-    time::Instant,
+use futures::{stream, StreamExt};
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, COOKIE, REFERER, USER_AGENT},
+    Url,
 };
-use tokio_util::bytes::BufMut;
-use tracing::{
-    debug, error, info,
-    subscriber::{self, SetGlobalDefaultError},
-    warn,
-};
-use tracing_subscriber::fmt::format;
+use tokio::time::Instant;
+use tracing::debug;
 
-struct DownloadTask {
-    url: String,
-    path: String,
-}
+/// Files downloaded at once with --recursive when --max-files isn't given
+const RECURSIVE_MAX_FILES: usize = 4;
 
-#[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[derive(Parser, Debug, Clone)]
+#[command(version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Cli {
-    ///URL to download file from
+    #[command(subcommand)]
+    command: Option<Command>,
+    ///URLs to download files from
+    #[arg(short, long, num_args = 1.., required_unless_present = "input_file")]
+    url: Vec<String>,
+    ///Treat the urls as directory listings and download the files in them
+    #[arg(short, long, requires = "url", conflicts_with = "same_file")]
+    recursive: bool,
+    ///List directories with WebDAV PROPFIND instead of reading HTML indexes
+    #[arg(long, requires = "recursive")]
+    webdav: bool,
+    ///Subdirectory levels to follow with --recursive
+    #[arg(long, default_value_t = autoindex::DEFAULT_DEPTH, requires = "recursive")]
+    depth: usize,
+    ///Only download files whose path in the listing matches, e.g. '*.tar.gz'
+    #[arg(long, requires = "recursive")]
+    include: Vec<String>,
+    ///Leave out files and directories (with a trailing /) whose path in the listing matches
+    #[arg(long, requires = "recursive")]
+    exclude: Vec<String>,
+    ///Treat all given urls as mirrors of the same file
+    #[arg(long, requires = "url")]
+    same_file: bool,
+    ///Another url serving the same file, ranges are spread across all of them
+    #[arg(long = "mirror")]
+    mirrors: Vec<String>,
+    ///Ignore mirrors and digests the server lists in Link: rel=duplicate and Digest headers
+    #[arg(long)]
+    no_metalink: bool,
+    ///Country code preferred among the server's listed mirrors, e.g. de
+    #[arg(long)]
+    geo: Option<String>,
+    ///Url to continue from if the url and its mirrors fail for good, e.g. start answering 404
+    #[arg(long = "fallback")]
+    fallbacks: Vec<String>,
+    ///Rank the url and its mirrors with a small download each, only use the fastest N
+    #[arg(long, value_name = "N")]
+    best_mirrors: Option<usize>,
+    ///HTTP version for ranges: 2 multiplexes them as streams over a few connections, auto asks the server with ALPN, 3 goes over QUIC
+    #[arg(long, default_value_t = HttpVersion::Auto, value_name = "auto|1.1|2|3")]
+    http_version: HttpVersion,
+    ///Switch to HTTP/3 where the server advertises it with Alt-Svc, staying on TCP if QUIC doesn't get through
+    #[arg(long)]
+    http3: bool,
+    ///HTTP/2 connections per download the ranges are spread over
+    #[arg(long, default_value_t = 1, value_name = "N")]
+    h2_connections: usize,
+    ///Switch ftp:// connections to TLS with AUTH TLS (explicit FTPS)
+    #[arg(long)]
+    ftp_tls: bool,
+    ///Let FTP servers connect back for data instead of using passive mode
+    #[arg(long)]
+    ftp_active: bool,
+    ///Private key to log in to sftp:// hosts with after the ssh-agent's, ~/.ssh/id_* by default
+    #[arg(long = "ssh-key", value_name = "PATH")]
+    ssh_keys: Vec<PathBuf>,
+    ///Connect to sftp:// hosts missing from ~/.ssh/known_hosts and add their key
+    #[arg(long)]
+    ssh_accept_new: bool,
+    ///Take the best .m3u8 variant or .mpd video at or below this many bits/s instead of the best one
+    #[arg(long, value_name = "BPS")]
+    max_bandwidth: Option<u64>,
+    ///Id of an .mpd representation to download instead of the best video and audio ones
+    #[arg(long = "representation", value_name = "ID")]
+    representations: Vec<String>,
+    ///Also download .torrent files from the peers their trackers know, not just web seeds
+    #[arg(long)]
+    peers: bool,
+    ///File listing urls to download, one per line, `-` for stdin
     #[arg(short, long)]
-    url: String, //can change to vec later to support multiple files
-    ///Target path to save the file
-    #[arg(short, long, default_value = "./")]
+    input_file: Option<String>,
+    ///Header sent with the probe and every range request, e.g. -H 'Authorization: Bearer abc'
+    #[arg(
+        short = 'H',
+        long = "header",
+        value_name = "NAME: VALUE",
+        value_parser = parse_header,
+        global = true
+    )]
+    headers: Vec<(HeaderName, HeaderValue)>,
+    ///User-Agent header to send
+    #[arg(long, global = true)]
+    user_agent: Option<HeaderValue>,
+    ///Referer header to send
+    #[arg(long, global = true)]
+    referer: Option<HeaderValue>,
+    ///Cookies to send, e.g. --cookie 'session=abc; theme=dark', instead of the cookie jar's
+    #[arg(
+        long = "cookie",
+        value_name = "NAME=VALUE",
+        value_parser = sensitive_value,
+        global = true
+    )]
+    cookies: Vec<HeaderValue>,
+    ///Send cookies from this Netscape cookies.txt file, e.g. exported from a browser
+    #[arg(long, value_name = "PATH", global = true)]
+    load_cookies: Option<PathBuf>,
+    ///Write the cookies, including the ones servers set during the downloads, to this cookies.txt file
+    #[arg(long, value_name = "PATH", global = true)]
+    save_cookies: Option<PathBuf>,
+    ///User to log in to the urls' hosts with, Basic or Digest as the server asks
+    #[arg(long, requires = "password", global = true)]
+    user: Option<String>,
+    ///Password for --user
+    #[arg(long, requires = "user", global = true)]
+    password: Option<Secret>,
+    ///Token sent as `Authorization: Bearer` to the urls' hosts
+    #[arg(long, value_name = "TOKEN", conflicts_with = "user", global = true)]
+    bearer: Option<Secret>,
+    ///Don't look up logins for hosts in ~/.netrc (or $NETRC)
+    #[arg(long, global = true)]
+    no_netrc: bool,
+    ///Send Basic logins and bearer tokens over plain http too, where they can be read on the way
+    #[arg(long, global = true)]
+    cleartext_auth: bool,
+    ///Target path to save the file, must be a directory for multiple urls
+    #[arg(short, long, default_value = "./", global = true)]
     path: String,
     ///Chunks to divide the file into concurrent downloads
-    #[arg(short, long, default_value_t = 8)]
+    #[arg(short, long, default_value_t = 8, global = true)]
     chunks: usize,
+    ///Cap on open connections across all files
+    #[arg(long, global = true)]
+    max_connections: Option<usize>,
+    ///Cap on open connections to a single host
+    #[arg(long, global = true)]
+    per_host: Option<usize>,
+    ///Limits for matching hosts as PATTERN=N[/INTERVAL], e.g. '*.example.org=4/250ms'
+    #[arg(long = "host-limit", global = true)]
+    host_limits: Vec<HostRule>,
+    ///Cap on files downloaded at once
+    #[arg(long, global = true)]
+    max_files: Option<usize>,
+    ///Times a failed request is retried
+    #[arg(long, default_value_t = DEFAULT_RETRIES, global = true)]
+    retries: usize,
+    ///Control socket of the daemon
+    #[arg(long, global = true)]
+    socket: Option<PathBuf>,
+    ///Expected digest of the file as type=hex, e.g. sha-256=...
+    #[arg(long)]
+    checksum: Option<Checksum>,
+    ///Detached minisign signature (url or path) to verify the file against
+    #[arg(long, requires = "pubkey")]
+    signature: Option<String>,
+    ///Minisign public key (base64 or path to .pub file)
+    #[arg(long, requires = "signature")]
+    pubkey: Option<String>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    ///Run the persistent download queue
+    Daemon {
+        ///Queue file, defaults to $XDG_STATE_HOME/donldr/queue.json
+        #[arg(long)]
+        queue: Option<PathBuf>,
+    },
+    ///Queue urls in the daemon
+    Add {
+        #[arg(required = true)]
+        urls: Vec<String>,
+    },
+    ///List the daemon's jobs
+    Ls,
+    ///Pause a queued or running job
+    Pause { id: u64 },
+    ///Queue a paused or failed job again
+    Resume { id: u64 },
+    ///Remove a job, deleting its partial download
+    Rm { id: u64 },
+    ///Compare mirrors of a file
+    Mirrors {
+        #[command(subcommand)]
+        command: MirrorsCommand,
+    },
+    ///Fetch Git LFS objects of the repository in the current directory
+    Lfs {
+        ///Pointer files or directories to look for them in, the whole worktree by default
+        paths: Vec<PathBuf>,
+        ///An object to fetch as oid:size, can be repeated
+        #[arg(long = "oid")]
+        oids: Vec<lfs::Pointer>,
+        ///Remote whose LFS endpoint to use
+        #[arg(long, default_value = "origin")]
+        remote: String,
+        ///LFS endpoint to use instead of the configured one
+        #[arg(long)]
+        endpoint: Option<Url>,
+    },
+    ///Pull images from OCI / Docker registries
+    Oci {
+        #[command(subcommand)]
+        command: OciCommand,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum OciCommand {
+    ///Download an image into an OCI image layout directory under the path
+    Pull {
+        ///registry/repository:tag or registry/repository@sha256:digest
+        reference: oci::Reference,
+        ///Image to take from multi-arch indexes as os/arch[/variant], linux on this arch by default
+        #[arg(long)]
+        platform: Option<oci::Platform>,
+        ///Talk to the registry over http instead of https
+        #[arg(long)]
+        plain_http: bool,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum MirrorsCommand {
+    ///Rank urls by throughput and time to first byte
+    Rank {
+        #[arg(required = true)]
+        urls: Vec<String>,
+        ///Bytes to fetch from each url
+        #[arg(long, default_value_t = mirrors::DEFAULT_SAMPLE)]
+        sample: u64,
+    },
 }
 
 #[tokio::main]
@@ -52,249 +262,845 @@ async fn main() -> DResult<()> {
     set_tracing()?;
 
     let c = Cli::parse();
-    debug!("parsed cli:\n{:#?}", c);
-    let download = Download::new(c.url, c.path, c.chunks).await?;
-
-    let file_path = determine_file_path(&download.path, &download.url);
-
-    let file = File::options()
-        .read(true)
-        .write(true)
-        .create(true)
-        .open(&file_path)
-        .await
-        .unwrap();
-
-    file.set_len(download.info.size).await?;
-
-    let mut mmap: memmap2::MmapMut =
-        unsafe { memmap2::MmapMut::map_mut(&file).expect("getting a mmap for file failed") };
-
-    // let mut downloaders: Vec<JoinHandle<()>> = vec![];
-    let mut downloaders: JoinSet<Result<usize, Errors>> = JoinSet::new();
-    let (progress_tx, mut progress_rx) = mpsc::channel(download.info.chunks * 2);
-
-    let start_time = Instant::now();
-    let stream_of_requests = stream::iter((0..download.info.chunks).map(|idx| {
-        let (from, to) = download.get_ranges(idx as usize);
-        let req = download
-            .client
-            .get(&download.url)
-            .header("Range", format!("bytes={}-{}", from, to))
-            .send();
-        req.map(move |x| (idx, x))
-    }));
-    let mut buffer = stream_of_requests.buffer_unordered(download.info.chunks);
-
-    while let Some((idx, response)) = buffer.next().await {
-        match response {
-            Ok(res) => {
-                let (from, to) = download.get_ranges(idx as usize);
-                let len = (to - from + 1) as usize;
-                let mut chunk = Memory::new(unsafe { mmap.as_mut_ptr().add(from as usize) }, len);
-                let mut stream = res.bytes_stream();
-                let p_tx = progress_tx.clone();
-                downloaders.spawn(async move {
-                    let mut written = 0;
-                    while let Some(chunk_result) = stream.next().await {
-                        let respchunk = chunk_result?;
-                        chunk.write_at(
-                            written,
-                            respchunk.as_ptr(),
-                            std::cmp::min(respchunk.len(), chunk.len - written),
-                        );
-                        written += respchunk.len();
-                        // let perc = written as f32 / len as f32;
-                        // debug!("{}/{} : {:>3}",written, len, perc);
-                        p_tx.send((idx, respchunk.len(), len))
-                            .await
-                            .map_err(|e| Errors::Custom(format!("{:?}", e)))?;
-                    }
-                    Ok(idx)
-                });
+    debug!("parsed cli:\n{:#?}", redacted_cli(&c));
+    let cookies = cookie_jar(&c).await?;
+    let res = match &c.command {
+        Some(command) => run_command(command, &c, &cookies).await,
+        None => download(&c, &cookies).await,
+    };
+    // what the servers set is kept even when downloads failed
+    if let (Some(path), Some(cookies)) = (&c.save_cookies, &cookies) {
+        cookies.save(path).await?;
+    }
+    res
+}
+
+/// Downloads the urls, input file entries and listings on the command line
+async fn download(c: &Cli, cookies: &Option<Arc<CookieJar>>) -> DResult<()> {
+    let client = client(c, cookies)?;
+    let entries = match &c.input_file {
+        Some(input_file) => batch::read(input_file).await?,
+        None => vec![],
+    };
+    // the input file's urls were named by the user too, the hosts they
+    // lead to weren't
+    let named = entries
+        .iter()
+        .flat_map(|entry| std::iter::once(&entry.url).chain(&entry.fallbacks))
+        .cloned()
+        .collect::<Vec<_>>();
+    let auth = auth(c, &named);
+    let mut builders: Vec<DownloadBuilder> = if c.recursive {
+        recursive_builders(c, cookies, &auth).await?
+    } else if c.same_file {
+        // the first url is the primary, the others mirrors of it
+        c.url
+            .split_first()
+            .map(|(url, mirrors)| {
+                mirrors
+                    .iter()
+                    .fold(DownloadBuilder::new(url).path(&c.path), |b, m| b.mirror(m))
+            })
+            .into_iter()
+            .collect()
+    } else {
+        let mut builders = vec![];
+        for url in c.url.iter().filter(|url| !is_stream(url)) {
+            builders.push(match torrent::is_torrent(url) {
+                true => torrent_builder(url, &c.path, &client, &auth, c).await?,
+                false => DownloadBuilder::new(url).path(&c.path),
+            });
+        }
+        builders
+    };
+    let headers = headers(c);
+    // streams are fetched segment by segment instead of in ranges
+    let mut streams: Vec<Stream> = if c.recursive || c.same_file {
+        vec![]
+    } else {
+        c.url
+            .iter()
+            .filter(|url| is_stream(url))
+            .map(|url| Stream {
+                url: url.clone(),
+                path: c.path.clone(),
+                headers: headers.clone(),
+            })
+            .collect()
+    };
+    if !c.mirrors.is_empty() {
+        match builders.as_mut_slice() {
+            [builder] => {
+                *builder = c.mirrors.iter().fold(builder.clone(), |b, m| b.mirror(m));
             }
-            Err((err)) => {
-                //Request failed how to retry?
-                error!("Send failed: {}", err);
-                // Err(idx)
-                // buffer.
+            _ => return Err("--mirror can only be used with a single url".into()),
+        }
+    }
+    if !c.fallbacks.is_empty() {
+        match builders.as_mut_slice() {
+            [builder] => {
+                *builder = c
+                    .fallbacks
+                    .iter()
+                    .fold(builder.clone(), |b, f| b.fallback(f));
             }
+            _ => return Err("--fallback can only be used with a single url".into()),
         }
     }
-
-    let lens = download
-        .info
-        .ranges
-        .iter()
-        .map(|(from, to)| (to - from + 1) as usize)
-        .collect();
-    debug!("lens   {:?}", lens);
-    let mut stats = Status::new(lens);
-    debug!("stats: {:?}", stats);
-    //TODO: indicatif
-    tokio::spawn(async move {
-        let mut write_checkp = 0;
-        let mut total_written = 0;
-        while let Some((idx, written, len)) = progress_rx.recv().await {
-            stats.add_to(idx, written);
-            total_written += written;
-            // debug!("[{:2}]: {} of {}", idx, written, len);
-            if ((total_written - write_checkp) as f32 / download.info.size as f32) * 100. > 1. {
-                write_checkp = total_written;
-                info!("\n{}", stats);
+    // the urls of an input file go where the same urls on the command
+    // line would, with the entry's options on top
+    for entry in entries {
+        if is_stream(&entry.url) {
+            let mut stream_headers: HeaderMap = entry.headers.iter().cloned().collect();
+            for (name, value) in &headers {
+                if !stream_headers.contains_key(name) {
+                    stream_headers.append(name, value.clone());
+                }
             }
+            streams.push(Stream {
+                path: entry.path(&c.path),
+                url: entry.url,
+                headers: stream_headers,
+            });
+        } else if torrent::is_torrent(&entry.url) {
+            let path = entry.path(&c.path);
+            let builder = torrent_builder(&entry.url, &path, &client, &auth, c).await?;
+            builders.push(entry.options(builder));
+        } else {
+            builders.push(entry.builder(&c.path));
+        }
+    }
+
+    let jobs = builders.len() + streams.len();
+    if jobs > 1 && !c.recursive && !Path::new(&c.path).is_dir() {
+        return Err("Target path must be a directory when downloading multiple urls".into());
+    }
+    if jobs > 1 && (c.signature.is_some() || c.checksum.is_some()) {
+        return Err("--signature and --checksum can only be used with a single url".into());
+    }
+    // fail early on a bad key instead of after the whole download
+    let pubkey = c
+        .pubkey
+        .as_deref()
+        .map(signature::load_pubkey)
+        .transpose()?;
+
+    let limits = limits(c);
+    // a listing can hold any number of files, don't start them all at once
+    let max_files = c
+        .max_files
+        .unwrap_or(if c.recursive {
+            RECURSIVE_MAX_FILES
+        } else {
+            jobs
+        })
+        .max(1);
+
+    let builders = builders.into_iter().map(|builder| {
+        let mut builder = configure(builder, c, &headers, cookies, &auth);
+        if let (Some(sig), Some(pubkey)) = (&c.signature, &pubkey) {
+            builder = builder.signature(sig, pubkey.clone());
+        }
+        if let Some(checksum) = &c.checksum {
+            builder = builder.checksum(checksum.clone());
         }
-        debug!("Progress loop ended");
+        builder
     });
+    let mut results = stream::iter(builders)
+        .map(|builder| {
+            let limits = limits.clone();
+            async move {
+                let start_time = Instant::now();
+                let url = builder.url().to_owned();
+                let res = async {
+                    let download = builder.build().await?;
+                    Ok(vec![engine::run(Arc::new(download), limits).await?])
+                }
+                .await;
+                (url, res, start_time.elapsed())
+            }
+        })
+        .buffered(max_files)
+        .collect::<Vec<_>>()
+        .await;
+    let hls_options = hls::Options {
+        max_bandwidth: c.max_bandwidth,
+        connections: c.chunks,
+        retries: c.retries,
+        auth: auth.clone(),
+    };
+    let dash_options = dash::Options {
+        representations: c.representations.clone(),
+        max_bandwidth: c.max_bandwidth,
+        connections: c.chunks,
+        retries: c.retries,
+        auth: auth.clone(),
+    };
+    results.extend(
+        stream::iter(streams)
+            .map(|stream| {
+                let (client, limits) = (&client, limits.clone());
+                let (hls_options, dash_options) = (&hls_options, &dash_options);
+                async move {
+                    let Stream { url, path, headers } = &stream;
+                    let start_time = Instant::now();
+                    let res = if dash::is_manifest(url) {
+                        dash::download(client, url, path, headers, dash_options, limits).await
+                    } else {
+                        hls::download(client, url, path, headers, hls_options, limits)
+                            .await
+                            .map(|path| vec![path])
+                    };
+                    (stream.url, res, start_time.elapsed())
+                }
+            })
+            .buffered(max_files)
+            .collect::<Vec<_>>()
+            .await,
+    );
 
-    while let Some(Ok(res)) = downloaders.join_next().await {
-        let idx = res.unwrap();
-        info!("done {}", idx);
+    let mut failed = 0;
+    for (url, res, took) in &results {
+        match res {
+            Ok(paths) => println!(
+                "{} {} -> {} ({:?})",
+                "✓".green(),
+                redact(url),
+                paths
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                took
+            ),
+            Err(e) => {
+                failed += 1;
+                println!("{} {}: {}", "x".red(), redact(url), e);
+            }
+        }
     }
 
-    debug!("Mem download finished in {:?}", start_time.elapsed());
-    debug!("all tasks finished and returned");
+    if failed > 0 {
+        Err(format!("{} of {} downloads failed", failed, results.len())
+            .as_str()
+            .into())
+    } else {
+        Ok(())
+    }
+}
 
-    let disk_time = Instant::now();
-    mmap.flush()?;
-    debug!("mmap flushed, took {:?}", disk_time.elapsed());
-    debug!("Total download finished in {:?}", start_time.elapsed());
+fn limits(c: &Cli) -> Arc<Limits> {
+    Arc::new(
+        c.host_limits
+            .iter()
+            .cloned()
+            .fold(Limits::new(c.max_connections), Limits::rule)
+            .per_host(c.per_host),
+    )
+}
 
-    Ok(())
+/// The download of a `.torrent`'s file from its web seeds and peers into
+/// `path`
+async fn torrent_builder(
+    url: &str,
+    path: &str,
+    client: &reqwest::Client,
+    auth: &Option<Arc<Auth>>,
+    c: &Cli,
+) -> DResult<DownloadBuilder> {
+    let torrent = torrent::Torrent::load(client, auth.as_deref(), url, c.retries).await?;
+    debug!(
+        "torrent {}: {} bytes in {} pieces, web seeds {:?}",
+        torrent.name,
+        torrent.length,
+        torrent.pieces.sha1.len(),
+        torrent.web_seeds
+    );
+    let options = torrent::Options {
+        peers: c.peers,
+        retries: c.retries,
+    };
+    torrent.builder(client, path, &options).await
+}
+
+/// An HLS playlist or DASH manifest to download, with where to and the
+/// headers to send
+struct Stream {
+    url: String,
+    path: String,
+    headers: HeaderMap,
 }
 
-/// SAFETY:
-///  This type's mutating functions are UNSAFE
-///  it's suppossed to be used with MMAPd memory
-///  address with a known length. If the length
-///  provided is not right or ptr is not a valid
-///  memory region with required permissions it
-///  will cause issues.
-struct Memory {
-    inner: *mut u8,
-    len: usize,
-    cursor: usize,
+/// Urls fetched segment by segment: HLS playlists and DASH manifests
+fn is_stream(url: &str) -> bool {
+    hls::is_playlist(url) || dash::is_manifest(url)
 }
 
-impl Memory {
-    ///SAFETY:
-    /// This type assumes it's a mmap memory region
-    /// with required permissions, and a valid len.
-    fn new(ptr: *mut u8, len: usize) -> Self {
-        Memory {
-            inner: ptr,
-            len,
-            cursor: 0,
-        }
+/// The -H, --user-agent, --referer and --cookie headers
+fn headers(c: &Cli) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for (name, value) in &c.headers {
+        headers.append(name.clone(), value.clone());
+    }
+    if let Some(user_agent) = &c.user_agent {
+        headers.insert(USER_AGENT, user_agent.clone());
+    }
+    if let Some(referer) = &c.referer {
+        headers.insert(REFERER, referer.clone());
+    }
+    if !c.cookies.is_empty() {
+        let cookies = c
+            .cookies
+            .iter()
+            .map(HeaderValue::as_bytes)
+            .collect::<Vec<_>>()
+            .join(&b"; "[..]);
+        let mut cookies =
+            HeaderValue::from_bytes(&cookies).expect("joined cookies are a valid header value");
+        cookies.set_sensitive(true);
+        headers.insert(COOKIE, cookies);
     }
+    headers
+}
 
-    ///SAFETY:
-    /// This type assumes it's a mmap memory region
-    /// with required permissions.
-    /// If the src to be copied from has length
-    /// less than self.len this function will try
-    /// to access beyond it's length:
-    ///     SIGSEGV ADDRESS BOUNDARY ERROR
-    /// consider using:
-    ///     copy_fill with min(self.len, src.len)
-    fn copy_fill_from(&mut self, src: *const u8) {
-        unsafe {
-            self.inner.copy_from(src, self.len);
-        }
+/// A header value left out of the `parsed cli` log
+fn sensitive_value(value: &str) -> DResult<HeaderValue> {
+    let mut value = HeaderValue::from_str(value)
+        .map_err(|e| Errors::Custom(format!("Invalid header value: {}", e)))?;
+    value.set_sensitive(true);
+    Ok(value)
+}
+
+/// The cli with the passwords in its urls replaced, for the log
+fn redacted_cli(c: &Cli) -> Cli {
+    let all = |urls: &[String]| urls.iter().map(|url| redact(url)).collect();
+    Cli {
+        url: all(&c.url),
+        mirrors: all(&c.mirrors),
+        fallbacks: all(&c.fallbacks),
+        ..c.clone()
     }
+}
 
-    ///SAFETY:
-    /// If the len doesnt go out of bounds for both
-    /// src and self, assuming this types usecase
-    /// as a mmap memory region and src an accessible
-    /// memory address, it should be safe.
-    /// len should be: min(self.len, src.len)
-    fn copy_fill(&mut self, src: *const u8, len: usize) {
-        assert!(
-            len <= self.len,
-            "Can't give a length larger than allocated memory length"
-        );
-        unsafe { self.inner.copy_from(src, len) }
+/// The --load-cookies jar, or an empty one to fill for --save-cookies
+async fn cookie_jar(c: &Cli) -> DResult<Option<Arc<CookieJar>>> {
+    Ok(match (&c.load_cookies, &c.save_cookies) {
+        (Some(path), _) => Some(Arc::new(CookieJar::load(path).await?)),
+        (None, Some(_)) => Some(Arc::default()),
+        (None, None) => None,
+    })
+}
+
+/// A client for requests outside of the downloads, with the cookie jar
+/// and the -H headers
+fn client(c: &Cli, cookies: &Option<Arc<CookieJar>>) -> DResult<reqwest::Client> {
+    let mut builder = reqwest::Client::builder().default_headers(headers(c));
+    if let Some(cookies) = cookies {
+        builder = builder.cookie_provider(cookies.clone());
     }
+    Ok(builder.build()?)
+}
 
-    fn write_at(&mut self, offset: usize, src: *const u8, len: usize) {
-        assert!(
-            offset + len <= self.len,
-            "Writes out of bounds, offset+len can't be larger than self.len"
-        );
-        unsafe { self.inner.add(offset).copy_from(src, len) }
+/// The options from the cli every download gets, whichever way its
+/// builder was made
+fn configure(
+    builder: DownloadBuilder,
+    c: &Cli,
+    headers: &HeaderMap,
+    cookies: &Option<Arc<CookieJar>>,
+    auth: &Option<Arc<Auth>>,
+) -> DownloadBuilder {
+    let mut builder = builder
+        .chunks(c.chunks)
+        .retries(c.retries)
+        .metalink(!c.no_metalink);
+    if let Some(geo) = &c.geo {
+        builder = builder.geo(geo);
     }
+    if let Some(n) = c.best_mirrors {
+        builder = builder.best_mirrors(n);
+    }
+    builder
+        .default_headers(headers)
+        .http(http_options(c, cookies, auth))
+        .ftp(ftp_options(c))
+        .sftp(sftp_options(c))
 }
 
-unsafe impl Send for Memory {}
-unsafe impl Sync for Memory {}
+/// The --user or --bearer credentials and the .netrc logins, for the hosts
+/// of the --url, --mirror and --fallback urls and the `named` ones (input
+/// file or `mirrors rank` urls). Hosts only reached through them, a web
+/// seed or a registry's CDN, get no login.
+fn auth(c: &Cli, named: &[String]) -> Option<Arc<Auth>> {
+    let credentials = match (&c.user, &c.password, &c.bearer) {
+        (Some(user), Some(password), _) => Some(Credentials::Login {
+            user: user.clone(),
+            password: password.clone(),
+        }),
+        (_, _, Some(token)) => Some(Credentials::Bearer(token.clone())),
+        _ => None,
+    };
+    let netrc = (!c.no_netrc).then(Netrc::load).flatten();
+    if credentials.is_none() && netrc.is_none() {
+        return None;
+    }
+    let hosts = named
+        .iter()
+        .chain(&c.url)
+        .chain(&c.mirrors)
+        .chain(&c.fallbacks)
+        .filter_map(|url| Url::parse(url).ok()?.host_str().map(str::to_owned))
+        .collect();
+    Some(Arc::new(
+        Auth::new(credentials, hosts, netrc).cleartext(c.cleartext_auth),
+    ))
+}
 
-#[derive(Debug)]
-struct Status {
-    progs: Vec<Progress>,
+fn http_options(
+    c: &Cli,
+    cookies: &Option<Arc<CookieJar>>,
+    auth: &Option<Arc<Auth>>,
+) -> HttpOptions {
+    HttpOptions {
+        version: c.http_version,
+        connections: c.h2_connections,
+        alt_svc: c.http3,
+        cookies: cookies.clone(),
+        auth: auth.clone(),
+        ..Default::default()
+    }
 }
-impl Status {
-    /// total size list must be index ordered
-    fn new(total_size_list: Vec<usize>) -> Self {
-        Status {
-            progs: total_size_list
-                .iter()
-                .map(|chunk_sz| Progress::new(0, *chunk_sz))
-                .collect(),
-        }
+
+fn ftp_options(c: &Cli) -> ftp::Options {
+    ftp::Options {
+        explicit_tls: c.ftp_tls,
+        active: c.ftp_active,
     }
+}
 
-    fn add_to(&mut self, idx: usize, written: usize) {
-        assert!(idx < self.progs.len(), "Indexing beyond existing progs?");
-        self.progs.get_mut(idx).unwrap().add_prog(written);
+fn sftp_options(c: &Cli) -> sftp::Options {
+    sftp::Options {
+        identities: c.ssh_keys.clone(),
+        accept_new_hosts: c.ssh_accept_new,
     }
+}
 
-    fn get_total_written(&self) -> usize {
-        self.progs.iter().fold(0, |a, b| a + b.current)
+/// A builder for every file in the listings at the urls, saved under the
+/// path in the same tree
+async fn recursive_builders(
+    c: &Cli,
+    cookies: &Option<Arc<CookieJar>>,
+    auth: &Option<Arc<Auth>>,
+) -> DResult<Vec<DownloadBuilder>> {
+    let client = client(c, cookies)?;
+    let headers = headers(c);
+    // an -H Authorization is the login then, like for the downloads
+    let auth = auth
+        .as_deref()
+        .filter(|_| !headers.contains_key(AUTHORIZATION));
+    let options = autoindex::Options {
+        depth: c.depth,
+        include: c.include.clone(),
+        exclude: c.exclude.clone(),
+    };
+    let mut files = vec![];
+    for url in &c.url {
+        if url.starts_with("s3://") {
+            let found = s3::S3::from_env(client.clone(), c.retries)
+                .crawl(url, &options)
+                .await?;
+            let total = found.len();
+            let before = files.len();
+            for file in found {
+                let path = Path::new(&c.path).join(&file.path);
+                let object = file.object;
+                if engine::is_up_to_date(&path, object.size, object.last_modified).await {
+                    debug!("{:?} is up to date, skipping", path);
+                    continue;
+                }
+                files.push((object.url, path));
+            }
+            println!(
+                "{} objects in {}, {} changed",
+                total,
+                redact(url),
+                files.len() - before
+            );
+        } else if c.webdav {
+            let found = webdav::crawl(&client, auth, url, &headers, &options, c.retries).await?;
+            let total = found.len();
+            let before = files.len();
+            for file in found {
+                let path = Path::new(&c.path).join(&file.path);
+                let resource = file.resource;
+                // the listing has what a probe would tell, no need to send one
+                if let Some(size) = resource.size {
+                    if engine::is_up_to_date(&path, size, resource.last_modified).await {
+                        debug!("{:?} is up to date, skipping", path);
+                        continue;
+                    }
+                }
+                files.push((resource.url, path));
+            }
+            println!(
+                "{} files in {}, {} changed",
+                total,
+                redact(url),
+                files.len() - before
+            );
+        } else {
+            let found = autoindex::crawl(&client, auth, url, &headers, &options, c.retries).await?;
+            println!("{} files in {}", found.len(), redact(url));
+            files.extend(
+                found
+                    .into_iter()
+                    .map(|file| (file.url, Path::new(&c.path).join(&file.path))),
+            );
+        }
     }
+    Ok(files
+        .into_iter()
+        .map(|(url, path)| {
+            DownloadBuilder::new(url)
+                .path(path.to_string_lossy())
+                .skip_unchanged(true)
+        })
+        .collect())
 }
 
-impl std::fmt::Display for Status {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "[")?;
-        for pg in 0..self.progs.len() - 1 {
-            write!(f, "{:^4}|", pg)?;
+async fn run_command(command: &Command, c: &Cli, cookies: &Option<Arc<CookieJar>>) -> DResult<()> {
+    let socket = c.socket.clone().unwrap_or_else(daemon::default_socket_path);
+    let requests = match command {
+        Command::Daemon { queue } => {
+            return daemon::serve(daemon::Config {
+                socket,
+                queue: queue.clone().unwrap_or_else(daemon::default_queue_path),
+                max_jobs: c.max_files.unwrap_or(2),
+                max_connections: c.max_connections,
+                per_host: c.per_host,
+                host_rules: c.host_limits.clone(),
+                retries: c.retries,
+            })
+            .await;
         }
-        write!(f, "|{:^4}]\n", self.progs.len() - 1)?;
-        write!(f, "[")?;
-        for pg in 0..self.progs.len() - 1 {
-            write!(
-                f,
-                "{:^4}%|",
-                self.progs.get(pg).unwrap().percentage() * 100.
-            )?;
+        Command::Add { urls } => {
+            // a job is only its url, path and chunks, the daemon would
+            // download without these
+            let dropped = [
+                ("-H/--header", !c.headers.is_empty()),
+                ("--user-agent", c.user_agent.is_some()),
+                ("--referer", c.referer.is_some()),
+                ("--cookie", !c.cookies.is_empty()),
+                ("--load-cookies", c.load_cookies.is_some()),
+                ("--save-cookies", c.save_cookies.is_some()),
+                ("--user", c.user.is_some()),
+                ("--bearer", c.bearer.is_some()),
+            ]
+            .into_iter()
+            .filter_map(|(flag, set)| set.then_some(flag))
+            .collect::<Vec<_>>();
+            if !dropped.is_empty() {
+                return Err(Errors::Custom(format!(
+                    "`donldr add` can't pass {} on to the daemon, `donldr -u` downloads with them",
+                    dropped.join(", ")
+                )));
+            }
+            // the daemon runs in its own directory
+            let path = std::path::absolute(&c.path)?;
+            urls.iter()
+                .map(|url| Request::Add {
+                    url: url.clone(),
+                    path: path.to_string_lossy().into_owned(),
+                    chunks: c.chunks,
+                })
+                .collect()
+        }
+        Command::Ls => vec![Request::List],
+        Command::Pause { id } => vec![Request::Pause { id: *id }],
+        Command::Resume { id } => vec![Request::Resume { id: *id }],
+        Command::Rm { id } => vec![Request::Remove { id: *id }],
+        Command::Mirrors {
+            command: MirrorsCommand::Rank { urls, sample },
+        } => return rank_mirrors(urls, *sample, c, cookies).await,
+        Command::Lfs {
+            paths,
+            oids,
+            remote,
+            endpoint,
+        } => return lfs_pull(paths, oids, remote, endpoint.as_ref(), c, cookies).await,
+        Command::Oci {
+            command:
+                OciCommand::Pull {
+                    reference,
+                    platform,
+                    plain_http,
+                },
+        } => return oci_pull(reference, platform.as_ref(), *plain_http, c, cookies).await,
+    };
+
+    for request in &requests {
+        match daemon::request(&socket, request).await? {
+            Response::Added { id } => println!("{} added as {}", "✓".green(), id),
+            Response::Ok => println!("{}", "✓".green()),
+            Response::Jobs { jobs } => {
+                for job in jobs {
+                    let state = match &job.state {
+                        JobState::Done => "done".green(),
+                        JobState::Failed(e) => format!("failed: {}", e).red(),
+                        JobState::Running => "running".yellow(),
+                        state => format!("{:?}", state).to_lowercase().normal(),
+                    };
+                    let progress = match job.size {
+                        0 => "   ?".to_owned(),
+                        size => format!("{:>3}%", job.written * 100 / size),
+                    };
+                    println!(
+                        "{:>4} {} {} {} -> {}",
+                        job.id,
+                        progress,
+                        state,
+                        redact(&job.url),
+                        job.path
+                    );
+                }
+            }
+            Response::Error { message } => return Err(Errors::Custom(message)),
         }
-        write!(
-            f,
-            "|{:^4}%]",
-            self.progs.last().unwrap().percentage() * 100.
-        )
     }
+    Ok(())
 }
 
-#[derive(Debug)]
-struct Progress {
-    current: usize,
-    total: usize,
+/// Resolves the image, downloads the blobs missing from its layout and
+/// adds it to the layout's index once they're all in
+async fn oci_pull(
+    reference: &oci::Reference,
+    platform: Option<&oci::Platform>,
+    plain_http: bool,
+    c: &Cli,
+    cookies: &Option<Arc<CookieJar>>,
+) -> DResult<()> {
+    let client = client(c, cookies)?;
+    let options = oci::Options {
+        platform: platform.cloned().unwrap_or_else(oci::Platform::current),
+        plain_http,
+        retries: c.retries,
+    };
+    let pull = oci::Pull::resolve(&client, reference.clone(), Path::new(&c.path), &options).await?;
+    let builders = pull.builders(&client, c.retries).await?;
+    println!(
+        "{}: {} of {} blobs to download",
+        reference,
+        builders.len(),
+        pull.blobs.len()
+    );
+
+    let total = builders.len();
+    let failed = download_all(builders, c, cookies).await;
+    if failed > 0 {
+        return Err(Errors::Custom(format!(
+            "{} of {} blobs failed",
+            failed, total
+        )));
+    }
+
+    pull.finish().await?;
+    println!("{} {} -> {}", "✓".green(), reference, pull.layout.display());
+    Ok(())
 }
 
-impl Progress {
-    fn new(current: usize, total: usize) -> Self {
-        Progress { current, total }
+/// Fetches the LFS objects the pointers under `paths` and `oids` name into
+/// the repository's object store
+async fn lfs_pull(
+    paths: &[PathBuf],
+    oids: &[lfs::Pointer],
+    remote: &str,
+    endpoint: Option<&Url>,
+    c: &Cli,
+    cookies: &Option<Arc<CookieJar>>,
+) -> DResult<()> {
+    let client = client(c, cookies)?;
+    let mut pointers = oids.to_vec();
+    if !paths.is_empty() || oids.is_empty() {
+        let paths = match paths.is_empty() {
+            true => vec![PathBuf::from(".")],
+            false => paths.to_vec(),
+        };
+        for pointer in lfs::pointers(&paths).await? {
+            if !pointers.contains(&pointer) {
+                pointers.push(pointer);
+            }
+        }
     }
-    fn percentage(&self) -> f32 {
-        self.current as f32 / self.total as f32
+    let mut store = lfs::Lfs::open(remote, endpoint.cloned()).await?;
+    let builders = store.builders(&client, &pointers, c.retries).await?;
+    println!(
+        "{} of {} LFS objects to download",
+        builders.len(),
+        pointers.len()
+    );
+
+    let total = builders.len();
+    let mut failed = 0;
+    let mut ok = vec![];
+    for builder in builders {
+        match builder {
+            Ok(builder) => ok.push(builder),
+            Err(e) => {
+                failed += 1;
+                println!("{} {}", "x".red(), e);
+            }
+        }
     }
-    fn add_prog(&mut self, written: usize) {
-        if self.current + written > self.total {
-            // error!("Progress overflow?, got more chunks than expected?: {}/{} wanted to write {} more -> !{}<={}", self.current, self.total, written, written+self.current, self.total);
+    failed += download_all(ok, c, cookies).await;
+    if failed > 0 {
+        return Err(Errors::Custom(format!(
+            "{} of {} LFS objects failed",
+            failed, total
+        )));
+    }
+    println!("{} {}", "✓".green(), store.objects.display());
+    Ok(())
+}
+
+/// Runs `builders` through the engine with the cli's options, `--max-files`
+/// at once, printing each result; the number that failed
+async fn download_all(
+    builders: Vec<DownloadBuilder>,
+    c: &Cli,
+    cookies: &Option<Arc<CookieJar>>,
+) -> usize {
+    let limits = limits(c);
+    let headers = headers(c);
+    let auth = auth(c, &[]);
+    let results = stream::iter(builders)
+        .map(|builder| {
+            let limits = limits.clone();
+            let builder = configure(builder, c, &headers, cookies, &auth);
+            async move {
+                let url = builder.url().to_owned();
+                let res = async {
+                    let download = builder.build().await?;
+                    engine::run(Arc::new(download), limits).await
+                }
+                .await;
+                (url, res)
+            }
+        })
+        .buffered(c.max_files.unwrap_or(RECURSIVE_MAX_FILES).max(1))
+        .collect::<Vec<_>>()
+        .await;
+    let mut failed = 0;
+    for (url, res) in &results {
+        match res {
+            Ok(path) => println!("{} {}", "✓".green(), path.display()),
+            Err(e) => {
+                failed += 1;
+                println!("{} {}: {}", "x".red(), redact(url), e);
+            }
         }
-        // assert!(written+self.current <= self.total, "Progress overflow?, got more chunks than expected");
-        self.current += written
+    }
+    failed
+}
+
+async fn rank_mirrors(
+    urls: &[String],
+    sample: u64,
+    c: &Cli,
+    cookies: &Option<Arc<CookieJar>>,
+) -> DResult<()> {
+    let auth = auth(c, urls);
+    let sources = Registry::standard(
+        client(c, cookies)?,
+        headers(c),
+        c.retries,
+        http_options(c, cookies, &auth),
+        ftp_options(c),
+        sftp_options(c),
+    );
+    let probes = mirrors::rank(&sources, urls, sample).await;
+    println!("{:>4} {:>9} {:>12}  url", "rank", "ttfb", "speed");
+    for (rank, probe) in probes.iter().enumerate() {
+        match &probe.sample {
+            Ok(sample) => println!(
+                "{:>4} {:>7}ms {:>7.2}MiB/s  {}",
+                rank + 1,
+                sample.ttfb.as_millis(),
+                sample.throughput / (1024.0 * 1024.0),
+                redact(&probe.url)
+            ),
+            Err(e) => println!(
+                "{:>4} {:>9} {:>12}  {}: {}",
+                "-",
+                "-",
+                "-",
+                redact(&probe.url),
+                e.to_string().red()
+            ),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::Method;
+
+    fn authorized(auth: &Option<Arc<Auth>>, url: &str) -> bool {
+        auth.as_ref()
+            .expect("a login was given")
+            .authorization(&Method::GET, &Url::parse(url).unwrap())
+            .is_some()
+    }
+
+    #[test]
+    fn web_seeds_get_no_login() {
+        let c = Cli::parse_from([
+            "donldr",
+            "--bearer",
+            "abc",
+            "--no-netrc",
+            "-u",
+            "https://tracker.example.org/linux.torrent",
+            "--fallback",
+            "https://backup.example.org/linux.torrent",
+        ]);
+        let auth = auth(&c, &[]);
+        assert!(authorized(
+            &auth,
+            "https://tracker.example.org/linux.torrent"
+        ));
+        assert!(authorized(
+            &auth,
+            "https://backup.example.org/linux.torrent"
+        ));
+        // where the .torrent says its file is
+        assert!(!authorized(&auth, "https://seed.example.net/linux.iso"));
+    }
+
+    #[test]
+    fn registry_cdns_get_no_login() {
+        let c = Cli::parse_from([
+            "donldr",
+            "--bearer",
+            "abc",
+            "--no-netrc",
+            "oci",
+            "pull",
+            "registry.example.org/app:1",
+        ]);
+        let auth = auth(&c, &[]);
+        assert!(!authorized(
+            &auth,
+            "https://cdn.example.net/blobs/sha256:00"
+        ));
+    }
+
+    #[test]
+    fn input_file_urls_get_the_login() {
+        let c = Cli::parse_from(["donldr", "--bearer", "abc", "--no-netrc", "-i", "-"]);
+        let auth = auth(&c, &["https://files.example.org/a.iso".to_owned()]);
+        assert!(authorized(&auth, "https://files.example.org/b.iso"));
+        assert!(!authorized(&auth, "https://mirror.example.net/a.iso"));
     }
 }
@@ -0,0 +1,233 @@
+//! Mirrors and hashes a server advertises in response headers, RFC 6249
+//! (Metalink/HTTP), as sent by MirrorBrain and similar mirror networks:
+//!
+//! ```text
+//! Link: <http://ftp.example.de/big.iso>; rel=duplicate; pri=1; geo=de
+//! Link: <http://ftp.example.org/big.iso>; rel=duplicate; pri=2
+//! Digest: SHA-256=MWVlY2NhNzQ1ZTAyNjBkYjJmNDQ4ZmI0MTNlNjVhNWI=
+//! ```
+
+use base64::Engine;
+use reqwest::header::HeaderMap;
+use tracing::debug;
+
+use crate::checksum::{Algorithm, Checksum};
+
+/// A `rel=duplicate` link, another url serving the same file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Duplicate {
+    pub url: String,
+    /// lower is preferred, links without one come after all that have one
+    pub pri: Option<u32>,
+    /// ISO 3166-1 country code of the mirror
+    pub geo: Option<String>,
+}
+
+/// The `rel=duplicate` links of `headers`, by priority. Among links of the
+/// same priority the ones in country `geo` come first.
+pub fn duplicates(headers: &HeaderMap, geo: Option<&str>) -> Vec<Duplicate> {
+    let mut links: Vec<Duplicate> = headers
+        .get_all("link")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(split_links)
+        .filter_map(parse_link)
+        .collect();
+    links.sort_by_key(|link| {
+        (
+            link.pri.unwrap_or(u32::MAX),
+            !geo.is_some_and(|geo| {
+                link.geo
+                    .as_deref()
+                    .is_some_and(|g| g.eq_ignore_ascii_case(geo))
+            }),
+        )
+    });
+    debug!("duplicate links: {:?}", links);
+    links
+}
+
+/// The strongest digest of the `Digest` header we can check, RFC 3230
+pub fn digest(headers: &HeaderMap) -> Option<Checksum> {
+    headers
+        .get_all("digest")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|digest| {
+            let (algorithm, value) = digest.trim().split_once('=')?;
+            // RFC 3230 calls SHA-1 just "SHA"
+            let algorithm = match algorithm.trim() {
+                a if a.eq_ignore_ascii_case("sha") => Algorithm::Sha1,
+                a => a.parse().ok()?,
+            };
+            let digest = base64::engine::general_purpose::STANDARD
+                .decode(value.trim())
+                .ok()?;
+            Some(Checksum::new(algorithm, digest))
+        })
+        .max_by_key(|checksum| strength(checksum.algorithm))
+}
+
+fn strength(algorithm: Algorithm) -> u8 {
+    match algorithm {
+        Algorithm::Md5 => 0,
+        Algorithm::Sha1 => 1,
+        Algorithm::Sha224 => 2,
+        Algorithm::Sha256 => 3,
+        Algorithm::Sha384 => 4,
+        Algorithm::Sha512 => 5,
+    }
+}
+
+/// Splits a header value holding several links on the commas between them,
+/// not the ones inside `<...>` or quotes
+fn split_links(value: &str) -> Vec<&str> {
+    let mut links = vec![];
+    let (mut start, mut in_url, mut in_quotes) = (0, false, false);
+    for (i, c) in value.char_indices() {
+        match c {
+            '<' if !in_quotes => in_url = true,
+            '>' if !in_quotes => in_url = false,
+            '"' if !in_url => in_quotes = !in_quotes,
+            ',' if !in_url && !in_quotes => {
+                links.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    links.push(&value[start..]);
+    links
+}
+
+fn parse_link(link: &str) -> Option<Duplicate> {
+    let link = link.trim();
+    let (url, params) = link.strip_prefix('<')?.split_once('>')?;
+    let mut duplicate = Duplicate {
+        url: url.trim().to_owned(),
+        pri: None,
+        geo: None,
+    };
+    let mut is_duplicate = false;
+    for param in params.split(';') {
+        let Some((name, value)) = param.split_once('=') else {
+            continue;
+        };
+        let value = value.trim().trim_matches('"');
+        match name.trim().to_ascii_lowercase().as_str() {
+            "rel" => {
+                is_duplicate = value
+                    .split_whitespace()
+                    .any(|rel| rel.eq_ignore_ascii_case("duplicate"))
+            }
+            "pri" => duplicate.pri = value.parse().ok(),
+            "geo" => duplicate.geo = Some(value.to_ascii_lowercase()),
+            _ => {}
+        }
+    }
+    is_duplicate.then_some(duplicate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn headers(name: &'static str, values: &[&'static str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn duplicate(url: &str, pri: Option<u32>, geo: Option<&str>) -> Duplicate {
+        Duplicate {
+            url: url.to_owned(),
+            pri,
+            geo: geo.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn links_split_outside_urls_and_quotes() {
+        assert_eq!(
+            split_links(
+                r#"<http://a/x,y>; rel=duplicate, <http://b/>; title="a, b"; rel=describedby"#
+            ),
+            [
+                "<http://a/x,y>; rel=duplicate",
+                r#" <http://b/>; title="a, b"; rel=describedby"#
+            ]
+        );
+    }
+
+    #[test]
+    fn only_duplicate_links() {
+        assert_eq!(
+            parse_link(r#"<http://ftp.example.de/big.iso>; rel="duplicate"; pri=1; geo=DE"#),
+            Some(duplicate(
+                "http://ftp.example.de/big.iso",
+                Some(1),
+                Some("de")
+            ))
+        );
+        assert_eq!(
+            parse_link("<http://example.org/big.iso.meta4>; rel=describedby; type=\"application/metalink4+xml\""),
+            None
+        );
+        assert_eq!(parse_link("http://example.org/; rel=duplicate"), None);
+    }
+
+    #[test]
+    fn duplicates_by_priority_then_country() {
+        let headers = headers(
+            "link",
+            &[
+                "<http://ftp.example.org/big.iso>; rel=duplicate",
+                "<http://ftp.example.de/big.iso>; rel=duplicate; pri=2; geo=de, \
+                 <http://ftp.example.fr/big.iso>; rel=duplicate; pri=2; geo=fr",
+                "<http://ftp.example.nl/big.iso>; rel=duplicate; pri=1",
+            ],
+        );
+        let urls = |geo| {
+            duplicates(&headers, geo)
+                .into_iter()
+                .map(|link| link.url)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            urls(Some("FR")),
+            [
+                "http://ftp.example.nl/big.iso",
+                "http://ftp.example.fr/big.iso",
+                "http://ftp.example.de/big.iso",
+                "http://ftp.example.org/big.iso",
+            ]
+        );
+        assert_eq!(urls(None)[1], "http://ftp.example.de/big.iso");
+    }
+
+    #[test]
+    fn strongest_digest_wins() {
+        let offered = headers(
+            "digest",
+            &[
+                "MD5=HUXZLQLMuI/KZ5KDcJPcOA==, SHA=2jmj7l5rSw0yVb/vlWAYkK/YBwk=",
+                "SHA-256=47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=, UNIXsum=30637",
+            ],
+        );
+        let checksum = digest(&offered).unwrap();
+        assert_eq!(checksum.algorithm, Algorithm::Sha256);
+        assert_eq!(
+            hex::encode(checksum.digest),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+
+        let sha1 = digest(&headers("digest", &["sha=2jmj7l5rSw0yVb/vlWAYkK/YBwk="])).unwrap();
+        assert_eq!(sha1.algorithm, Algorithm::Sha1);
+        assert_eq!(digest(&headers("digest", &["SHA-256=not base64!"])), None);
+        assert_eq!(digest(&HeaderMap::new()), None);
+    }
+}
@@ -0,0 +1,83 @@
+//! Ranking mirrors of the same file by fetching a small range from each:
+//! time to the first byte, then throughput over the rest of the sample.
+//!
+//! Mirrors are probed one after another so they don't compete for the same
+//! link and skew each other's throughput.
+
+use std::time::Duration;
+
+use futures::StreamExt;
+use tokio::time::Instant;
+use tracing::debug;
+
+use crate::{auth::redact, source::Registry, DResult};
+
+/// Bytes fetched from each mirror when ranking
+pub const DEFAULT_SAMPLE: u64 = 256 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    /// from sending the request to the first byte of the body
+    pub ttfb: Duration,
+    pub bytes: u64,
+    /// bytes per second after the first byte
+    pub throughput: f64,
+}
+
+#[derive(Debug)]
+pub struct Probe {
+    pub url: String,
+    pub sample: DResult<Sample>,
+}
+
+/// Fetches the first `sample` bytes of `url` and times them
+pub async fn probe(sources: &Registry, url: &str, sample: u64) -> DResult<Sample> {
+    let start = Instant::now();
+    let mut stream = sources.open_range(url, (0, sample.max(1) - 1)).await?;
+
+    let mut ttfb = None;
+    let mut bytes = 0;
+    // a server ignoring the range sends the whole file, only read the sample
+    while bytes < sample {
+        let Some(chunk) = stream.next().await else {
+            break;
+        };
+        let chunk = chunk?;
+        ttfb.get_or_insert_with(|| start.elapsed());
+        bytes += chunk.len() as u64;
+    }
+    let ttfb = ttfb.ok_or("Mirror sent an empty body")?;
+    let transfer = start
+        .elapsed()
+        .saturating_sub(ttfb)
+        .max(Duration::from_millis(1));
+    let sample = Sample {
+        ttfb,
+        bytes,
+        throughput: bytes as f64 / transfer.as_secs_f64(),
+    };
+    debug!("probed {}: {:?}", redact(url), sample);
+    Ok(sample)
+}
+
+/// Probes every url, fastest first by throughput then time to first byte,
+/// the ones that failed last
+pub async fn rank(sources: &Registry, urls: &[String], sample: u64) -> Vec<Probe> {
+    let mut probes = vec![];
+    for url in urls {
+        probes.push(Probe {
+            url: url.clone(),
+            sample: probe(sources, url, sample).await,
+        });
+    }
+    probes.sort_by(|a, b| match (&a.sample, &b.sample) {
+        (Ok(a), Ok(b)) => b
+            .throughput
+            .total_cmp(&a.throughput)
+            .then(a.ttfb.cmp(&b.ttfb)),
+        (Ok(_), Err(_)) => std::cmp::Ordering::Less,
+        (Err(_), Ok(_)) => std::cmp::Ordering::Greater,
+        (Err(_), Err(_)) => std::cmp::Ordering::Equal,
+    });
+    probes
+}
@@ -0,0 +1,717 @@
+//! Pulling images from OCI / Docker registries: the manifest is resolved,
+//! through a multi-arch index to the platform's one, and every blob it
+//! lists is a regular download checked against its `sha256:` digest. The
+//! pulled image ends up in an OCI image layout directory.
+//!
+//! Registries that want a token answer with a `Bearer` challenge, which
+//! is followed anonymously or with the login `docker login` stored in
+//! `~/.docker/config.json`.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use reqwest::{
+    header::{HeaderValue, ACCEPT, AUTHORIZATION, WWW_AUTHENTICATE},
+    Client, Response, StatusCode, Url,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{debug, info, warn};
+
+use crate::{
+    auth::{challenge_params, sensitive},
+    checksum::{Algorithm, Checksum},
+    download::{send_with_retry, DownloadBuilder},
+    DResult, Errors,
+};
+
+const DOCKER_HUB: &str = "registry-1.docker.io";
+
+/// Manifests and indexes, OCI and Docker flavored
+const MANIFEST_TYPES: [&str; 4] = [
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+    "application/vnd.oci.image.manifest.v1+json",
+    "application/vnd.docker.distribution.manifest.v2+json",
+];
+
+/// Annotation naming a manifest in a layout's `index.json`
+const REF_NAME: &str = "org.opencontainers.image.ref.name";
+
+/// `registry/repository:tag` or `registry/repository@sha256:...`, Docker
+/// Hub when there's no registry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reference {
+    pub registry: String,
+    pub repository: String,
+    /// a tag or a digest
+    pub reference: String,
+}
+
+impl FromStr for Reference {
+    type Err = Errors;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, reference) = match s.split_once('@') {
+            // `name:tag@digest` is pinned to the digest
+            Some((name, digest)) => (untagged(name).0, digest.to_owned()),
+            None => match untagged(s) {
+                (name, Some(tag)) => (name, tag.to_owned()),
+                (name, None) => (name, "latest".to_owned()),
+            },
+        };
+        let (registry, repository) = match name.split_once('/') {
+            Some(("docker.io" | "index.docker.io", rest)) => (DOCKER_HUB, rest),
+            Some((first, rest)) if first.contains(['.', ':']) || first == "localhost" => {
+                (first, rest)
+            }
+            _ => (DOCKER_HUB, name),
+        };
+        if repository.is_empty() || reference.is_empty() {
+            return Err(Errors::Custom(format!("Invalid image reference {}", s)));
+        }
+        // official images live under `library/` on Docker Hub
+        let repository = match registry == DOCKER_HUB && !repository.contains('/') {
+            true => format!("library/{}", repository),
+            false => repository.to_owned(),
+        };
+        let registry = registry.to_owned();
+        Ok(Reference {
+            registry,
+            repository,
+            reference,
+        })
+    }
+}
+
+/// `name` without its `:tag`, and the tag. A colon after the last slash is
+/// a tag, before it a registry's port.
+fn untagged(name: &str) -> (&str, Option<&str>) {
+    match name.rsplit_once(':') {
+        Some((untagged, tag)) if !tag.contains('/') => (untagged, Some(tag)),
+        _ => (name, None),
+    }
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let separator = if self.reference.contains(':') {
+            '@'
+        } else {
+            ':'
+        };
+        write!(
+            f,
+            "{}/{}{}{}",
+            self.registry, self.repository, separator, self.reference
+        )
+    }
+}
+
+/// `os/architecture[/variant]`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Platform {
+    pub os: String,
+    pub architecture: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+}
+
+impl Platform {
+    /// Linux on this machine's architecture, in the Go naming registries use
+    pub fn current() -> Self {
+        let architecture = match std::env::consts::ARCH {
+            "x86_64" => "amd64",
+            "aarch64" => "arm64",
+            "x86" => "386",
+            "powerpc64" => "ppc64le",
+            arch => arch,
+        };
+        Platform {
+            os: "linux".to_owned(),
+            architecture: architecture.to_owned(),
+            variant: None,
+        }
+    }
+
+    /// Whether an index entry for `other` runs here, any variant if none
+    /// was asked for
+    fn matches(&self, other: &Platform) -> bool {
+        self.os == other.os
+            && self.architecture == other.architecture
+            && (self.variant.is_none() || self.variant == other.variant)
+    }
+}
+
+impl FromStr for Platform {
+    type Err = Errors;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split('/');
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(os), Some(arch), variant, None) if !os.is_empty() && !arch.is_empty() => {
+                Ok(Platform {
+                    os: os.to_owned(),
+                    architecture: arch.to_owned(),
+                    variant: variant.map(str::to_owned),
+                })
+            }
+            _ => Err(Errors::Custom(format!(
+                "Platform isn't os/arch[/variant]: {}",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "/{}", variant)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Descriptor {
+    #[serde(default)]
+    pub media_type: String,
+    pub digest: String,
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<Platform>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub annotations: HashMap<String, String>,
+}
+
+impl Descriptor {
+    /// The hex of a `sha256:` digest, the only kind pulled
+    fn sha256(&self) -> DResult<&str> {
+        self.digest
+            .strip_prefix("sha256:")
+            .filter(|hex| hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_hexdigit()))
+            .ok_or_else(|| Errors::Custom(format!("Unsupported digest {}", self.digest)))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    config: Descriptor,
+    #[serde(default)]
+    layers: Vec<Descriptor>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Index {
+    manifests: Vec<Descriptor>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LayoutIndex {
+    schema_version: u32,
+    manifests: Vec<Descriptor>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// picked from multi-arch indexes
+    pub platform: Platform,
+    /// talk to the registry over http instead of https
+    pub plain_http: bool,
+    pub retries: usize,
+}
+
+/// A resolved image: its manifest is in the layout, its blobs still have
+/// to be downloaded
+#[derive(Debug)]
+pub struct Pull {
+    pub reference: Reference,
+    /// the OCI image layout directory
+    pub layout: PathBuf,
+    pub manifest: Descriptor,
+    /// config first, then the layers
+    pub blobs: Vec<Descriptor>,
+    registry: Url,
+    authorization: Option<HeaderValue>,
+}
+
+impl Pull {
+    /// Fetches the manifest of `reference`, going through an index to the
+    /// `options.platform` one, and stores it in a layout directory named
+    /// after the repository under `path`
+    pub async fn resolve(
+        client: &Client,
+        reference: Reference,
+        path: &Path,
+        options: &Options,
+    ) -> DResult<Pull> {
+        let scheme = if options.plain_http { "http" } else { "https" };
+        let registry =
+            Url::parse(&format!("{}://{}/", scheme, reference.registry)).map_err(|e| {
+                Errors::Custom(format!("Invalid registry {}: {}", reference.registry, e))
+            })?;
+        let mut authorization = None;
+
+        let (mut manifest, mut bytes) = fetch_manifest(
+            client,
+            &registry,
+            &reference,
+            &reference.reference,
+            &mut authorization,
+            options.retries,
+        )
+        .await?;
+        if let Ok(index) = serde_json::from_slice::<Index>(&bytes) {
+            let platforms = index
+                .manifests
+                .iter()
+                .filter_map(|m| m.platform.as_ref().map(|p| p.to_string()))
+                .collect::<Vec<_>>();
+            let picked = index
+                .manifests
+                .into_iter()
+                .find(|m| {
+                    m.platform
+                        .as_ref()
+                        .is_some_and(|p| options.platform.matches(p))
+                })
+                .ok_or_else(|| {
+                    Errors::Custom(format!(
+                        "{} has no {} image, only {}",
+                        reference,
+                        options.platform,
+                        platforms.join(", ")
+                    ))
+                })?;
+            info!("picked the {} manifest {}", options.platform, picked.digest);
+            let (platform_manifest, platform_bytes) = fetch_manifest(
+                client,
+                &registry,
+                &reference,
+                &picked.digest,
+                &mut authorization,
+                options.retries,
+            )
+            .await?;
+            manifest = Descriptor {
+                platform: picked.platform,
+                ..platform_manifest
+            };
+            bytes = platform_bytes;
+        }
+        let image: Manifest = serde_json::from_slice(&bytes).map_err(|e| {
+            Errors::Custom(format!("Unreadable image manifest of {}: {}", reference, e))
+        })?;
+
+        let name = reference
+            .repository
+            .rsplit('/')
+            .next()
+            .unwrap_or(&reference.repository);
+        let layout = path.join(name);
+        let manifest_path = blob_path(&layout, &manifest)?;
+        tokio::fs::create_dir_all(manifest_path.parent().unwrap_or(&layout)).await?;
+        tokio::fs::write(&manifest_path, &bytes).await?;
+
+        let blobs: Vec<Descriptor> = std::iter::once(image.config).chain(image.layers).collect();
+        for blob in &blobs {
+            blob.sha256()?;
+        }
+        Ok(Pull {
+            reference,
+            layout,
+            manifest,
+            blobs,
+            registry,
+            authorization,
+        })
+    }
+
+    /// A download for every blob that isn't in the layout yet, checked
+    /// against its digest
+    pub async fn builders(&self, client: &Client, retries: usize) -> DResult<Vec<DownloadBuilder>> {
+        let mut builders = vec![];
+        for blob in &self.blobs {
+            let path = blob_path(&self.layout, blob)?;
+            // blobs are named by their digest, the right size is enough
+            if tokio::fs::metadata(&path)
+                .await
+                .is_ok_and(|m| m.len() == blob.size)
+            {
+                debug!("{} is already in the layout", blob.digest);
+                continue;
+            }
+            let checksum = Checksum::new(
+                Algorithm::Sha256,
+                hex::decode(blob.sha256()?).map_err(|e| Errors::Custom(e.to_string()))?,
+            );
+            let url = self.blob_url(&blob.digest)?;
+            let builder = match self.storage_url(client, &url, retries).await? {
+                Some(storage) => DownloadBuilder::new(storage),
+                None => {
+                    let builder = DownloadBuilder::new(url.as_str());
+                    match &self.authorization {
+                        Some(value) => builder.header(AUTHORIZATION, value.clone()),
+                        None => builder,
+                    }
+                }
+            };
+            builders.push(
+                builder
+                    .path(path.to_string_lossy())
+                    .checksum(checksum)
+                    .metalink(false),
+            );
+        }
+        Ok(builders)
+    }
+
+    /// Writes `oci-layout` and adds the manifest to the layout's
+    /// `index.json`, replacing an older one with the same tag
+    pub async fn finish(&self) -> DResult<()> {
+        tokio::fs::write(
+            self.layout.join("oci-layout"),
+            r#"{"imageLayoutVersion":"1.0.0"}"#,
+        )
+        .await?;
+        let index_path = self.layout.join("index.json");
+        let mut index = match tokio::fs::read(&index_path).await {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(_) => LayoutIndex {
+                schema_version: 2,
+                manifests: vec![],
+            },
+        };
+        let mut manifest = self.manifest.clone();
+        if !self.reference.reference.contains(':') {
+            manifest
+                .annotations
+                .insert(REF_NAME.to_owned(), self.reference.reference.clone());
+        }
+        index.manifests.retain(|m| {
+            m.digest != manifest.digest
+                && (!m.annotations.contains_key(REF_NAME)
+                    || m.annotations.get(REF_NAME) != manifest.annotations.get(REF_NAME))
+        });
+        index.manifests.push(manifest);
+        tokio::fs::write(&index_path, serde_json::to_vec_pretty(&index)?).await?;
+        Ok(())
+    }
+
+    fn blob_url(&self, digest: &str) -> DResult<Url> {
+        self.registry
+            .join(&format!(
+                "v2/{}/blobs/{}",
+                self.reference.repository, digest
+            ))
+            .map_err(|e| Errors::Custom(format!("Invalid blob url: {}", e)))
+    }
+
+    /// Where the registry redirects the blob to when that's another host,
+    /// like a CDN. Ranges go straight there instead of through the
+    /// registry, whose token may run out during a long download.
+    async fn storage_url(
+        &self,
+        client: &Client,
+        url: &Url,
+        retries: usize,
+    ) -> DResult<Option<String>> {
+        let res = send_with_retry(
+            || {
+                let request = client.head(url.clone());
+                match &self.authorization {
+                    Some(value) => request.header(AUTHORIZATION, value.clone()),
+                    None => request,
+                }
+            },
+            retries,
+        )
+        .await?;
+        Ok(
+            (res.url().host_str() != url.host_str() || res.url().port() != url.port())
+                .then(|| res.url().to_string()),
+        )
+    }
+}
+
+fn blob_path(layout: &Path, descriptor: &Descriptor) -> DResult<PathBuf> {
+    Ok(layout
+        .join("blobs")
+        .join("sha256")
+        .join(descriptor.sha256()?))
+}
+
+/// The manifest at `reference` (a tag or digest) and its raw bytes,
+/// authorizing first if the registry asks for it
+async fn fetch_manifest(
+    client: &Client,
+    registry: &Url,
+    image: &Reference,
+    reference: &str,
+    authorization: &mut Option<HeaderValue>,
+    retries: usize,
+) -> DResult<(Descriptor, Vec<u8>)> {
+    let url = registry
+        .join(&format!("v2/{}/manifests/{}", image.repository, reference))
+        .map_err(|e| Errors::Custom(format!("Invalid manifest url: {}", e)))?;
+    let request = |authorization: Option<&HeaderValue>| {
+        let request = client
+            .get(url.clone())
+            .header(ACCEPT, MANIFEST_TYPES.join(", "));
+        match authorization {
+            Some(value) => request.header(AUTHORIZATION, value.clone()),
+            None => request,
+        }
+    };
+    let first = request(authorization.as_ref()).send().await?;
+    let res = if first.status().is_success() {
+        first
+    } else {
+        if first.status() == StatusCode::UNAUTHORIZED && authorization.is_none() {
+            *authorization = Some(authorize(client, &first, image, retries).await?);
+        }
+        send_with_retry(|| request(authorization.as_ref()), retries).await?
+    };
+
+    let media_type = res
+        .headers()
+        .get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_owned();
+    let bytes = res.bytes().await?.to_vec();
+    let digest = format!("sha256:{}", hex::encode(Sha256::digest(&bytes)));
+    if reference.starts_with("sha256:") && reference != digest {
+        return Err(Errors::Custom(format!(
+            "Manifest {} of {} hashes to {}",
+            reference, image, digest
+        )));
+    }
+    let media_type = match serde_json::from_slice::<serde_json::Value>(&bytes)?
+        .get("mediaType")
+        .and_then(|v| v.as_str())
+    {
+        Some(declared) => declared.to_owned(),
+        None => media_type,
+    };
+    debug!("manifest {} of {}: {}", digest, image, media_type);
+    Ok((
+        Descriptor {
+            media_type,
+            digest,
+            size: bytes.len() as u64,
+            platform: None,
+            annotations: HashMap::new(),
+        },
+        bytes,
+    ))
+}
+
+/// The `Authorization` a `401` response's challenge asks for: a token
+/// from the registry's auth server, or the login itself for `Basic`
+async fn authorize(
+    client: &Client,
+    unauthorized: &Response,
+    image: &Reference,
+    retries: usize,
+) -> DResult<HeaderValue> {
+    let challenge = unauthorized
+        .headers()
+        .get(WWW_AUTHENTICATE)
+        .and_then(|v| v.to_str().ok())
+        .ok_or_else(|| {
+            Errors::Custom(format!(
+                "{} refused access without a challenge",
+                image.registry
+            ))
+        })?;
+    debug!("challenge from {}: {}", image.registry, challenge);
+    let login = docker_login(&image.registry);
+    let (scheme, params) = challenge.split_once(' ').unwrap_or((challenge, ""));
+    if scheme.eq_ignore_ascii_case("basic") {
+        let login = login.ok_or_else(|| {
+            Errors::Custom(format!(
+                "{} wants a login, run docker login first",
+                image.registry
+            ))
+        })?;
+        return sensitive(&format!("Basic {}", login));
+    }
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return Err(Errors::Custom(format!(
+            "Unsupported auth scheme {} from {}",
+            scheme, image.registry
+        )));
+    }
+
+    let params = challenge_params(params);
+    let realm = params
+        .get("realm")
+        .ok_or_else(|| Errors::Custom("Bearer challenge without a realm".to_owned()))?;
+    let mut url = Url::parse(realm)
+        .map_err(|e| Errors::Custom(format!("Invalid token realm {}: {}", realm, e)))?;
+    {
+        let mut query = url.query_pairs_mut();
+        if let Some(service) = params.get("service") {
+            query.append_pair("service", service);
+        }
+        let scope = params
+            .get("scope")
+            .cloned()
+            .unwrap_or_else(|| format!("repository:{}:pull", image.repository));
+        query.append_pair("scope", &scope);
+    }
+    debug!("getting a token from {}", url);
+    let res = send_with_retry(
+        || {
+            let request = client.get(url.clone());
+            match &login {
+                Some(login) => request.header(AUTHORIZATION, format!("Basic {}", login)),
+                None => request,
+            }
+        },
+        retries,
+    )
+    .await?;
+    let body: serde_json::Value = serde_json::from_slice(&res.bytes().await?)?;
+    let token = body
+        .get("token")
+        .or_else(|| body.get("access_token"))
+        .and_then(|t| t.as_str())
+        .ok_or_else(|| Errors::Custom(format!("No token from {}", realm)))?;
+    sensitive(&format!("Bearer {}", token))
+}
+
+/// The base64 `user:password` `docker login` stored for `registry`
+fn docker_login(registry: &str) -> Option<String> {
+    let dir = std::env::var_os("DOCKER_CONFIG")
+        .map(PathBuf::from)
+        .or_else(|| std::env::home_dir().map(|home| home.join(".docker")))?;
+    let config: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(dir.join("config.json")).ok()?).ok()?;
+    let auths = config.get("auths")?.as_object()?;
+    // Docker Hub logins are kept under the old index url
+    let keys = if registry == DOCKER_HUB {
+        vec![
+            "https://index.docker.io/v1/".to_owned(),
+            "docker.io".to_owned(),
+        ]
+    } else {
+        vec![registry.to_owned(), format!("https://{}", registry)]
+    };
+    let login = keys
+        .iter()
+        .find_map(|key| auths.get(key)?.get("auth")?.as_str())?;
+    match STANDARD.decode(login) {
+        Ok(decoded) if decoded.contains(&b':') => Some(login.to_owned()),
+        _ => {
+            warn!("Ignoring unreadable docker login for {}", registry);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reference(s: &str) -> (String, String, String) {
+        let r: Reference = s.parse().unwrap();
+        (r.registry, r.repository, r.reference)
+    }
+
+    fn expect(registry: &str, repository: &str, reference: &str) -> (String, String, String) {
+        (registry.into(), repository.into(), reference.into())
+    }
+
+    const DIGEST: &str = "sha256:4b9a8c1d2e3f405162738495a6b7c8d9e0f1a2b3c4d5e6f708192a3b4c5d6e7f";
+
+    #[test]
+    fn docker_hub_is_the_default() {
+        assert_eq!(
+            reference("ubuntu"),
+            expect(DOCKER_HUB, "library/ubuntu", "latest")
+        );
+        assert_eq!(
+            reference("ubuntu:22.04"),
+            expect(DOCKER_HUB, "library/ubuntu", "22.04")
+        );
+        assert_eq!(
+            reference("grafana/grafana:10.2.0"),
+            expect(DOCKER_HUB, "grafana/grafana", "10.2.0")
+        );
+        assert_eq!(
+            reference("docker.io/ubuntu"),
+            expect(DOCKER_HUB, "library/ubuntu", "latest")
+        );
+        assert_eq!(
+            reference("index.docker.io/grafana/grafana"),
+            expect(DOCKER_HUB, "grafana/grafana", "latest")
+        );
+    }
+
+    #[test]
+    fn registries() {
+        assert_eq!(
+            reference("ghcr.io/owner/app:v1"),
+            expect("ghcr.io", "owner/app", "v1")
+        );
+        assert_eq!(
+            reference("localhost/app"),
+            expect("localhost", "app", "latest")
+        );
+        // a port isn't a tag
+        assert_eq!(
+            reference("registry.example:5000/team/app"),
+            expect("registry.example:5000", "team/app", "latest")
+        );
+        assert_eq!(
+            reference("localhost:5000/app:dev"),
+            expect("localhost:5000", "app", "dev")
+        );
+    }
+
+    #[test]
+    fn digests() {
+        assert_eq!(
+            reference(&format!("ghcr.io/owner/app@{}", DIGEST)),
+            expect("ghcr.io", "owner/app", DIGEST)
+        );
+        // the digest wins over a tag
+        assert_eq!(
+            reference(&format!("ubuntu:22.04@{}", DIGEST)),
+            expect(DOCKER_HUB, "library/ubuntu", DIGEST)
+        );
+        assert_eq!(
+            reference(&format!("registry.example:5000/app@{}", DIGEST)),
+            expect("registry.example:5000", "app", DIGEST)
+        );
+    }
+
+    #[test]
+    fn invalid_references() {
+        for invalid in ["", "ubuntu:", "ubuntu@", "ghcr.io/", "docker.io/"] {
+            assert!(invalid.parse::<Reference>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for s in [
+            "ghcr.io/owner/app:v1",
+            "registry.example:5000/team/app:latest",
+            &format!("{}/library/ubuntu@{}", DOCKER_HUB, DIGEST),
+        ] {
+            assert_eq!(s.parse::<Reference>().unwrap().to_string(), s);
+        }
+    }
+}
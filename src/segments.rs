@@ -0,0 +1,230 @@
+//! Files that come as many small segments joined in order, the way HLS and
+//! DASH streams do. Segments are fetched in parallel, each holding a
+//! connection from the shared `Limits`, and wait in a `.segments`
+//! directory next to the file until the last one is in, so an interrupted
+//! download only fetches the segments it's missing.
+
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use aes::Aes128;
+use bytes::Bytes;
+use cbc::cipher::{block_padding::Pkcs7, BlockDecryptMut, KeyIvInit};
+use reqwest::{header::HeaderMap, Client, Method, StatusCode, Url};
+use tokio::{io::AsyncWriteExt, task::JoinSet};
+use tracing::{debug, info, warn};
+
+use crate::{
+    auth::{redacted, Auth},
+    download::{host_of, part_file_path},
+    limits::Limits,
+    source::send_authorized,
+    DResult, Errors,
+};
+
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub url: Url,
+    /// inclusive, for segments that are a part of a bigger file
+    pub range: Option<(u64, u64)>,
+    /// AES-128-CBC key and IV the segment is encrypted with
+    pub aes128: Option<([u8; 16], [u8; 16])>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// segments downloaded at once
+    pub connections: usize,
+    pub retries: usize,
+    /// logins for the segments' hosts
+    pub auth: Option<Arc<Auth>>,
+}
+
+/// Fetches `segments`, `options.connections` of them at once, and joins
+/// them in order into `file_path`
+pub async fn download(
+    client: &Client,
+    segments: Vec<Segment>,
+    file_path: &Path,
+    headers: &HeaderMap,
+    options: &Options,
+    limits: Arc<Limits>,
+) -> DResult<()> {
+    let (connections, retries) = (options.connections, options.retries);
+    if let Some(dir) = file_path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let dir = segments_dir(file_path);
+    prepare_segments_dir(&dir, &segments).await?;
+
+    let total = segments.len();
+    let mut missing: VecDeque<usize> = (0..total)
+        .filter(|idx| !segment_path(&dir, *idx).is_file())
+        .collect();
+    if missing.len() < total {
+        info!(
+            "resuming {:?}: {} of {} segments on disk",
+            file_path,
+            total - missing.len(),
+            total
+        );
+    }
+
+    let segments = Arc::new(segments);
+    let mut tasks: JoinSet<DResult<usize>> = JoinSet::new();
+    let mut done = total - missing.len();
+    loop {
+        while tasks.len() < connections.max(1) {
+            let Some(idx) = missing.pop_front() else {
+                break;
+            };
+            let (client, headers, limits) = (client.clone(), headers.clone(), limits.clone());
+            let (segments, dir, auth) = (segments.clone(), dir.clone(), options.auth.clone());
+            tasks.spawn(async move {
+                let segment = &segments[idx];
+                let bytes = fetch(
+                    &client,
+                    auth.as_deref(),
+                    segment,
+                    &headers,
+                    &limits,
+                    retries,
+                )
+                .await?;
+                let bytes = match segment.aes128 {
+                    Some((key, iv)) => decrypt(bytes, &key, &iv).map_err(|e| {
+                        Errors::Custom(format!(
+                            "Segment {} of {}: {}",
+                            idx,
+                            redacted(&segment.url),
+                            e
+                        ))
+                    })?,
+                    None => bytes.to_vec(),
+                };
+                // written aside first, a segment file is always a whole one
+                let path = segment_path(&dir, idx);
+                let tmp = path.with_extension("tmp");
+                tokio::fs::write(&tmp, bytes).await?;
+                tokio::fs::rename(&tmp, &path).await?;
+                Ok(idx)
+            });
+        }
+        match tasks.join_next().await {
+            None => break,
+            Some(Ok(Ok(idx))) => {
+                done += 1;
+                debug!("segment {} done", idx);
+                info!("{:?}: {}/{} segments", file_path, done, total);
+            }
+            // dropping the set stops the others, finished segments stay for a resume
+            Some(Ok(Err(e))) => return Err(e),
+            Some(Err(e)) => return Err(Errors::Custom(format!("{:?}", e))),
+        }
+    }
+
+    let part_path = part_file_path(file_path);
+    let mut file = tokio::fs::File::create(&part_path).await?;
+    for idx in 0..total {
+        let mut segment = tokio::fs::File::open(segment_path(&dir, idx)).await?;
+        tokio::io::copy(&mut segment, &mut file).await?;
+    }
+    file.flush().await?;
+    drop(file);
+    tokio::fs::rename(&part_path, file_path).await?;
+    tokio::fs::remove_dir_all(&dir).await?;
+    debug!("joined {} segments into {:?}", total, file_path);
+    Ok(())
+}
+
+pub(crate) async fn fetch(
+    client: &Client,
+    auth: Option<&Auth>,
+    segment: &Segment,
+    headers: &HeaderMap,
+    limits: &Limits,
+    retries: usize,
+) -> DResult<Bytes> {
+    let _permit = limits.acquire(&host_of(segment.url.as_str())).await?;
+    let res = send_authorized(
+        auth,
+        &Method::GET,
+        segment.url.as_str(),
+        || {
+            let request = client.get(segment.url.clone()).headers(headers.clone());
+            match segment.range {
+                Some((from, to)) => request.header("Range", format!("bytes={}-{}", from, to)),
+                None => request,
+            }
+        },
+        retries,
+    )
+    .await?;
+    let partial = res.status() == StatusCode::PARTIAL_CONTENT;
+    let bytes = res.bytes().await?;
+    match segment.range {
+        // the server ignored the range and sent the whole file
+        Some((from, to)) if !partial => bytes
+            .get(from as usize..=to as usize)
+            .map(|range| bytes.slice_ref(range))
+            .ok_or_else(|| {
+                Errors::Custom(format!(
+                    "{} is {} bytes, too short for range {}-{}",
+                    redacted(&segment.url),
+                    bytes.len(),
+                    from,
+                    to
+                ))
+            }),
+        _ => Ok(bytes),
+    }
+}
+
+/// AES-128-CBC with PKCS7 padding
+fn decrypt(bytes: Bytes, key: &[u8; 16], iv: &[u8; 16]) -> DResult<Vec<u8>> {
+    let mut buf = bytes.to_vec();
+    let len = cbc::Decryptor::<Aes128>::new(key.into(), iv.into())
+        .decrypt_padded_mut::<Pkcs7>(&mut buf)
+        .map_err(|_| Errors::Custom("Decryption failed, wrong key or IV".to_owned()))?
+        .len();
+    buf.truncate(len);
+    Ok(buf)
+}
+
+fn segments_dir(file_path: &Path) -> PathBuf {
+    let mut dir = file_path.as_os_str().to_owned();
+    dir.push(".segments");
+    PathBuf::from(dir)
+}
+
+fn segment_path(dir: &Path, idx: usize) -> PathBuf {
+    dir.join(format!("{:06}", idx))
+}
+
+/// Creates the segments directory, emptying one left by a run over
+/// different segments
+async fn prepare_segments_dir(dir: &Path, segments: &[Segment]) -> DResult<()> {
+    let listing = segments
+        .iter()
+        // without passwords, the listing stays on disk
+        .map(|s| match s.range {
+            Some((from, to)) => format!("{} {}-{}\n", redacted(&s.url), from, to),
+            None => format!("{}\n", redacted(&s.url)),
+        })
+        .collect::<String>();
+    let listing_path = dir.join("segments");
+    match tokio::fs::read_to_string(&listing_path).await {
+        Ok(previous) if previous == listing => return Ok(()),
+        Ok(_) => {
+            warn!("Segments changed since the last run, starting over");
+            tokio::fs::remove_dir_all(dir).await?;
+        }
+        Err(_) => {}
+    }
+    tokio::fs::create_dir_all(dir).await?;
+    tokio::fs::write(&listing_path, listing).await?;
+    Ok(())
+}
@@ -0,0 +1,296 @@
+//! `sftp://user@host/path` downloads over SSH. Every piece opens its own
+//! SSH connection and SFTP channel, then reads its range from an offset, so
+//! pieces run in parallel like HTTP ranges.
+//!
+//! Logging in tries, in order, the password in the url, the keys of the
+//! running ssh-agent and the given key files (`~/.ssh/id_*` without any).
+//! Host keys are checked against `~/.ssh/known_hosts`.
+
+use std::{
+    path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use futures::{future::BoxFuture, FutureExt, StreamExt, TryStreamExt};
+use percent_encoding::percent_decode_str;
+use reqwest::Url;
+use russh::{
+    client::{self, Handle},
+    keys::{
+        agent::{client::AgentClient, AgentIdentity},
+        known_hosts, PrivateKeyWithHashAlg, PublicKeyOrCertificate,
+    },
+};
+use russh_sftp::client::{error::Error as SftpError, fs::File, SftpSession};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, ReadBuf};
+use tokio_util::io::ReaderStream;
+use tracing::{debug, warn};
+
+use crate::{
+    auth::redacted,
+    download::format_http_date,
+    source::{ByteStream, Meta, Source},
+    DResult, Errors,
+};
+
+/// Key files looked for in `~/.ssh` when none are given, like ssh does
+const DEFAULT_IDENTITIES: [&str; 3] = ["id_ed25519", "id_ecdsa", "id_rsa"];
+
+/// How to log in to SSH servers
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// private keys tried after the agent's
+    pub identities: Vec<PathBuf>,
+    /// connect to hosts missing from known_hosts, adding their key to it
+    pub accept_new_hosts: bool,
+}
+
+/// Checks the server's host key against known_hosts
+struct Client {
+    host: String,
+    port: u16,
+    accept_new_hosts: bool,
+}
+
+impl client::Handler for Client {
+    type Error = Errors;
+
+    async fn check_server_key(&mut self, key: &PublicKeyOrCertificate) -> DResult<bool> {
+        let PublicKeyOrCertificate::PublicKey { key, .. } = key else {
+            return Err(Errors::Custom(format!(
+                "{} offered a host certificate, only plain host keys are supported",
+                self.host
+            )));
+        };
+        match known_hosts::check_known_hosts(&self.host, self.port, key) {
+            Ok(true) => Ok(true),
+            Ok(false) if self.accept_new_hosts => {
+                warn!("Adding the host key of {} to known_hosts", self.host);
+                known_hosts::learn_known_hosts(&self.host, self.port, key)
+                    .map_err(|e| Errors::Custom(format!("Failed adding host key: {}", e)))?;
+                Ok(true)
+            }
+            Ok(false) => Err(Errors::Custom(format!(
+                "{} isn't in known_hosts, connect with ssh once or accept new hosts",
+                self.host
+            ))),
+            Err(e) => Err(Errors::Custom(format!(
+                "Host key of {} doesn't match known_hosts: {}",
+                self.host, e
+            ))),
+        }
+    }
+}
+
+/// A logged in SSH connection with an SFTP channel, and the path of the
+/// file on the server
+async fn connect(url: &Url, options: &Options) -> DResult<(Handle<Client>, SftpSession, String)> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| Errors::Custom(format!("No host in {}", url)))?;
+    let port = url.port().unwrap_or(22);
+    let handler = Client {
+        host: host.to_owned(),
+        port,
+        accept_new_hosts: options.accept_new_hosts,
+    };
+    let config = client::Config {
+        inactivity_timeout: Some(Duration::from_secs(60)),
+        ..Default::default()
+    };
+    let mut ssh = client::connect(Arc::new(config), (host, port), handler).await?;
+
+    let decode = |s: &str| percent_decode_str(s).decode_utf8_lossy().into_owned();
+    let user = match url.username() {
+        "" => std::env::var("USER").unwrap_or_else(|_| "root".to_owned()),
+        user => decode(user),
+    };
+    if !authenticate(&mut ssh, &user, url.password().map(decode), options).await? {
+        return Err(Errors::Custom(format!(
+            "No way to log in to {} as {}, tried the password, agent and key files",
+            host, user
+        )));
+    }
+
+    let channel = ssh.channel_open_session().await?;
+    channel.request_subsystem(true, "sftp").await?;
+    let sftp = SftpSession::new(channel.into_stream()).await?;
+    Ok((ssh, sftp, decode(url.path())))
+}
+
+async fn authenticate(
+    ssh: &mut Handle<Client>,
+    user: &str,
+    password: Option<String>,
+    options: &Options,
+) -> DResult<bool> {
+    if let Some(password) = password {
+        return Ok(ssh.authenticate_password(user, password).await?.success());
+    }
+    let hash_alg = ssh.best_supported_rsa_hash().await?.flatten();
+
+    match AgentClient::connect_env().await {
+        Ok(mut agent) => {
+            let identities = agent.request_identities().await.unwrap_or_default();
+            for identity in identities {
+                let AgentIdentity::PublicKey { key, comment } = identity else {
+                    continue;
+                };
+                debug!("trying agent key {}", comment);
+                let auth = ssh
+                    .authenticate_publickey_with(user, key, hash_alg, &mut agent)
+                    .await
+                    .map_err(|e| Errors::Custom(format!("ssh-agent failed: {}", e)))?;
+                if auth.success() {
+                    return Ok(true);
+                }
+            }
+        }
+        Err(e) => debug!("no ssh-agent: {}", e),
+    }
+
+    let identities = if options.identities.is_empty() {
+        let Some(ssh_dir) = std::env::home_dir().map(|home| home.join(".ssh")) else {
+            return Ok(false);
+        };
+        DEFAULT_IDENTITIES
+            .iter()
+            .map(|name| ssh_dir.join(name))
+            .filter(|path| path.is_file())
+            .collect()
+    } else {
+        options.identities.clone()
+    };
+    for path in identities {
+        debug!("trying key file {:?}", path);
+        let key = match russh::keys::load_secret_key(&path, None) {
+            Ok(key) => key,
+            Err(e) => {
+                warn!("Skipping key {:?}: {}", path, e);
+                continue;
+            }
+        };
+        let key = PrivateKeyWithHashAlg::new(Arc::new(key), hash_alg);
+        if ssh.authenticate_publickey(user, key).await?.success() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Runs `f` again when the connection fails. Missing files and refused
+/// logins fail right away.
+async fn with_retry<T, F, Fut>(retries: usize, f: F) -> DResult<T>
+where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = DResult<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        match f().await {
+            Ok(value) => return Ok(value),
+            Err(e @ (Errors::Sftp(SftpError::Status(_)) | Errors::Custom(_))) => return Err(e),
+            Err(e) if attempt >= retries => return Err(e),
+            Err(e) => {
+                attempt += 1;
+                debug!("SFTP failed with {}, retrying [{}/{}]", e, attempt, retries);
+                tokio::time::sleep(Duration::from_millis(250 * attempt as u64)).await;
+            }
+        }
+    }
+}
+
+/// [`Source`] for `sftp://` urls
+#[derive(Debug, Clone)]
+pub struct Sftp {
+    options: Options,
+    retries: usize,
+}
+
+impl Sftp {
+    pub fn new(options: Options, retries: usize) -> Self {
+        Sftp { options, retries }
+    }
+
+    /// Size and modification time of the file
+    pub async fn probe(&self, url: &str) -> DResult<Meta> {
+        let url = Url::parse(url)
+            .map_err(|e| Errors::Custom(format!("Failed parsing url {}: {}", url, e)))?;
+        with_retry(self.retries, || async {
+            let (_ssh, sftp, path) = connect(&url, &self.options).await?;
+            let metadata = sftp.metadata(&path).await?;
+            let _ = sftp.close().await;
+            let size = metadata.size.ok_or_else(|| {
+                Errors::Custom(format!("{} didn't send the size of {}", url, path))
+            })?;
+            debug!(
+                "{}: {} bytes, mtime {:?}",
+                redacted(&url),
+                size,
+                metadata.mtime
+            );
+
+            let modified = metadata
+                .mtime
+                .map(|mtime| UNIX_EPOCH + Duration::from_secs(mtime.into()));
+            Ok(Meta {
+                url: url.to_string(),
+                size,
+                accept_ranges: Some(true),
+                validator: modified.map(format_http_date),
+                last_modified: modified,
+                headers: Default::default(),
+            })
+        })
+        .await
+    }
+
+    /// The inclusive byte range `from..=to` of the file
+    pub async fn open_range(&self, url: &str, (from, to): (u64, u64)) -> DResult<ByteStream> {
+        let url = Url::parse(url)
+            .map_err(|e| Errors::Custom(format!("Failed parsing url {}: {}", url, e)))?;
+        let transfer = with_retry(self.retries, || async {
+            let (ssh, sftp, path) = connect(&url, &self.options).await?;
+            let mut file = sftp.open(&path).await?;
+            file.seek(std::io::SeekFrom::Start(from)).await?;
+            Ok(Transfer { file, _ssh: ssh })
+        })
+        .await?;
+        Ok(ReaderStream::new(transfer.take(to - from + 1))
+            .map_err(Errors::from)
+            .boxed())
+    }
+}
+
+impl Source for Sftp {
+    fn probe<'a>(&'a self, url: &'a str) -> BoxFuture<'a, DResult<Meta>> {
+        self.probe(url).boxed()
+    }
+
+    fn open_range<'a>(
+        &'a self,
+        url: &'a str,
+        range: (u64, u64),
+    ) -> BoxFuture<'a, DResult<ByteStream>> {
+        self.open_range(url, range).boxed()
+    }
+}
+
+/// An open remote file, keeping its connection up until it's dropped
+struct Transfer {
+    file: File,
+    _ssh: Handle<Client>,
+}
+
+impl AsyncRead for Transfer {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.file).poll_read(cx, buf)
+    }
+}
@@ -0,0 +1,50 @@
+//! Detached minisign (ed25519) signature verification of finished downloads.
+//!
+//! The signature is fetched with the download's own client and retry policy
+//! once the download completes, the check itself is done offline against the
+//! finished `.part` file.
+
+use std::path::Path;
+
+use minisign_verify::{PublicKey, Signature};
+use tracing::{debug, info};
+
+use crate::{auth::redacted, download::Download, DResult};
+
+/// `key` is either a path to a minisign `.pub` file or the base64 public key
+/// itself (the second line of the `.pub` file).
+pub fn load_pubkey(key: &str) -> DResult<PublicKey> {
+    if Path::new(key).is_file() {
+        debug!("reading public key from file: {}", key);
+        Ok(PublicKey::from_file(key)?)
+    } else {
+        Ok(PublicKey::from_base64(key.trim())?)
+    }
+}
+
+/// `location` is either an http(s) url or a path to a local `.minisig` file.
+pub async fn load_signature(download: &Download, location: &str) -> DResult<Signature> {
+    let text = match reqwest::Url::parse(location) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => {
+            debug!("fetching signature from: {}", redacted(&url));
+            download.fetch(url.as_str()).await?.text().await?
+        }
+        _ => {
+            debug!("reading signature from file: {}", location);
+            tokio::fs::read_to_string(location).await?
+        }
+    };
+    Ok(Signature::decode(&text)?)
+}
+
+/// Verifies `data` (the whole downloaded file) against the signature.
+/// Legacy (non-prehashed) signatures are refused, like `minisign -V` does
+/// without `-l`.
+pub fn verify(pubkey: &PublicKey, signature: &Signature, data: &[u8]) -> DResult<()> {
+    pubkey.verify(data, signature, false)?;
+    info!(
+        "signature verified, trusted comment: {}",
+        signature.trusted_comment()
+    );
+    Ok(())
+}
@@ -0,0 +1,627 @@
+//! Where the bytes of a download come from. A [`Source`] answers two
+//! questions for a url: how big the file is and what it can do (the probe),
+//! and what's in a byte range of it. The range plan, the piece scheduler
+//! and the mmap writer only ever talk to sources, picked from a
+//! [`Registry`] by the url's scheme.
+//!
+//! `http(s)://` and `file://` are here, `ftp://`, `sftp://` and `s3://` live
+//! in their own modules. Other protocols plug in with [`Registry::register`].
+//!
+//! Over HTTP/1.1 every range in flight is a connection of its own. When a
+//! server speaks HTTP/2, picked by ALPN or forced, the ranges are streams
+//! multiplexed over a few connections instead, one per client the `Http`
+//! source takes turns with. HTTP/3 is opt-in, see [`crate::http3`].
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Debug},
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::SystemTime,
+};
+
+use bytes::Bytes;
+use futures::{
+    future::BoxFuture, stream::BoxStream, FutureExt, StreamExt, TryFutureExt, TryStreamExt,
+};
+use reqwest::{
+    header::{HeaderMap, AUTHORIZATION},
+    Client, Method, RequestBuilder, Response, StatusCode, Url, Version,
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+use tower_layer::Layer;
+use tower_service::Service;
+use tracing::{debug, warn};
+
+use crate::{
+    auth::{redacted, Auth},
+    cookies::CookieJar,
+    download::{format_http_date, parse_http_date, send_with_retry, validator},
+    ftp,
+    http3::{alt_svc, Http3},
+    s3, sftp, DResult, Errors,
+};
+
+/// The bytes of a range, in order
+pub type ByteStream = BoxStream<'static, DResult<Bytes>>;
+
+/// What a probe learns about a remote file
+#[derive(Debug, Clone, Default)]
+pub struct Meta {
+    /// where the file was found, after redirects
+    pub url: String,
+    pub size: u64,
+    /// `Some(false)` when the source said it can't serve ranges, `None`
+    /// when it didn't say
+    pub accept_ranges: Option<bool>,
+    /// changes whenever the file does, e.g. an ETag
+    pub validator: Option<String>,
+    pub last_modified: Option<SystemTime>,
+    /// response headers for HTTP, for `Link` mirrors and `Digest`
+    pub headers: HeaderMap,
+}
+
+/// A protocol files can be downloaded over in ranges
+pub trait Source: Debug + Send + Sync {
+    /// Size and capabilities of the file at `url`
+    fn probe<'a>(&'a self, url: &'a str) -> BoxFuture<'a, DResult<Meta>>;
+
+    /// The inclusive byte range `from..=to` of the file at `url`
+    fn open_range<'a>(
+        &'a self,
+        url: &'a str,
+        range: (u64, u64),
+    ) -> BoxFuture<'a, DResult<ByteStream>>;
+}
+
+/// Sources by url scheme
+#[derive(Debug, Clone, Default)]
+pub struct Registry {
+    sources: HashMap<String, Arc<dyn Source>>,
+}
+
+impl Registry {
+    /// `http`, `https`, `ftp`, `sftp`, `file` and `s3`
+    pub fn standard(
+        client: Client,
+        headers: HeaderMap,
+        retries: usize,
+        http: HttpOptions,
+        ftp: ftp::Options,
+        sftp: sftp::Options,
+    ) -> Self {
+        let http = Arc::new(Http::new(http, headers, retries));
+        Registry::default()
+            .register("http", http.clone())
+            .register("https", http)
+            .register("ftp", Arc::new(ftp::Ftp::new(ftp, retries)))
+            .register("sftp", Arc::new(sftp::Sftp::new(sftp, retries)))
+            .register("file", Arc::new(LocalFile))
+            .register("s3", Arc::new(s3::S3::from_env(client, retries)))
+    }
+
+    /// Serves `scheme` urls with `source`, replacing the one it had
+    pub fn register<S: AsRef<str>>(mut self, scheme: S, source: Arc<dyn Source>) -> Self {
+        self.sources
+            .insert(scheme.as_ref().to_ascii_lowercase(), source);
+        self
+    }
+
+    /// The source for `url`'s scheme
+    pub fn get(&self, url: &str) -> DResult<&dyn Source> {
+        let url = Url::parse(url)
+            .map_err(|e| Errors::Custom(format!("Failed parsing url {}: {}", url, e)))?;
+        self.sources
+            .get(url.scheme())
+            .map(|source| source.as_ref())
+            .ok_or_else(|| Errors::Custom(format!("Can't download {} urls", url.scheme())))
+    }
+
+    pub async fn probe(&self, url: &str) -> DResult<Meta> {
+        self.get(url)?.probe(url).await
+    }
+
+    pub async fn open_range(&self, url: &str, range: (u64, u64)) -> DResult<ByteStream> {
+        self.get(url)?.open_range(url, range).await
+    }
+}
+
+/// The HTTP version ranges are requested over
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HttpVersion {
+    /// HTTP/2 when the server offers it in the TLS handshake (ALPN),
+    /// HTTP/1.1 otherwise and for `http://`
+    #[default]
+    Auto,
+    Http1,
+    /// HTTP/2 without asking first, also for `http://` (h2c)
+    Http2,
+    /// HTTP/3 for `https://` urls, TCP where QUIC doesn't get through
+    Http3,
+}
+
+impl FromStr for HttpVersion {
+    type Err = Errors;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(HttpVersion::Auto),
+            "1.1" | "1" => Ok(HttpVersion::Http1),
+            "2" => Ok(HttpVersion::Http2),
+            "3" => Ok(HttpVersion::Http3),
+            _ => Err(Errors::Custom(format!(
+                "Unknown HTTP version {}, expected auto, 1.1, 2 or 3",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for HttpVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            HttpVersion::Auto => "auto",
+            HttpVersion::Http1 => "1.1",
+            HttpVersion::Http2 => "2",
+            HttpVersion::Http3 => "3",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpOptions {
+    pub version: HttpVersion,
+    /// HTTP/2 connections to a host the ranges are spread over
+    pub connections: usize,
+    /// switch origins that advertise HTTP/3 in `Alt-Svc` over to it
+    pub alt_svc: bool,
+    /// sent with requests and filled from responses, can be shared by
+    /// many downloads
+    pub cookies: Option<Arc<CookieJar>>,
+    /// logins for the hosts, a challenge answered for one download is
+    /// answered up front for the others sharing it
+    pub auth: Option<Arc<Auth>>,
+    /// counted into by the `Http` source, shared with whoever reports them
+    pub stats: Arc<HttpStats>,
+}
+
+impl Default for HttpOptions {
+    fn default() -> Self {
+        HttpOptions {
+            version: HttpVersion::Auto,
+            connections: 1,
+            alt_svc: false,
+            cookies: None,
+            auth: None,
+            stats: Arc::default(),
+        }
+    }
+}
+
+/// Connections opened and requests sent by an `Http` source
+#[derive(Debug, Default)]
+pub struct HttpStats {
+    pub connections: AtomicUsize,
+    pub requests: AtomicUsize,
+    /// requests that were HTTP/2 streams
+    pub streams: AtomicUsize,
+    /// requests that went over HTTP/3
+    pub quic: AtomicUsize,
+}
+
+impl HttpStats {
+    fn record(&self, res: &Response) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if res.version() == Version::HTTP_2 {
+            self.streams.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl fmt::Display for HttpStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let connections = self.connections.load(Ordering::Relaxed);
+        let requests = self.requests.load(Ordering::Relaxed);
+        let streams = self.streams.load(Ordering::Relaxed);
+        let quic = self.quic.load(Ordering::Relaxed);
+        write!(
+            f,
+            "{} connection{}, {} request{}",
+            connections,
+            if connections == 1 { "" } else { "s" },
+            requests,
+            if requests == 1 { "" } else { "s" }
+        )?;
+        match (streams, quic) {
+            (0, 0) => write!(f, " over HTTP/1.1"),
+            (_, 0) if streams == requests => write!(f, " as HTTP/2 streams"),
+            (0, _) if quic == requests => write!(f, " over HTTP/3"),
+            (0, _) => write!(f, ", {} of them over HTTP/3", quic),
+            (_, 0) => write!(f, ", {} of them HTTP/2 streams", streams),
+            _ => write!(
+                f,
+                ", {} of them HTTP/2 streams, {} over HTTP/3",
+                streams, quic
+            ),
+        }
+    }
+}
+
+/// `HEAD` to probe, ranged `GET`s for the pieces
+#[derive(Debug)]
+pub struct Http {
+    /// requests take turns on these, for HTTP/2 each keeps one
+    /// connection to a host
+    clients: Vec<Client>,
+    next: AtomicUsize,
+    /// sent with the probe and every ranged GET
+    pub headers: HeaderMap,
+    pub retries: usize,
+    pub stats: Arc<HttpStats>,
+    version: HttpVersion,
+    http3: Option<Http3>,
+    /// `None` when `headers` has an `Authorization` of its own
+    auth: Option<Arc<Auth>>,
+}
+
+impl Http {
+    pub fn new(options: HttpOptions, headers: HeaderMap, retries: usize) -> Self {
+        // HTTP/1.1 fans out over as many connections as one client wants
+        let clients = match options.version {
+            HttpVersion::Http1 => 1,
+            _ => options.connections.max(1),
+        };
+        Http {
+            clients: (0..clients)
+                .map(|_| {
+                    let mut builder = Client::builder()
+                        .connector_layer(CountConnections(options.stats.clone()))
+                        .http2_adaptive_window(true);
+                    if let Some(cookies) = &options.cookies {
+                        builder = builder.cookie_provider(cookies.clone());
+                    }
+                    match options.version {
+                        HttpVersion::Auto | HttpVersion::Http3 => builder,
+                        HttpVersion::Http1 => builder.http1_only(),
+                        HttpVersion::Http2 => builder.http2_prior_knowledge(),
+                    }
+                    .build()
+                    .expect("building a HTTP client failed")
+                })
+                .collect(),
+            next: AtomicUsize::new(0),
+            auth: options
+                .auth
+                .filter(|_| !headers.contains_key(AUTHORIZATION)),
+            headers,
+            retries,
+            version: options.version,
+            http3: (options.alt_svc || options.version == HttpVersion::Http3)
+                .then(|| Http3::new(options.stats.clone(), options.cookies.clone()))
+                .and_then(|http3| http3.map_err(|e| warn!("{}, staying on TCP", e)).ok()),
+            stats: options.stats,
+        }
+    }
+
+    fn client(&self) -> &Client {
+        &self.clients[self.next.fetch_add(1, Ordering::Relaxed) % self.clients.len()]
+    }
+
+    /// `headers` with the url's `Authorization` if there's one to send yet
+    fn authorized_headers(&self, method: &Method, url: &str) -> HeaderMap {
+        let mut headers = self.headers.clone();
+        let parsed = Url::parse(url).ok();
+        if let Some(value) = self
+            .auth
+            .as_ref()
+            .zip(parsed)
+            .and_then(|(auth, url)| auth.authorization(method, &url))
+        {
+            headers.insert(AUTHORIZATION, value);
+        }
+        headers
+    }
+}
+
+/// Sends the request like [`send_with_retry`] with the url's credentials,
+/// a `401` is answered with the login it asks for before retrying
+pub async fn send_authorized<F>(
+    auth: Option<&Auth>,
+    method: &Method,
+    url: &str,
+    make_request: F,
+    retries: usize,
+) -> DResult<Response>
+where
+    F: Fn() -> RequestBuilder,
+{
+    let Some((auth, parsed)) = auth.zip(Url::parse(url).ok()) else {
+        return send_with_retry(make_request, retries).await;
+    };
+    let authorized = || match auth.authorization(method, &parsed) {
+        Some(value) => make_request().header(AUTHORIZATION, value),
+        None => make_request(),
+    };
+    // a new connection may be challenged again even when the host's
+    // challenge was answered before, so each request can get one
+    if let Ok(res) = authorized().send().await {
+        let answered = res.status() == StatusCode::UNAUTHORIZED && auth.challenged(&parsed, &res);
+        if !answered && !res.status().is_server_error() {
+            return Ok(res.error_for_status()?);
+        }
+    }
+    send_with_retry(authorized, retries).await
+}
+
+/// A ranged `GET` with the url's credentials
+async fn get_range(
+    client: &Client,
+    auth: Option<&Auth>,
+    url: &str,
+    headers: &HeaderMap,
+    (from, to): (u64, u64),
+    retries: usize,
+) -> DResult<Response> {
+    send_authorized(
+        auth,
+        &Method::GET,
+        url,
+        || {
+            client
+                .get(url)
+                .headers(headers.clone())
+                .header("Range", format!("bytes={}-{}", from, to))
+        },
+        retries,
+    )
+    .await
+    .and_then(|res| partial_content(res, (from, to)))
+}
+
+/// The response to a ranged request, unless the server ignored the range
+/// and sent the whole file: that's only the bytes asked for when the range
+/// starts at 0, anywhere else they'd be written over the wrong part
+pub(crate) fn partial_content(res: Response, (from, to): (u64, u64)) -> DResult<Response> {
+    match res.status() {
+        StatusCode::PARTIAL_CONTENT => Ok(res),
+        StatusCode::OK if from == 0 => Ok(res),
+        status => Err(Errors::Custom(format!(
+            "{} answered the range {}-{} with {} instead of 206 Partial Content",
+            res.url(),
+            from,
+            to,
+            status
+        ))),
+    }
+}
+
+impl Source for Http {
+    fn probe<'a>(&'a self, url: &'a str) -> BoxFuture<'a, DResult<Meta>> {
+        async move {
+            if let Some(http3) = &self.http3 {
+                if let (HttpVersion::Http3, Ok(parsed)) = (self.version, Url::parse(url)) {
+                    if let (Some(host), Some(port), "https") = (
+                        parsed.host_str(),
+                        parsed.port_or_known_default(),
+                        parsed.scheme(),
+                    ) {
+                        http3.learn(&parsed, (host.to_owned(), port)).await;
+                    }
+                }
+                let headers = self.authorized_headers(&Method::HEAD, url);
+                if let Some(meta) = http3.probe(url, &headers).await {
+                    return meta;
+                }
+            }
+            let client = &self.clients[0];
+            let res = send_authorized(
+                self.auth.as_deref(),
+                &Method::HEAD,
+                url,
+                || client.head(url).headers(self.headers.clone()),
+                self.retries,
+            )
+            .await?;
+            self.stats.record(&res);
+            debug!("headers at {}:\n{:#?}", redacted(res.url()), res.headers());
+            if let (Some(http3), Ok(parsed)) = (&self.http3, Url::parse(url)) {
+                if let Some(authority) = alt_svc(res.headers(), &parsed) {
+                    http3.learn(&parsed, authority).await;
+                }
+            }
+            // the other clients connect now, one at a time, or all the
+            // ranges they start with would race to open a connection each
+            if res.version() == Version::HTTP_2 {
+                for client in &self.clients[1..] {
+                    let res = send_authorized(
+                        self.auth.as_deref(),
+                        &Method::HEAD,
+                        url,
+                        || client.head(url).headers(self.headers.clone()),
+                        self.retries,
+                    )
+                    .await?;
+                    self.stats.record(&res);
+                }
+            }
+            http_meta(&res)
+        }
+        .boxed()
+    }
+
+    fn open_range<'a>(
+        &'a self,
+        url: &'a str,
+        range: (u64, u64),
+    ) -> BoxFuture<'a, DResult<ByteStream>> {
+        async move {
+            if let Some(http3) = &self.http3 {
+                let client = self.client().clone();
+                let (headers, retries, stats, auth) = (
+                    self.headers.clone(),
+                    self.retries,
+                    self.stats.clone(),
+                    self.auth.clone(),
+                );
+                let tcp = url.to_owned();
+                let resume = move |from| {
+                    async move {
+                        let res = get_range(
+                            &client,
+                            auth.as_deref(),
+                            &tcp,
+                            &headers,
+                            (from, range.1),
+                            retries,
+                        )
+                        .await?;
+                        stats.record(&res);
+                        Ok(res.bytes_stream().map_err(Errors::from).boxed())
+                    }
+                    .boxed()
+                };
+                let headers = self.authorized_headers(&Method::GET, url);
+                if let Some(stream) = http3.open_range(url, &headers, range, resume).await {
+                    return stream;
+                }
+            }
+            let res = get_range(
+                self.client(),
+                self.auth.as_deref(),
+                url,
+                &self.headers,
+                range,
+                self.retries,
+            )
+            .await?;
+            self.stats.record(&res);
+            Ok(res.bytes_stream().map_err(Errors::from).boxed())
+        }
+        .boxed()
+    }
+}
+
+/// Counts the connections a client's connector opens
+#[derive(Debug, Clone)]
+struct CountConnections(Arc<HttpStats>);
+
+impl<S> Layer<S> for CountConnections {
+    type Service = Counted<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Counted {
+            inner,
+            stats: self.0.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Counted<S> {
+    inner: S,
+    stats: Arc<HttpStats>,
+}
+
+impl<S, R> Service<R> for Counted<S>
+where
+    S: Service<R>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<S::Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: R) -> Self::Future {
+        let stats = self.stats.clone();
+        self.inner
+            .call(request)
+            .inspect_ok(move |_| {
+                stats.connections.fetch_add(1, Ordering::Relaxed);
+            })
+            .boxed()
+    }
+}
+
+/// What the headers of a `HEAD` response say about the file
+pub(crate) fn http_meta(res: &Response) -> DResult<Meta> {
+    headers_meta(res.url().as_str(), res.headers())
+}
+
+pub(crate) fn headers_meta(url: &str, headers: &HeaderMap) -> DResult<Meta> {
+    let size = headers
+        .get("content-length")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| Errors::Custom(format!("{} didn't send a content-length", url)))?;
+    Ok(Meta {
+        url: url.to_owned(),
+        size,
+        accept_ranges: headers
+            .get("accept-ranges")
+            .map(|v| v.as_bytes() != b"none"),
+        validator: validator(headers),
+        last_modified: headers
+            .get("last-modified")
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_http_date),
+        headers: headers.clone(),
+    })
+}
+
+/// Files on this machine, mostly for mirrors on a mounted share
+#[derive(Debug, Clone, Copy)]
+pub struct LocalFile;
+
+fn file_path(url: &str) -> DResult<PathBuf> {
+    Url::parse(url)
+        .ok()
+        .and_then(|url| url.to_file_path().ok())
+        .ok_or_else(|| Errors::Custom(format!("Not a local file url: {}", url)))
+}
+
+impl Source for LocalFile {
+    fn probe<'a>(&'a self, url: &'a str) -> BoxFuture<'a, DResult<Meta>> {
+        async move {
+            let metadata = tokio::fs::metadata(file_path(url)?).await?;
+            if !metadata.is_file() {
+                return Err(Errors::Custom(format!("{} isn't a file", url)));
+            }
+            let modified = metadata.modified().ok();
+            Ok(Meta {
+                url: url.to_owned(),
+                size: metadata.len(),
+                accept_ranges: Some(true),
+                validator: modified.map(format_http_date),
+                last_modified: modified,
+                headers: HeaderMap::new(),
+            })
+        }
+        .boxed()
+    }
+
+    fn open_range<'a>(
+        &'a self,
+        url: &'a str,
+        (from, to): (u64, u64),
+    ) -> BoxFuture<'a, DResult<ByteStream>> {
+        async move {
+            let mut file = tokio::fs::File::open(file_path(url)?).await?;
+            file.seek(std::io::SeekFrom::Start(from)).await?;
+            Ok(ReaderStream::new(file.take(to - from + 1))
+                .map_err(Errors::from)
+                .boxed())
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,866 @@
+//! Single-file torrents, downloaded from their BEP 19 web seeds like
+//! mirrors of each other, every piece a range checked against the
+//! torrent's SHA-1 for it.
+//!
+//! With `Options::peers` the trackers are asked for peers too and each
+//! one joins as another mirror, `bt://ip:port`, served by [`Peers`] over
+//! the peer wire protocol. Nothing is uploaded back, so peers that only
+//! trade pieces keep us choked; seeders are what this gets anything from.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use futures::{future::BoxFuture, stream, FutureExt, StreamExt};
+use percent_encoding::{percent_encode, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::{Client, Method, Url};
+use sha1::{Digest, Sha1};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::Mutex,
+    time::timeout,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    auth::Auth,
+    checksum::Pieces,
+    download::{send_with_retry, DownloadBuilder},
+    source::{send_authorized, ByteStream, Meta, Source},
+    DResult, Errors,
+};
+
+const PROTOCOL: &[u8] = b"BitTorrent protocol";
+/// what pieces are requested from peers in
+const BLOCK: u64 = 16 * 1024;
+/// block requests a peer has outstanding at once
+const PIPELINE: usize = 16;
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+/// the port we claim in announces, nothing listens on it
+const PORT: u16 = 6881;
+/// how deep lists and dictionaries may nest, deeper is no torrent
+const MAX_DEPTH: usize = 64;
+/// what a file name is escaped with when it's added to a web seed url
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+// peer wire message ids
+const CHOKE: u8 = 0;
+const UNCHOKE: u8 = 1;
+const INTERESTED: u8 = 2;
+const HAVE: u8 = 4;
+const BITFIELD: u8 = 5;
+const REQUEST: u8 = 6;
+const PIECE: u8 = 7;
+
+pub fn is_torrent(url: &str) -> bool {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    path.to_ascii_lowercase().ends_with(".torrent")
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// also download from the peers the trackers know
+    pub peers: bool,
+    pub retries: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Torrent {
+    pub name: String,
+    pub length: u64,
+    pub pieces: Pieces,
+    pub web_seeds: Vec<String>,
+    pub trackers: Vec<String>,
+    pub info_hash: [u8; 20],
+}
+
+impl Torrent {
+    /// The `.torrent` at a http(s) or file url, or a local path, logging in
+    /// with `auth` if the server asks
+    pub async fn load(
+        client: &Client,
+        auth: Option<&Auth>,
+        location: &str,
+        retries: usize,
+    ) -> DResult<Torrent> {
+        let bytes = match Url::parse(location) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => send_authorized(
+                auth,
+                &Method::GET,
+                location,
+                || client.get(url.clone()),
+                retries,
+            )
+            .await?
+            .bytes()
+            .await?
+            .to_vec(),
+            Ok(url) if url.scheme() == "file" => {
+                let path = url
+                    .to_file_path()
+                    .map_err(|_| Errors::Custom(format!("Invalid file url {}", url)))?;
+                tokio::fs::read(path).await?
+            }
+            _ => tokio::fs::read(location).await?,
+        };
+        Torrent::parse(&bytes)
+            .map_err(|e| Errors::Custom(format!("Unreadable torrent {}: {}", location, e)))
+    }
+
+    pub fn parse(bytes: &[u8]) -> DResult<Torrent> {
+        let mut parser = Parser {
+            data: bytes,
+            pos: 0,
+            info: None,
+        };
+        let root = parser.value(0)?;
+        let info_span = parser.info.clone().ok_or("no info dictionary")?;
+        let info = root.get("info").ok_or("no info dictionary")?;
+
+        if info.get("files").is_some() {
+            return Err("only single-file torrents are supported".into());
+        }
+        let name = info
+            .get("name")
+            .and_then(Value::str)
+            .ok_or("no name in info")?;
+        // the name is the file's, never a path
+        let name = Path::new(name)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| Errors::Custom(format!("Invalid file name {}", name)))?
+            .to_owned();
+        let length = info
+            .get("length")
+            .and_then(Value::int)
+            .and_then(|n| u64::try_from(n).ok())
+            .ok_or("no length in info")?;
+        let piece_length = info
+            .get("piece length")
+            .and_then(Value::int)
+            .and_then(|n| u64::try_from(n).ok())
+            .ok_or("no piece length in info")?;
+        let hashes = info
+            .get("pieces")
+            .and_then(Value::bytes)
+            .filter(|hashes| hashes.len() % 20 == 0)
+            .ok_or("pieces aren't a list of SHA-1s")?;
+        let pieces = Pieces {
+            length: piece_length,
+            sha1: hashes
+                .chunks(20)
+                .map(|hash| hash.try_into().expect("chunks of 20"))
+                .collect(),
+        };
+        if !pieces.fits(length) {
+            return Err(Errors::Custom(format!(
+                "{} pieces of {} bytes don't make up {} bytes",
+                pieces.sha1.len(),
+                piece_length,
+                length
+            )));
+        }
+
+        // a single url-list entry may be a string instead of a list
+        let web_seeds = match root.get("url-list") {
+            Some(Value::List(urls)) => urls.iter().filter_map(Value::str).collect(),
+            Some(url) => url.str().into_iter().collect(),
+            None => vec![],
+        }
+        .into_iter()
+        .filter(|url| !url.is_empty())
+        .map(str::to_owned)
+        .collect();
+        let mut trackers: Vec<String> = vec![];
+        let tiers = match root.get("announce-list") {
+            Some(Value::List(tiers)) => tiers.iter().collect(),
+            _ => vec![],
+        };
+        for tracker in std::iter::once(root.get("announce"))
+            .flatten()
+            .chain(tiers.into_iter().flat_map(|tier| match tier {
+                Value::List(urls) => urls.iter().collect(),
+                _ => vec![],
+            }))
+            .filter_map(Value::str)
+        {
+            if !trackers.iter().any(|t| t == tracker) {
+                trackers.push(tracker.to_owned());
+            }
+        }
+
+        Ok(Torrent {
+            name,
+            length,
+            pieces,
+            web_seeds,
+            trackers,
+            info_hash: Sha1::digest(&bytes[info_span]).into(),
+        })
+    }
+
+    /// A download of the file into `path` (or a file named after the
+    /// torrent in it, if it's a directory) from the web seeds, and the
+    /// peers with `options.peers`
+    pub async fn builder(
+        &self,
+        client: &Client,
+        path: &str,
+        options: &Options,
+    ) -> DResult<DownloadBuilder> {
+        let mut urls = self
+            .web_seeds
+            .iter()
+            .map(|seed| self.web_seed_url(seed))
+            .collect::<Vec<_>>();
+        let mut peers = None;
+        if options.peers {
+            let source = Arc::new(Peers::new(self));
+            let addrs = self
+                .announce(client, &source.peer_id, options.retries)
+                .await;
+            info!("{} peers for {}", addrs.len(), self.name);
+            urls.extend(addrs.iter().map(|addr| format!("bt://{}", addr)));
+            peers = Some(source);
+        }
+        let (url, mirrors) = urls.split_first().ok_or_else(|| {
+            Errors::Custom(match options.peers {
+                true => format!("{} has no web seeds and no peers", self.name),
+                false => format!(
+                    "{} has no web seeds, --peers downloads from peers",
+                    self.name
+                ),
+            })
+        })?;
+
+        let file_path = match Path::new(path).is_dir() {
+            true => Path::new(path).join(&self.name),
+            false => PathBuf::from(path),
+        };
+        let builder = DownloadBuilder::new(url)
+            .path(file_path.to_string_lossy())
+            .pieces(self.pieces.clone());
+        let builder = mirrors.iter().fold(builder, |b, m| b.mirror(m));
+        Ok(match peers {
+            Some(source) => builder.source("bt", source),
+            None => builder,
+        })
+    }
+
+    /// BEP 19: a url ending in `/` is the directory the file is in
+    fn web_seed_url(&self, seed: &str) -> String {
+        match seed.ends_with('/') {
+            true => format!("{}{}", seed, utf8_percent_encode(&self.name, PATH_SEGMENT)),
+            false => seed.to_owned(),
+        }
+    }
+
+    /// Peers from every http(s) tracker that answers
+    async fn announce(
+        &self,
+        client: &Client,
+        peer_id: &[u8; 20],
+        retries: usize,
+    ) -> Vec<SocketAddr> {
+        let mut peers = vec![];
+        for tracker in &self.trackers {
+            if !tracker.starts_with("http://") && !tracker.starts_with("https://") {
+                debug!("skipping tracker {}, only http trackers are asked", tracker);
+                continue;
+            }
+            match self.announce_to(client, tracker, peer_id, retries).await {
+                Ok(found) => {
+                    debug!("{} peers from {}", found.len(), tracker);
+                    for peer in found {
+                        if !peers.contains(&peer) {
+                            peers.push(peer);
+                        }
+                    }
+                }
+                Err(e) => warn!("Tracker {} failed: {}", tracker, e),
+            }
+        }
+        peers
+    }
+
+    async fn announce_to(
+        &self,
+        client: &Client,
+        tracker: &str,
+        peer_id: &[u8; 20],
+        retries: usize,
+    ) -> DResult<Vec<SocketAddr>> {
+        let url = format!(
+            "{}{}info_hash={}&peer_id={}&port={}&uploaded=0&downloaded=0&left={}&compact=1&event=started",
+            tracker,
+            if tracker.contains('?') { '&' } else { '?' },
+            percent_encode(&self.info_hash, NON_ALPHANUMERIC),
+            percent_encode(peer_id, NON_ALPHANUMERIC),
+            PORT,
+            self.length
+        );
+        let body = send_with_retry(|| client.get(&url), retries)
+            .await?
+            .bytes()
+            .await?;
+        let response = Parser {
+            data: &body,
+            pos: 0,
+            info: None,
+        }
+        .value(0)?;
+        if let Some(reason) = response.get("failure reason").and_then(Value::str) {
+            return Err(Errors::Custom(reason.to_owned()));
+        }
+
+        let mut peers = vec![];
+        match response.get("peers") {
+            Some(Value::Bytes(compact)) => peers.extend(compact.chunks_exact(6).map(|peer| {
+                let ip: [u8; 4] = peer[..4].try_into().expect("6 byte chunks");
+                SocketAddr::from((Ipv4Addr::from(ip), u16::from_be_bytes([peer[4], peer[5]])))
+            })),
+            Some(Value::List(dicts)) => peers.extend(dicts.iter().filter_map(|peer| {
+                let ip = peer.get("ip")?.str()?.parse().ok()?;
+                let port = u16::try_from(peer.get("port")?.int()?).ok()?;
+                Some(SocketAddr::new(ip, port))
+            })),
+            _ => {}
+        }
+        if let Some(Value::Bytes(compact)) = response.get("peers6") {
+            peers.extend(compact.chunks_exact(18).map(|peer| {
+                let ip: [u8; 16] = peer[..16].try_into().expect("18 byte chunks");
+                SocketAddr::from((Ipv6Addr::from(ip), u16::from_be_bytes([peer[16], peer[17]])))
+            }));
+        }
+        Ok(peers)
+    }
+}
+
+/// A bencoded value
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+impl Value {
+    fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Dict(dict) => dict.get(key.as_bytes()),
+            _ => None,
+        }
+    }
+
+    fn int(&self) -> Option<i64> {
+        match self {
+            Value::Int(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    fn bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    fn str(&self) -> Option<&str> {
+        std::str::from_utf8(self.bytes()?).ok()
+    }
+}
+
+struct Parser<'a> {
+    data: &'a [u8],
+    pos: usize,
+    /// where the top level `info` dictionary is, the info hash is of
+    /// exactly those bytes
+    info: Option<Range<usize>>,
+}
+
+impl Parser<'_> {
+    fn value(&mut self, depth: usize) -> DResult<Value> {
+        if depth > MAX_DEPTH {
+            return Err(Errors::Custom(format!(
+                "nested deeper than {} at {}",
+                MAX_DEPTH, self.pos
+            )));
+        }
+        match self.data.get(self.pos) {
+            Some(b'i') => {
+                self.pos += 1;
+                let n = self.until(b'e')?;
+                n.parse()
+                    .map(Value::Int)
+                    .map_err(|_| Errors::Custom(format!("invalid integer {}", n)))
+            }
+            Some(b'l') => {
+                self.pos += 1;
+                let mut list = vec![];
+                while self.data.get(self.pos) != Some(&b'e') {
+                    list.push(self.value(depth + 1)?);
+                }
+                self.pos += 1;
+                Ok(Value::List(list))
+            }
+            Some(b'd') => {
+                self.pos += 1;
+                let mut dict = BTreeMap::new();
+                while self.data.get(self.pos) != Some(&b'e') {
+                    let Value::Bytes(key) = self.value(depth + 1)? else {
+                        return Err("dictionary key isn't a string".into());
+                    };
+                    let start = self.pos;
+                    let value = self.value(depth + 1)?;
+                    if depth == 0 && key == b"info" {
+                        self.info = Some(start..self.pos);
+                    }
+                    dict.insert(key, value);
+                }
+                self.pos += 1;
+                Ok(Value::Dict(dict))
+            }
+            Some(b'0'..=b'9') => {
+                let len = self.until(b':')?;
+                let len: usize = len
+                    .parse()
+                    .map_err(|_| Errors::Custom(format!("invalid length {}", len)))?;
+                let bytes = self
+                    .pos
+                    .checked_add(len)
+                    .and_then(|end| self.data.get(self.pos..end))
+                    .ok_or("string past the end")?;
+                self.pos += len;
+                Ok(Value::Bytes(bytes.to_vec()))
+            }
+            Some(c) => Err(Errors::Custom(format!(
+                "unexpected {:?} at {}",
+                *c as char, self.pos
+            ))),
+            None => Err("unexpected end".into()),
+        }
+    }
+
+    /// The text up to `end`, moving past it
+    fn until(&mut self, end: u8) -> DResult<String> {
+        let len = self.data[self.pos..]
+            .iter()
+            .position(|c| *c == end)
+            .ok_or("unexpected end")?;
+        let text = String::from_utf8_lossy(&self.data[self.pos..self.pos + len]).into_owned();
+        self.pos += len + 1;
+        Ok(text)
+    }
+}
+
+/// The peers of a torrent as a `Source` for `bt://ip:port` urls. Each
+/// peer gets one connection, kept open between pieces; pieces wanted from
+/// the same peer at once take turns on it. A peer handed a piece it
+/// doesn't have drops out like a failed mirror.
+#[derive(Debug)]
+pub struct Peers {
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    length: u64,
+    piece_length: u64,
+    pieces: usize,
+    connections: Mutex<HashMap<String, Arc<Mutex<Option<Connection>>>>>,
+}
+
+impl Peers {
+    pub fn new(torrent: &Torrent) -> Self {
+        // Azureus style, made unique by the time and process
+        let seed = format!(
+            "{:?}{}",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+            std::process::id()
+        );
+        let mut peer_id = [0; 20];
+        peer_id[..8].copy_from_slice(b"-DL0100-");
+        for (byte, random) in peer_id[8..].iter_mut().zip(Sha1::digest(seed)) {
+            *byte = b'0' + random % 10;
+        }
+        Peers {
+            info_hash: torrent.info_hash,
+            peer_id,
+            length: torrent.length,
+            piece_length: torrent.pieces.length,
+            pieces: torrent.pieces.sha1.len(),
+            connections: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn fetch(&self, addr: &str, range: (u64, u64)) -> DResult<Bytes> {
+        let slot = self
+            .connections
+            .lock()
+            .await
+            .entry(addr.to_owned())
+            .or_default()
+            .clone();
+        let mut slot = slot.lock().await;
+        let mut connection = match slot.take() {
+            Some(connection) => connection,
+            None => Connection::open(addr, &self.info_hash, &self.peer_id, self.pieces).await?,
+        };
+        let bytes = connection.fetch(range, self.piece_length).await?;
+        // only a connection that finished its piece is left in a known state
+        *slot = Some(connection);
+        Ok(bytes)
+    }
+}
+
+impl Source for Peers {
+    fn probe<'a>(&'a self, url: &'a str) -> BoxFuture<'a, DResult<Meta>> {
+        async move {
+            Ok(Meta {
+                url: url.to_owned(),
+                size: self.length,
+                accept_ranges: Some(true),
+                ..Default::default()
+            })
+        }
+        .boxed()
+    }
+
+    fn open_range<'a>(
+        &'a self,
+        url: &'a str,
+        range: (u64, u64),
+    ) -> BoxFuture<'a, DResult<ByteStream>> {
+        async move {
+            let addr = url
+                .strip_prefix("bt://")
+                .ok_or_else(|| Errors::Custom(format!("Not a peer url: {}", url)))?
+                .trim_end_matches('/');
+            let bytes = self.fetch(addr, range).await?;
+            Ok(stream::once(async move { Ok(bytes) }).boxed())
+        }
+        .boxed()
+    }
+}
+
+#[derive(Debug)]
+struct Connection {
+    stream: TcpStream,
+    addr: String,
+    choked: bool,
+    /// pieces the peer said it has
+    has: Vec<bool>,
+}
+
+impl Connection {
+    async fn open(
+        addr: &str,
+        info_hash: &[u8; 20],
+        peer_id: &[u8; 20],
+        pieces: usize,
+    ) -> DResult<Connection> {
+        let mut stream = timeout(PEER_TIMEOUT, TcpStream::connect(addr))
+            .await
+            .map_err(|_| Errors::Custom(format!("Connecting to peer {} timed out", addr)))??;
+        let mut handshake = vec![PROTOCOL.len() as u8];
+        handshake.extend_from_slice(PROTOCOL);
+        handshake.extend_from_slice(&[0; 8]);
+        handshake.extend_from_slice(info_hash);
+        handshake.extend_from_slice(peer_id);
+        stream.write_all(&handshake).await?;
+
+        let mut reply = [0; 68];
+        timeout(PEER_TIMEOUT, stream.read_exact(&mut reply))
+            .await
+            .map_err(|_| Errors::Custom(format!("Peer {} didn't shake hands", addr)))??;
+        if reply[0] as usize != PROTOCOL.len() || &reply[1..20] != PROTOCOL {
+            return Err(Errors::Custom(format!("{} doesn't speak BitTorrent", addr)));
+        }
+        if &reply[28..48] != info_hash {
+            return Err(Errors::Custom(format!("{} has another torrent", addr)));
+        }
+        debug!("connected to peer {}", addr);
+
+        let mut connection = Connection {
+            stream,
+            addr: addr.to_owned(),
+            choked: true,
+            has: vec![false; pieces],
+        };
+        connection.send(INTERESTED, &[]).await?;
+        Ok(connection)
+    }
+
+    async fn send(&mut self, id: u8, payload: &[u8]) -> DResult<()> {
+        let mut message = Vec::with_capacity(5 + payload.len());
+        message.extend_from_slice(&(1 + payload.len() as u32).to_be_bytes());
+        message.push(id);
+        message.extend_from_slice(payload);
+        self.stream.write_all(&message).await?;
+        Ok(())
+    }
+
+    /// The next message that isn't a keep-alive, with the choke state and
+    /// the pieces the peer has kept up to date
+    async fn receive(&mut self) -> DResult<(u8, Vec<u8>)> {
+        loop {
+            let mut len = [0; 4];
+            timeout(PEER_TIMEOUT, self.stream.read_exact(&mut len))
+                .await
+                .map_err(|_| Errors::Custom(format!("Peer {} went quiet", self.addr)))??;
+            let len = u32::from_be_bytes(len) as usize;
+            if len == 0 {
+                continue;
+            }
+            // a block and its header, or a bitfield
+            if len > (BLOCK as usize + 9).max(self.has.len() / 8 + 2) {
+                return Err(Errors::Custom(format!(
+                    "Peer {} sent a {} byte message",
+                    self.addr, len
+                )));
+            }
+            let mut message = vec![0; len];
+            timeout(PEER_TIMEOUT, self.stream.read_exact(&mut message))
+                .await
+                .map_err(|_| Errors::Custom(format!("Peer {} went quiet", self.addr)))??;
+            let payload = message.split_off(1);
+            let id = message[0];
+            match id {
+                CHOKE => self.choked = true,
+                UNCHOKE => self.choked = false,
+                HAVE if payload.len() == 4 => {
+                    let idx = u32::from_be_bytes(payload[..4].try_into().expect("4 bytes"));
+                    if let Some(has) = self.has.get_mut(idx as usize) {
+                        *has = true;
+                    }
+                }
+                BITFIELD => {
+                    for (idx, has) in self.has.iter_mut().enumerate() {
+                        *has = payload
+                            .get(idx / 8)
+                            .is_some_and(|byte| byte & (0x80 >> (idx % 8)) != 0);
+                    }
+                }
+                _ => {}
+            }
+            return Ok((id, payload));
+        }
+    }
+
+    /// `range`, which lies within a single piece, requested in blocks
+    async fn fetch(&mut self, (from, to): (u64, u64), piece_length: u64) -> DResult<Bytes> {
+        let index = from / piece_length;
+        if to / piece_length != index {
+            return Err(Errors::Custom(format!(
+                "Range {}-{} spans more than one piece",
+                from, to
+            )));
+        }
+        let start = from - index * piece_length;
+        let end = to - index * piece_length + 1;
+        let mut buf = vec![0; (end - start) as usize];
+        let mut queued: Vec<u64> = (start..end).step_by(BLOCK as usize).collect();
+        queued.reverse();
+        let mut requested: Vec<u64> = vec![];
+
+        while !queued.is_empty() || !requested.is_empty() {
+            if !self.choked {
+                if !self.has[index as usize] {
+                    return Err(Errors::Custom(format!(
+                        "Peer {} doesn't have piece {}",
+                        self.addr, index
+                    )));
+                }
+                while requested.len() < PIPELINE {
+                    let Some(begin) = queued.pop() else { break };
+                    let mut request = Vec::with_capacity(12);
+                    request.extend_from_slice(&(index as u32).to_be_bytes());
+                    request.extend_from_slice(&(begin as u32).to_be_bytes());
+                    request.extend_from_slice(&(BLOCK.min(end - begin) as u32).to_be_bytes());
+                    self.send(REQUEST, &request).await?;
+                    requested.push(begin);
+                }
+            }
+            match self.receive().await? {
+                (CHOKE, _) => {
+                    // a choke drops every request, they're asked again after the unchoke
+                    queued.append(&mut requested);
+                    queued.sort_unstable_by(|a, b| b.cmp(a));
+                }
+                (PIECE, payload) if payload.len() >= 8 => {
+                    let piece = u32::from_be_bytes(payload[..4].try_into().expect("4 bytes"));
+                    let begin = u32::from_be_bytes(payload[4..8].try_into().expect("4 bytes"));
+                    let Some(pos) = requested
+                        .iter()
+                        .position(|b| piece as u64 == index && *b == begin as u64)
+                    else {
+                        continue;
+                    };
+                    let block = &payload[8..];
+                    let expected = BLOCK.min(end - begin as u64) as usize;
+                    if block.len() != expected {
+                        return Err(Errors::Custom(format!(
+                            "Peer {} sent {} bytes for a {} byte block",
+                            self.addr,
+                            block.len(),
+                            expected
+                        )));
+                    }
+                    let at = (begin as u64 - start) as usize;
+                    buf[at..at + block.len()].copy_from_slice(block);
+                    requested.swap_remove(pos);
+                }
+                _ => {}
+            }
+        }
+        Ok(Bytes::from(buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A torrent of an 8 byte file in two pieces, `extra` going in the
+    /// root dictionary after `info`
+    fn torrent(extra: &str) -> Vec<u8> {
+        let hashes: Vec<u8> = [b"abcd", b"efgh"].iter().flat_map(Sha1::digest).collect();
+        let mut bytes =
+            b"d8:announce26:http://tracker.example/ann4:infod6:lengthi8e4:name8:a file.b".to_vec();
+        bytes.extend(b"12:piece lengthi4e6:pieces40:");
+        bytes.extend(hashes);
+        bytes.extend(b"e");
+        bytes.extend(extra.as_bytes());
+        bytes.extend(b"e");
+        bytes
+    }
+
+    fn parse(bytes: &[u8]) -> DResult<Value> {
+        Parser {
+            data: bytes,
+            pos: 0,
+            info: None,
+        }
+        .value(0)
+    }
+
+    #[test]
+    fn bencode() {
+        assert_eq!(parse(b"i-42e").unwrap(), Value::Int(-42));
+        assert_eq!(parse(b"4:spam").unwrap(), Value::Bytes(b"spam".to_vec()));
+        assert_eq!(parse(b"0:").unwrap(), Value::Bytes(vec![]));
+        assert_eq!(
+            parse(b"l4:spami7ee").unwrap(),
+            Value::List(vec![Value::Bytes(b"spam".to_vec()), Value::Int(7)])
+        );
+        let dict = parse(b"d3:cow3:moo4:spaml1:aee").unwrap();
+        assert_eq!(dict.get("cow").and_then(Value::str), Some("moo"));
+        assert_eq!(
+            dict.get("spam"),
+            Some(&Value::List(vec![Value::Bytes(b"a".to_vec())]))
+        );
+    }
+
+    #[test]
+    fn bencode_errors() {
+        assert!(parse(b"i12").is_err());
+        assert!(parse(b"ixe").is_err());
+        assert!(parse(b"5:spam").is_err());
+        assert!(parse(b"l4:spam").is_err());
+        assert!(parse(b"di1e1:ae").is_err());
+        assert!(parse(b"x").is_err());
+        assert!(parse(b"").is_err());
+        // a length that overflows the position
+        assert!(parse(format!("{}:a", usize::MAX).as_bytes()).is_err());
+    }
+
+    #[test]
+    fn nesting_is_bounded() {
+        let deep = |n: usize| [vec![b'l'; n], vec![b'e'; n]].concat();
+        assert!(parse(&deep(MAX_DEPTH + 1)).is_ok());
+        assert!(parse(&deep(MAX_DEPTH + 2)).is_err());
+        // never a stack overflow
+        assert!(parse(&deep(1_000_000)).is_err());
+    }
+
+    #[test]
+    fn info_hash_is_of_the_info_bytes() {
+        let bytes = torrent("");
+        let torrent = Torrent::parse(&bytes).unwrap();
+        let start = bytes.windows(6).position(|w| w == b"4:info").unwrap() + 6;
+        let info = &bytes[start..bytes.len() - 1];
+        assert!(info.starts_with(b"d6:length") && info.ends_with(b"e"));
+        assert_eq!(torrent.info_hash, <[u8; 20]>::from(Sha1::digest(info)));
+
+        assert_eq!(torrent.name, "a file.b");
+        assert_eq!(torrent.length, 8);
+        assert_eq!(torrent.pieces.length, 4);
+        assert_eq!(torrent.pieces.sha1.len(), 2);
+        assert_eq!(torrent.trackers, ["http://tracker.example/ann"]);
+        assert!(torrent.web_seeds.is_empty());
+    }
+
+    #[test]
+    fn nested_info_keys_are_not_the_info() {
+        // only the top level `info` counts for the hash
+        let bytes = torrent("5:extrad4:infod1:ai1eee");
+        let torrent = Torrent::parse(&bytes).unwrap();
+        let start = bytes.windows(6).position(|w| w == b"4:info").unwrap() + 6;
+        let end = bytes.windows(5).position(|w| w == b"5:ext").unwrap();
+        assert_eq!(
+            torrent.info_hash,
+            <[u8; 20]>::from(Sha1::digest(&bytes[start..end]))
+        );
+    }
+
+    #[test]
+    fn rejects_bad_torrents() {
+        assert!(Torrent::parse(b"d8:announce1:xe").is_err());
+        assert!(Torrent::parse(b"d4:infod5:filesleee").is_err());
+        // 9 bytes aren't two pieces of 4
+        let mut short = torrent("");
+        let at = short.windows(9).position(|w| w == b"lengthi8e").unwrap();
+        short[at + 7] = b'9';
+        assert!(Torrent::parse(&short).is_err());
+    }
+
+    #[test]
+    fn web_seed_urls() {
+        let seeded = Torrent::parse(&torrent(
+            "8:url-listl23:https://seed.example/d/26:https://seed.example/f.bin0:ee",
+        ))
+        .unwrap();
+        assert_eq!(
+            seeded.web_seeds,
+            ["https://seed.example/d/", "https://seed.example/f.bin"]
+        );
+        let urls: Vec<String> = seeded
+            .web_seeds
+            .iter()
+            .map(|seed| seeded.web_seed_url(seed))
+            .collect();
+        assert_eq!(
+            urls,
+            [
+                "https://seed.example/d/a%20file.b",
+                "https://seed.example/f.bin"
+            ]
+        );
+
+        // a single url instead of a list
+        let torrent = Torrent::parse(&torrent("8:url-list23:https://seed.example/d/")).unwrap();
+        assert_eq!(torrent.web_seeds, ["https://seed.example/d/"]);
+    }
+}
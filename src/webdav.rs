@@ -0,0 +1,312 @@
+//! Listing WebDAV collections with `PROPFIND` (RFC 4918), one `Depth: 1`
+//! request per collection, for recursive downloads of a WebDAV tree.
+//!
+//! The listing already has every file's size and modification time, so
+//! files that are unchanged locally are left out without a request each.
+//! Only hrefs below the listed collection, on the same origin, are
+//! followed.
+
+use std::{collections::HashSet, path::PathBuf, time::SystemTime};
+
+use percent_encoding::percent_decode_str;
+use quick_xml::events::Event;
+use reqwest::{header::HeaderMap, Client, Method, Url};
+use tracing::{debug, warn};
+
+use crate::{
+    auth::{redacted, Auth},
+    autoindex::Options,
+    download::parse_http_date,
+    source::send_authorized,
+    DResult, Errors,
+};
+
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:">
+  <d:prop>
+    <d:resourcetype/>
+    <d:getcontentlength/>
+    <d:getlastmodified/>
+  </d:prop>
+</d:propfind>"#;
+
+/// A `<response>` of a multistatus listing
+#[derive(Debug, Clone, Default)]
+pub struct Resource {
+    pub url: String,
+    pub collection: bool,
+    pub size: Option<u64>,
+    pub last_modified: Option<SystemTime>,
+}
+
+/// A file found in the tree
+#[derive(Debug, Clone)]
+pub struct RemoteFile {
+    pub resource: Resource,
+    /// where it goes locally, relative to the save path, starting with the
+    /// listed collection's name
+    pub path: PathBuf,
+}
+
+/// The collection at `url` itself and its members
+pub async fn propfind(
+    client: &Client,
+    auth: Option<&Auth>,
+    url: &Url,
+    headers: &HeaderMap,
+    retries: usize,
+) -> DResult<Vec<Resource>> {
+    let method = Method::from_bytes(b"PROPFIND").expect("PROPFIND is a valid method");
+    let res = send_authorized(
+        auth,
+        &method,
+        url.as_str(),
+        || {
+            client
+                .request(method.clone(), url.as_str())
+                .headers(headers.clone())
+                .header("Depth", "1")
+                .header("Content-Type", "application/xml; charset=utf-8")
+                .body(PROPFIND_BODY)
+        },
+        retries,
+    )
+    .await?;
+    if res.status().as_u16() != 207 {
+        return Err(Errors::Custom(format!(
+            "{} answered PROPFIND with {} instead of a multistatus",
+            redacted(url),
+            res.status()
+        )));
+    }
+    parse_multistatus(&res.text().await?, url)
+}
+
+/// Parses a `207 Multi-Status` body, hrefs are resolved against `base`
+pub fn parse_multistatus(xml: &str, base: &Url) -> DResult<Vec<Resource>> {
+    let invalid = |e: quick_xml::Error| Errors::Custom(format!("Invalid multistatus: {}", e));
+    // not trimmed while reading, that drops the spaces around `&amp;`,
+    // values are trimmed at their end tag
+    let mut reader = quick_xml::Reader::from_str(xml);
+
+    let mut resources = vec![];
+    let mut resource = Resource::default();
+    // text of the innermost element
+    let mut text = String::new();
+    loop {
+        match reader.read_event().map_err(invalid)? {
+            Event::Start(start) => {
+                text.clear();
+                if start.local_name().as_ref().eq_ignore_ascii_case("response") {
+                    resource = Resource::default();
+                }
+            }
+            Event::Empty(empty)
+                if empty
+                    .local_name()
+                    .as_ref()
+                    .eq_ignore_ascii_case("collection") =>
+            {
+                resource.collection = true;
+            }
+            Event::Text(t) => text.push_str(&t.xml10_content()),
+            Event::CData(t) => text.push_str(&t.xml10_content()),
+            Event::GeneralRef(r) => match r.resolve_char_ref().map_err(invalid)? {
+                Some(c) => text.push(c),
+                None => text.push_str(match &*r {
+                    "amp" => "&",
+                    "lt" => "<",
+                    "gt" => ">",
+                    "quot" => "\"",
+                    "apos" => "'",
+                    other => {
+                        return Err(Errors::Custom(format!("Unknown entity &{};", other)));
+                    }
+                }),
+            },
+            Event::End(end) => {
+                let value = text.trim();
+                match end.local_name().as_ref().to_ascii_lowercase().as_str() {
+                    "href" => {
+                        resource.url = base
+                            .join(value)
+                            .map_err(|e| Errors::Custom(format!("Invalid href {}: {}", value, e)))?
+                            .to_string()
+                    }
+                    "collection" => resource.collection = true,
+                    "getcontentlength" => resource.size = value.parse().ok(),
+                    "getlastmodified" => resource.last_modified = parse_http_date(value),
+                    "response" => resources.push(std::mem::take(&mut resource)),
+                    _ => {}
+                }
+                text.clear();
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    debug!("{} resources in multistatus", resources.len());
+    Ok(resources)
+}
+
+/// Walks the collection at `root` and its members, returning the files
+pub async fn crawl(
+    client: &Client,
+    auth: Option<&Auth>,
+    root: &str,
+    headers: &HeaderMap,
+    options: &Options,
+    retries: usize,
+) -> DResult<Vec<RemoteFile>> {
+    let mut root = Url::parse(root)
+        .map_err(|e| Errors::Custom(format!("Failed parsing url {}: {}", root, e)))?;
+    if !root.path().ends_with('/') {
+        root.set_path(&format!("{}/", root.path()));
+    }
+    let top = last_segment(&root)
+        .or_else(|| root.host_str().map(|host| host.to_owned()))
+        .unwrap_or_else(|| "webdav".to_owned());
+
+    let mut files = vec![];
+    // (collection url, path relative to root, depth)
+    let mut collections = vec![(root.clone(), String::new(), 0)];
+    let mut visited = HashSet::from([decoded_path(&root)]);
+    while let Some((collection, relative, depth)) = collections.pop() {
+        debug!("listing {}", redacted(&collection));
+        for resource in propfind(client, auth, &collection, headers, retries).await? {
+            let Ok(url) = Url::parse(&resource.url) else {
+                continue;
+            };
+            // the collection lists itself too, and the headers shouldn't
+            // go anywhere else than below it
+            let below = url.origin() == collection.origin()
+                && decoded_path(&url)
+                    .strip_prefix(&decoded_path(&collection))
+                    .is_some_and(|name| !name.trim_end_matches('/').is_empty());
+            if !below {
+                if decoded_path(&url).trim_end_matches('/')
+                    != decoded_path(&collection).trim_end_matches('/')
+                {
+                    debug!(
+                        "Skipping {}: not below {}",
+                        redacted(&url),
+                        redacted(&collection)
+                    );
+                }
+                continue;
+            }
+            let Some(name) = last_segment(&url) else {
+                continue;
+            };
+            if name == "." || name == ".." || name.contains(['/', '\\']) {
+                warn!("Skipping {}: unsafe name", redacted(&url));
+                continue;
+            }
+            let path = format!("{}{}", relative, name);
+            if resource.collection {
+                if depth < options.depth && options.wants_dir(&format!("{}/", path)) {
+                    let mut url = url;
+                    if !url.path().ends_with('/') {
+                        url.set_path(&format!("{}/", url.path()));
+                    }
+                    if visited.insert(decoded_path(&url)) {
+                        collections.push((url, format!("{}/", path), depth + 1));
+                    }
+                }
+            } else if options.wants_file(&path) {
+                files.push(RemoteFile {
+                    resource,
+                    path: PathBuf::from(&top).join(&path),
+                });
+            }
+        }
+    }
+    debug!("found {} files under {}", files.len(), redacted(&root));
+    Ok(files)
+}
+
+fn decoded_path(url: &Url) -> String {
+    percent_decode_str(url.path())
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+fn last_segment(url: &Url) -> Option<String> {
+    url.path_segments()?
+        .rfind(|s| !s.is_empty())
+        .map(|s| percent_decode_str(s).decode_utf8_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+
+    const LISTING: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<D:multistatus xmlns:D="DAV:">
+  <D:response>
+    <D:href>/dav/files/</D:href>
+    <D:propstat>
+      <D:prop>
+        <D:resourcetype><D:collection/></D:resourcetype>
+        <D:getlastmodified>Sun, 06 Nov 1994 08:49:37 GMT</D:getlastmodified>
+      </D:prop>
+      <D:status>HTTP/1.1 200 OK</D:status>
+    </D:propstat>
+  </D:response>
+  <D:response>
+    <D:href>/dav/files/R &amp; D.txt</D:href>
+    <D:propstat>
+      <D:prop>
+        <D:resourcetype/>
+        <D:getcontentlength> 1234 </D:getcontentlength>
+        <D:getlastmodified>Sun, 06 Nov 1994 08:49:37 GMT</D:getlastmodified>
+      </D:prop>
+    </D:propstat>
+  </D:response>
+  <response xmlns="DAV:">
+    <href><![CDATA[sub%20dir/]]></href>
+    <propstat><prop><resourcetype><collection></collection></resourcetype></prop></propstat>
+  </response>
+  <D:response>
+    <D:href>https://other.example/x&#46;bin</D:href>
+    <D:propstat><D:prop><D:getcontentlength>big</D:getcontentlength></D:prop></D:propstat>
+  </D:response>
+</D:multistatus>"#;
+
+    #[test]
+    fn multistatus() {
+        let base = Url::parse("https://example.org/dav/files/").unwrap();
+        let resources = parse_multistatus(LISTING, &base).unwrap();
+        let urls: Vec<&str> = resources.iter().map(|r| r.url.as_str()).collect();
+        assert_eq!(
+            urls,
+            [
+                "https://example.org/dav/files/",
+                "https://example.org/dav/files/R%20&%20D.txt",
+                "https://example.org/dav/files/sub%20dir/",
+                "https://other.example/x.bin",
+            ]
+        );
+        let collections: Vec<bool> = resources.iter().map(|r| r.collection).collect();
+        assert_eq!(collections, [true, false, true, false]);
+        assert_eq!(resources[1].size, Some(1234));
+        assert_eq!(
+            resources[1].last_modified,
+            Some(UNIX_EPOCH + Duration::from_secs(784111777))
+        );
+        // an unreadable size is no size
+        assert_eq!(resources[3].size, None);
+        assert_eq!(resources[3].last_modified, None);
+    }
+
+    #[test]
+    fn invalid_multistatus() {
+        let base = Url::parse("https://example.org/").unwrap();
+        assert!(parse_multistatus("<multistatus><response></multistatus>", &base).is_err());
+        assert!(parse_multistatus("<href>&nbsp;</href>", &base).is_err());
+        assert!(parse_multistatus("<href>http://[::1</href>", &base).is_err());
+        assert!(parse_multistatus("", &base).unwrap().is_empty());
+    }
+}